@@ -2,7 +2,7 @@ use experiment::responsive::layout_machine::ConstraintMap;
 use experiment::serde_json;
 use experiment::{responsive::layout_machine::LayoutMachine, uses::use_svg};
 use guppies::bytemuck::cast_slice;
-use guppies::{GpuRedraw, Guppy};
+use guppies::{GpuRedraw, Guppy, GuppyConfig};
 use mobile_entry_point::mobile_entry_point;
 
 pub fn main() {
@@ -19,11 +19,15 @@ pub fn main() {
         None,
     );
 
-    let mut guppy = Guppy::new([GpuRedraw::default()]);
+    let mut guppy = Guppy::new([GpuRedraw::default()], GuppyConfig::default());
     guppy.register(move |event, gpu_redraws| {
         layout_machine.event_handler(event);
         gpu_redraws[0].update_texture([cast_slice(&layout_machine.transforms[..])].concat());
-        gpu_redraws[0].update_triangles(svg_set.get_combined_geometries().triangles, 0);
+        let geometry = svg_set.get_combined_geometries();
+        gpu_redraws[0].update_gradients(cast_slice(&geometry.gradients).to_vec());
+        gpu_redraws[0].update_images(geometry.images);
+        gpu_redraws[0].update_draw_commands(geometry.draw_commands);
+        gpu_redraws[0].update_triangles(geometry.triangles, 0);
     });
     guppy.start();
 }
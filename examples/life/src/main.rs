@@ -5,7 +5,7 @@ use experiment::uses::use_svg;
 use guppies::bytemuck::{cast_slice, Pod, Zeroable};
 use guppies::glam::{Mat4, Vec2};
 use guppies::winit::event::{Event, WindowEvent};
-use guppies::{GpuRedraw, Guppy};
+use guppies::{GpuRedraw, Guppy, GuppyConfig};
 use regex::Regex;
 use salvage::svg_set::SvgSet;
 use salvage::usvg::NodeExt;
@@ -94,16 +94,22 @@ pub fn main() {
     };
     let mut scroll_state = ScrollState::new_from_svg_set(&svg_set);
     let mut texture = Texture::default();
+    // These change every turn, so they're drawn from glyphs rasterized once
+    svg_set.use_glyph_atlas("instruction #dynamicText");
+    for player in 1..=4 {
+        svg_set.use_glyph_atlas(&format!("{}. Player #dynamicText", player));
+    }
     svg_set.update_text("instruction #dynamicText", "Please click");
     let mut tip_animation = SpringMat4::default();
     let mut player_animations = texture
         .player_avatar_transforms
         .map(|_| SpringMat4::default());
     let start_center = Mat4::from_translation((life_game.position_to_coordinates[0], 0.).into());
-    let mut guppy = Guppy::new([GpuRedraw::default()]);
+    let mut guppy = Guppy::new([GpuRedraw::default()], GuppyConfig::default());
 
     guppy.register(move |event, gpu_redraw| {
         let clicked = scroll_state.event_handler(event);
+        svg_set.set_zoom(scroll_state.zoom());
         match event {
             Event::WindowEvent {
                 window_id: _,
@@ -154,7 +160,15 @@ pub fn main() {
                 after_tip_animation,
             );
         }
-        gpu_redraw[0].update_triangles(svg_set.get_combined_geometries().triangles, 0);
+        if tip_animation.is_animating || player_animations.iter().any(|spring| spring.is_animating)
+        {
+            gpu_redraw[0].request_redraw();
+        }
+        let geometry = svg_set.get_combined_geometries();
+        gpu_redraw[0].update_gradients(cast_slice(&geometry.gradients).to_vec());
+        gpu_redraw[0].update_images(geometry.images);
+        gpu_redraw[0].update_draw_commands(geometry.draw_commands);
+        gpu_redraw[0].update_triangles(geometry.triangles, 0);
         gpu_redraw[0].update_texture(
             [
                 cast_slice(&[scroll_state.transform]),
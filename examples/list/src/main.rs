@@ -1,21 +1,27 @@
 use experiment::responsive::constraint::YConstraint;
+use experiment::responsive::interaction_state::InteractionStates;
 use experiment::responsive::layout_machine::ConstraintMap;
+use experiment::responsive::pointer::{EventDispatcher, PointerEventKind, Selector};
 use experiment::serde_json;
 use experiment::uses::use_duplicate;
 use experiment::{responsive::layout_machine::LayoutMachine, uses::use_svg};
 use guppies::bytemuck::cast_slice;
-use guppies::{GpuRedraw, Guppy};
+use guppies::{GpuRedraw, Guppy, GuppyConfig};
 use mobile_entry_point::mobile_entry_point;
+use salvage::geometry::Geometry;
+use salvage::svg_set::SvgSet;
 
 pub fn main() {
     let mut layout_machine = LayoutMachine::default();
     let json = include_str!("constraints.json");
     layout_machine.constraint_map = serde_json::from_str::<ConstraintMap>(json).unwrap();
 
-    let svg_set = use_svg(
+    let mut interaction_states = InteractionStates::default();
+    let mut svg_set = use_svg(
         include_str!("../V2.svg").to_string(),
         |node, mut pass_down| {
             layout_machine.add_node(&node, &mut pass_down, None);
+            interaction_states.add_node(node, pass_down, None);
         },
         None,
         None,
@@ -24,38 +30,63 @@ pub fn main() {
 
     let component_name = "ListItem #transform #layout #component".to_string();
     let xml = &include_str!("../V2.svg");
-    let mut list_1 = use_duplicate(
-        xml.to_string(),
-        &mut layout_machine,
-        component_name.clone(),
-        container_name.clone(),
-        0,
-        70.0,
-    );
-    let mut list_2 = use_duplicate(
-        xml.to_string(),
-        &mut layout_machine,
-        component_name,
-        container_name.clone(),
-        1,
-        70.0,
+    let mut lists = [0, 1].map(|index| {
+        use_duplicate(
+            xml.to_string(),
+            &mut layout_machine,
+            component_name.clone(),
+            container_name.clone(),
+            index,
+            70.0,
+        )
+    });
+    let word = "word #dynamicText #transform #layout";
+    lists[0].update_text(word, "abb");
+    lists[1].update_text(word, "abbbbbbbabfdkj");
+    let dynamic_text = Selector::Tag("dynamicText".to_string());
+
+    let mut event_dispatcher = EventDispatcher::<[SvgSet; 2]>::default();
+    event_dispatcher.add_handler(
+        Selector::Tag("clickable".to_string()),
+        PointerEventKind::Click,
+        move |event, lists| {
+            // Shows what was clicked in the first list item
+            let name = event.id.split(" #").next().unwrap_or_default();
+            lists[0].update_text(word, &format!("{name} clicked"));
+        },
     );
-    list_1.update_text("word #dynamicText #transform #layout", "abb");
-    list_2.update_text("word #dynamicText #transform #layout", "abbbbbbbabfdkj");
 
-    let mut guppy = Guppy::new([GpuRedraw::default()]);
+    let mut guppy = Guppy::new([GpuRedraw::default()], GuppyConfig::default());
 
     guppy.register(move |event, gpu_redraws| {
         layout_machine.event_handler(event);
+        event_dispatcher.event_handler(event, &layout_machine, &mut lists);
+        interaction_states.event_handler(event, &layout_machine);
+        for (id, visible) in interaction_states.take_changed_visibility() {
+            svg_set.set_visibility(&id, visible);
+        }
+        for (id, text_box) in layout_machine.take_changed_text_boxes() {
+            // Ids of duplicates end with their index, see `use_duplicate`
+            let duplicate = id
+                .rsplit_once(' ')
+                .filter(|_| dynamic_text.matches(&id))
+                .and_then(|(id, index)| Some((id, lists.get_mut(index.parse::<usize>().ok()?)?)));
+            if let Some((id, list)) = duplicate {
+                list.set_text_box(id, Some(text_box));
+            }
+        }
         gpu_redraws[0].update_texture(cast_slice(&layout_machine.transforms.clone()).to_vec());
-        gpu_redraws[0].update_triangles(
-            list_1
-                .get_combined_geometries()
-                .extend(&list_2.get_combined_geometries())
-                .extend(&svg_set.get_combined_geometries())
-                .triangles,
-            0,
-        );
+        gpu_redraws[0].update_attributes(cast_slice(&layout_machine.get_attributes()).to_vec());
+        let geometry = lists
+            .iter()
+            .fold(Geometry::default(), |acc, list| {
+                acc.extend(&list.get_combined_geometries())
+            })
+            .extend(&svg_set.get_combined_geometries());
+        gpu_redraws[0].update_gradients(cast_slice(&geometry.gradients).to_vec());
+        gpu_redraws[0].update_images(geometry.images);
+        gpu_redraws[0].update_draw_commands(geometry.draw_commands);
+        gpu_redraws[0].update_triangles(geometry.triangles, 0);
     });
 
     guppy.start();
@@ -1,6 +1,11 @@
 use super::layout_machine::LayoutMachine;
 use guppies::glam::Mat4;
+use guppies::glam::Vec2;
+use guppies::glam::Vec3;
 use guppies::glam::Vec4;
+use guppies::primitives::DrawCommand;
+use guppies::primitives::Rect;
+use salvage::geometry::Geometry;
 
 #[derive(Debug, Clone)]
 pub enum ClickableBbox {
@@ -9,12 +14,14 @@ pub enum ClickableBbox {
 }
 
 impl ClickableBbox {
-    pub fn click_detection(&self, click: Vec4, layout_machine: &LayoutMachine) -> bool {
-        let bbox = match self {
+    fn bbox(&self, layout_machine: &LayoutMachine) -> Mat4 {
+        match self {
             ClickableBbox::Layout(id) => layout_machine.get_bbox_for(id.to_string()).unwrap(),
             ClickableBbox::Bbox(bbox) => *bbox,
-        };
-        let click = bbox.inverse()
+        }
+    }
+    pub fn click_detection(&self, click: Vec4, layout_machine: &LayoutMachine) -> bool {
+        let click = self.bbox(layout_machine).inverse()
             * Mat4::from_scale([1., -1., 1.].into())
             * Mat4::from_translation([-1.0, -1., 0.].into())
             * layout_machine.display_mat4.inverse()
@@ -24,10 +31,313 @@ impl ClickableBbox {
         }
         false
     }
+    /// The window pixels `click_detection` takes clicks in.
+    pub fn bounds(&self, layout_machine: &LayoutMachine) -> Rect {
+        let to_pixels = layout_machine.display_mat4
+            * Mat4::from_translation([1., 1., 0.].into())
+            * Mat4::from_scale([1., -1., 1.].into())
+            * self.bbox(layout_machine);
+        let corners = [[-1., -1.], [1., -1.], [1., 1.], [-1., 1.]].map(|corner| {
+            to_pixels
+                .transform_point3(Vec2::from(corner).extend(0.))
+                .truncate()
+        });
+        bounds_of(&corners).unwrap()
+    }
+    /// Where `position` in window pixels is in the element's own units,
+    /// from the top left of its bbox. `parent` is the layout it's in.
+    pub fn local_position(
+        &self,
+        parent: Option<&String>,
+        position: Vec2,
+        layout_machine: &LayoutMachine,
+    ) -> Vec2 {
+        let bbox = match self {
+            ClickableBbox::Layout(id) => layout_machine.id_to_layout[id].bbox,
+            ClickableBbox::Bbox(bbox) => *bbox,
+        };
+        let own = self
+            .to_pixels(parent, layout_machine)
+            .inverse()
+            .transform_point3(position.extend(0.));
+        (own - bbox.transform_point3(Vec3::ZERO)).truncate()
+    }
+    /// Maps the element's own units to window pixels, like its triangles are drawn,
+    /// i.e. moved with `parent`, the layout it's in.
+    fn to_pixels(&self, parent: Option<&String>, layout_machine: &LayoutMachine) -> Mat4 {
+        let transform = match (self, parent) {
+            (ClickableBbox::Layout(id), _) | (ClickableBbox::Bbox(_), Some(id)) => {
+                layout_machine.calculate_layout(id)
+            }
+            (ClickableBbox::Bbox(_), None) => Mat4::IDENTITY,
+        };
+        layout_machine.display_mat4
+            * Mat4::from_translation([1., 1., 0.].into())
+            * Mat4::from_scale([1., -1., 1.].into())
+            * transform
+    }
+}
+
+/// The triangles of a clickable, which clicks are tested against instead of its bbox,
+/// so round buttons or icons with transparent corners only take clicks where they're drawn.
+#[derive(Debug, Clone, Default)]
+pub struct HitShape {
+    // Corners of every drawn triangle, in the element's own units
+    pub triangles: Vec<[Vec2; 3]>,
+    // Clicks this many pixels away from the triangles still hit, e.g. for thin strokes
+    pub tolerance: f32,
+}
+
+impl HitShape {
+    /// The triangles `geometries` draw, leaving out clip shapes.
+    pub fn from_geometries(geometries: &[Geometry], tolerance: f32) -> Self {
+        let geometry = geometries
+            .iter()
+            .fold(Geometry::default(), |acc, geometry| acc.extend(geometry));
+        let indices = &geometry.triangles.indices;
+        let draw_all = [DrawCommand::Draw(0..indices.len() as u32)];
+        let draw_commands = match geometry.draw_commands.is_empty() {
+            true => &draw_all[..],
+            false => &geometry.draw_commands[..],
+        };
+        let vertices = &geometry.triangles.vertices;
+        let corner = |index: u32| {
+            let position = vertices[index as usize].position;
+            Vec2::new(position[0], position[1])
+        };
+        let triangles = draw_commands
+            .iter()
+            .filter_map(|command| match command {
+                DrawCommand::Draw(range) => {
+                    Some(&indices[range.start as usize..range.end as usize])
+                }
+                _ => None,
+            })
+            .flat_map(|indices| indices.chunks_exact(3))
+            .map(|triangle| {
+                [
+                    corner(triangle[0]),
+                    corner(triangle[1]),
+                    corner(triangle[2]),
+                ]
+            })
+            .collect();
+        Self {
+            triangles,
+            tolerance,
+        }
+    }
+    /// The window pixels `contains` takes clicks in, if it has any triangles.
+    pub fn bounds(&self, to_pixels: Mat4) -> Option<Rect> {
+        let corners: Vec<Vec2> = self
+            .triangles
+            .iter()
+            .flatten()
+            .map(|corner| to_pixels.transform_point3(corner.extend(0.)).truncate())
+            .collect();
+        bounds_of(&corners).map(|rect| {
+            Rect::new(
+                rect.position - self.tolerance,
+                rect.size + 2. * self.tolerance,
+            )
+        })
+    }
+    /// Whether the triangles, mapped to pixels by `to_pixels`, cover `click`
+    /// or come within `tolerance` of it.
+    pub fn contains(&self, click: Vec2, to_pixels: Mat4) -> bool {
+        let pixels = |corner: Vec2| to_pixels.transform_point3(corner.extend(0.)).truncate();
+        self.triangles.iter().any(|triangle| {
+            let [a, b, c] = triangle.map(pixels);
+            let sides = [(a, b), (b, c), (c, a)];
+            let crosses = sides.map(|(from, to)| (to - from).perp_dot(click - from));
+            // Degenerate triangles only count by their sides
+            let inside = (b - a).perp_dot(c - a) != 0.
+                && (crosses.iter().all(|cross| *cross >= 0.)
+                    || crosses.iter().all(|cross| *cross <= 0.));
+            inside
+                || sides
+                    .iter()
+                    .any(|(from, to)| distance_to_segment(click, *from, *to) <= self.tolerance)
+        })
+    }
+}
+
+fn bounds_of(points: &[Vec2]) -> Option<Rect> {
+    let min = points.iter().copied().reduce(Vec2::min)?;
+    let max = points.iter().copied().reduce(Vec2::max)?;
+    Some(Rect::new(min, max - min))
+}
+
+fn distance_to_segment(point: Vec2, from: Vec2, to: Vec2) -> f32 {
+    let segment = to - from;
+    let length_squared = segment.length_squared();
+    if length_squared == 0. {
+        return point.distance(from);
+    }
+    let t = ((point - from).dot(segment) / length_squared).clamp(0., 1.);
+    point.distance(from + segment * t)
 }
 
 #[derive(Debug, Clone)]
 pub struct Clickable {
     pub bbox: ClickableBbox,
     pub id: String,
+    // Tested instead of the bbox when set, see `LayoutMachine::use_precise_click_detection`
+    pub shape: Option<HitShape>,
+    // The layout it's in, which pointer events propagate to, see `EventDispatcher`
+    pub parent: Option<String>,
+}
+
+impl Clickable {
+    pub fn click_detection(&self, click: Vec4, layout_machine: &LayoutMachine) -> bool {
+        match &self.shape {
+            Some(shape) => shape.contains(
+                Vec2::new(click.x, click.y),
+                self.bbox.to_pixels(self.parent.as_ref(), layout_machine),
+            ),
+            None => self.bbox.click_detection(click, layout_machine),
+        }
+    }
+    /// The window pixels `click_detection` takes clicks in, see `LayoutMachine::update_spatial_index`.
+    pub fn bounds(&self, layout_machine: &LayoutMachine) -> Option<Rect> {
+        match &self.shape {
+            Some(shape) => shape.bounds(self.bbox.to_pixels(self.parent.as_ref(), layout_machine)),
+            None => Some(self.bbox.bounds(layout_machine)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::constraint::{Constraint, XConstraint, YConstraint};
+    use super::super::layout::{bbox_to_mat4, Layout};
+    use super::*;
+    use guppies::primitives::{Triangles, Vertex};
+    use guppies::winit::dpi::PhysicalSize;
+    use salvage::usvg::PathBbox;
+
+    fn vertex(x: f32, y: f32) -> Vertex {
+        Vertex {
+            position: [x, y, 0.],
+            ..Default::default()
+        }
+    }
+
+    /// A 10 by 10 square of two triangles at the origin.
+    fn square() -> Geometry {
+        Geometry {
+            triangles: Triangles {
+                vertices: vec![
+                    vertex(0., 0.),
+                    vertex(10., 0.),
+                    vertex(10., 10.),
+                    vertex(0., 10.),
+                ],
+                indices: vec![0, 1, 2, 0, 2, 3],
+            },
+            ..Default::default()
+        }
+    }
+
+    fn shape(triangles: Vec<[Vec2; 3]>, tolerance: f32) -> HitShape {
+        HitShape {
+            triangles,
+            tolerance,
+        }
+    }
+
+    #[test]
+    fn contains_clicks_inside_either_winding() {
+        let [a, b, c] = [Vec2::ZERO, Vec2::new(10., 0.), Vec2::new(0., 10.)];
+        for triangle in [[a, b, c], [a, c, b]] {
+            let shape = shape(vec![triangle], 0.);
+            assert!(shape.contains(Vec2::new(2., 2.), Mat4::IDENTITY));
+            assert!(!shape.contains(Vec2::new(8., 8.), Mat4::IDENTITY));
+        }
+    }
+
+    #[test]
+    fn takes_clicks_within_the_tolerance() {
+        let triangle = [Vec2::ZERO, Vec2::new(10., 0.), Vec2::new(0., 10.)];
+        let shape = shape(vec![triangle], 2.);
+        assert!(shape.contains(Vec2::new(-1.5, 5.), Mat4::IDENTITY));
+        assert!(!shape.contains(Vec2::new(-2.5, 5.), Mat4::IDENTITY));
+    }
+
+    #[test]
+    fn degenerate_triangles_count_by_their_sides() {
+        // A line from (0, 0) to (10, 0), e.g. a hairline stroke
+        let line = [Vec2::ZERO, Vec2::new(10., 0.), Vec2::new(5., 0.)];
+        let shape = shape(vec![line], 1.);
+        assert!(shape.contains(Vec2::new(5., 0.5), Mat4::IDENTITY));
+        assert!(!shape.contains(Vec2::new(5., 1.5), Mat4::IDENTITY));
+        assert!(!shape.contains(Vec2::new(11.5, 0.), Mat4::IDENTITY));
+    }
+
+    #[test]
+    fn tests_in_pixels_after_the_transform() {
+        let shape = HitShape::from_geometries(&[square()], 1.);
+        let to_pixels =
+            Mat4::from_translation([100., 0., 0.].into()) * Mat4::from_scale([2., 2., 1.].into());
+        assert!(shape.contains(Vec2::new(119., 19.), to_pixels));
+        assert!(!shape.contains(Vec2::new(19., 19.), to_pixels));
+        // The tolerance is in pixels, not the element's units
+        assert!(shape.contains(Vec2::new(120.5, 10.), to_pixels));
+        assert!(!shape.contains(Vec2::new(121.5, 10.), to_pixels));
+    }
+
+    #[test]
+    fn leaves_out_clip_shapes() {
+        let mut clipped = square();
+        clipped.draw_commands = vec![DrawCommand::PushClip(0..3), DrawCommand::Draw(3..6)];
+        let shape = HitShape::from_geometries(&[clipped], 0.);
+        assert_eq!(shape.triangles.len(), 1);
+        // Only in the triangle under the diagonal from (0, 0) to (10, 10)
+        assert!(shape.contains(Vec2::new(2., 8.), Mat4::IDENTITY));
+        assert!(!shape.contains(Vec2::new(8., 2.), Mat4::IDENTITY));
+    }
+
+    #[test]
+    fn moves_shapes_with_their_layout() {
+        let mut layout_machine = LayoutMachine::default();
+        layout_machine.resize(&PhysicalSize::new(200, 200));
+        // The square's bbox, which the layout of the row pins to the bottom right
+        let bbox = bbox_to_mat4(PathBbox::new(0., 0., 10., 10.).unwrap());
+        let layout = Layout {
+            constraint: Constraint {
+                x: XConstraint::Right(-20.),
+                y: YConstraint::Bottom(-20.),
+            },
+            bbox,
+            parent: None,
+            wraps_text: false,
+        };
+        layout_machine
+            .id_to_layout
+            .insert("Row #layout".to_string(), layout);
+        let icon = Clickable {
+            bbox: ClickableBbox::Bbox(bbox),
+            id: "Icon #clickable".to_string(),
+            shape: Some(HitShape::from_geometries(&[square()], 0.)),
+            parent: Some("Row #layout".to_string()),
+        };
+        // Where the row is drawn, not where the square is in the SVG
+        let row = ClickableBbox::Layout("Row #layout".to_string()).bounds(&layout_machine);
+        let bounds = icon.bounds(&layout_machine).unwrap();
+        assert!(row.position.cmple(bounds.position).all());
+        assert!((bounds.position + bounds.size)
+            .cmple(row.position + row.size)
+            .all());
+        assert!(icon.click_detection(Vec4::new(150., 150., 1., 1.), &layout_machine));
+        assert!(!icon.click_detection(Vec4::new(5., 5., 1., 1.), &layout_machine));
+    }
+
+    #[test]
+    fn bounds_grow_by_the_tolerance() {
+        let shape = HitShape::from_geometries(&[square()], 1.);
+        let bounds = shape.bounds(Mat4::from_scale([2., 2., 1.].into())).unwrap();
+        assert_eq!(bounds.position, Vec2::new(-1., -1.));
+        assert_eq!(bounds.size, Vec2::new(22., 22.));
+        assert!(HitShape::default().bounds(Mat4::IDENTITY).is_none());
+    }
 }
@@ -77,6 +77,22 @@ impl Default for Constraint {
 }
 
 impl Constraint {
+    /// The constraint with stretching ones pinned to the start or center instead,
+    /// for elements which fit their content into the stretched box themselves, e.g. text.
+    pub fn without_stretch(self) -> Self {
+        Self {
+            x: match self.x {
+                XConstraint::LeftAndRight { left, .. } => XConstraint::Left(left),
+                XConstraint::Scale => XConstraint::Center(0.),
+                x => x,
+            },
+            y: match self.y {
+                YConstraint::TopAndBottom { top, .. } => YConstraint::Top(top),
+                YConstraint::Scale => YConstraint::Center(0.),
+                y => y,
+            },
+        }
+    }
     pub fn to_mat4(self, display: Mat4, bbox: Mat4, parent_bbox: Mat4) -> Mat4 {
         let Constraint {
             x: constraint_x,
@@ -0,0 +1,348 @@
+use super::layout_machine::LayoutMachine;
+use super::pointer::EventDispatcher;
+use super::pointer::PointerEvent;
+use super::pointer::PointerEventKind;
+use super::pointer::Selector;
+use crate::svg_init::PassDown;
+use crate::svg_init::CLICKABLE_REGEX;
+use crate::svg_init::STATE_REGEX;
+use guppies::winit::event::Event;
+use regex::Regex;
+use salvage::usvg::Node;
+use salvage::usvg::NodeExt;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+static CLICKABLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(CLICKABLE_REGEX).unwrap());
+static STATE: LazyLock<Regex> = LazyLock::new(|| Regex::new(STATE_REGEX).unwrap());
+
+/// Which variant of an element is shown, by the `#state=` tag Figma exports it with,
+/// e.g. `Button #state=hover`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InteractionState {
+    Default,
+    Hover,
+    Press,
+    Disabled,
+}
+
+impl InteractionState {
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "default" => Some(InteractionState::Default),
+            "hover" => Some(InteractionState::Hover),
+            "press" | "pressed" | "active" => Some(InteractionState::Press),
+            "disabled" => Some(InteractionState::Disabled),
+            _ => None,
+        }
+    }
+    // The states to show instead, when there's no variant for it
+    fn fallbacks(self) -> &'static [InteractionState] {
+        match self {
+            InteractionState::Default => &[InteractionState::Default],
+            InteractionState::Hover => &[InteractionState::Hover, InteractionState::Default],
+            InteractionState::Press => &[
+                InteractionState::Press,
+                InteractionState::Hover,
+                InteractionState::Default,
+            ],
+            InteractionState::Disabled => &[InteractionState::Disabled, InteractionState::Default],
+        }
+    }
+}
+
+/// Sibling variants of one element, of which one is shown at a time.
+#[derive(Debug, Clone)]
+struct VariantGroup {
+    // Ids, with their suffix
+    variants: HashMap<InteractionState, String>,
+    // Clickables whose pointer events change the state, the variants or the element around them
+    triggers: Vec<String>,
+    shown: String,
+    hovered: bool,
+    pressed: bool,
+    disabled: bool,
+}
+
+impl VariantGroup {
+    fn state(&self) -> InteractionState {
+        match (self.disabled, self.pressed, self.hovered) {
+            (true, _, _) => InteractionState::Disabled,
+            (false, true, _) => InteractionState::Press,
+            (false, false, true) => InteractionState::Hover,
+            (false, false, false) => InteractionState::Default,
+        }
+    }
+    fn variant(&self) -> &String {
+        self.state()
+            .fallbacks()
+            .iter()
+            .find_map(|state| self.variants.get(state))
+            .unwrap()
+    }
+    fn matches(&self, selector: &Selector) -> bool {
+        self.variants
+            .values()
+            .chain(&self.triggers)
+            .any(|id| selector.matches(id))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct VariantGroups {
+    groups: Vec<VariantGroup>,
+    trigger_to_groups: HashMap<String, Vec<usize>>,
+    // Ids to show or hide since `take_changed_visibility`
+    changed_visibility: Vec<(String, bool)>,
+}
+
+impl VariantGroups {
+    fn pointer_event(&mut self, event: &PointerEvent) {
+        let groups = match self.trigger_to_groups.get(&event.id) {
+            Some(groups) => groups.clone(),
+            None => return,
+        };
+        for i in groups {
+            let group = &mut self.groups[i];
+            match event.kind {
+                PointerEventKind::Enter => group.hovered = true,
+                PointerEventKind::Leave => {
+                    group.hovered = false;
+                    group.pressed = false;
+                }
+                PointerEventKind::Press => group.pressed = true,
+                PointerEventKind::Release => group.pressed = false,
+                PointerEventKind::Click => {}
+            }
+            self.show_variant(i);
+        }
+    }
+    fn show_variant(&mut self, i: usize) {
+        let group = &mut self.groups[i];
+        let variant = group.variant().clone();
+        if group.shown != variant {
+            let hidden = std::mem::replace(&mut group.shown, variant.clone());
+            self.changed_visibility.push((hidden, false));
+            self.changed_visibility.push((variant, true));
+        }
+    }
+}
+
+/// Shows the variant of each element matching how it's interacted with, e.g. its
+/// `#state=hover` sibling while the pointer is over its `#clickable`, so interactive
+/// states come from the design alone. Siblings with the same name before the tags are
+/// variants of one element, and the default is the one tagged `#state=default` or untagged.
+pub struct InteractionStates {
+    dispatcher: EventDispatcher<VariantGroups>,
+    groups: VariantGroups,
+}
+
+impl Default for InteractionStates {
+    fn default() -> Self {
+        let mut dispatcher = EventDispatcher::default();
+        for kind in [
+            PointerEventKind::Enter,
+            PointerEventKind::Leave,
+            PointerEventKind::Press,
+            PointerEventKind::Release,
+        ] {
+            dispatcher.add_handler(
+                Selector::Tag("clickable".to_string()),
+                kind,
+                |event, groups: &mut VariantGroups| groups.pointer_event(event),
+            );
+        }
+        Self {
+            dispatcher,
+            groups: VariantGroups::default(),
+        }
+    }
+}
+
+impl InteractionStates {
+    /// Finds the variants among the children of `node`, like `LayoutMachine::add_node`
+    /// in the callback of `use_svg`. All but the defaults start hidden.
+    pub fn add_node(&mut self, node: &Node, pass_down: &PassDown, id_suffix: Option<&str>) {
+        if !pass_down.is_include {
+            return;
+        }
+        let with_suffix =
+            |id: &str| id.to_string() + &id_suffix.map_or("".to_string(), |s| " ".to_owned() + s);
+        let mut names: Vec<String> = vec![];
+        let mut name_to_variants: HashMap<String, Vec<(Option<InteractionState>, String)>> =
+            HashMap::new();
+        for child in node.children() {
+            let id = child.id().to_string();
+            if id.is_empty() {
+                continue;
+            }
+            let state = match STATE.captures(&id) {
+                Some(captures) => match InteractionState::from_tag(&captures[1]) {
+                    Some(state) => Some(state),
+                    None => continue,
+                },
+                None => None,
+            };
+            let name = id.split(" #").next().unwrap_or_default().to_string();
+            if !name_to_variants.contains_key(&name) {
+                names.push(name.clone());
+            }
+            name_to_variants
+                .entry(name)
+                .or_default()
+                .push((state, with_suffix(&id)));
+        }
+        let parent_id = with_suffix(&node.id());
+        for name in names {
+            let siblings = &name_to_variants[&name];
+            if siblings.iter().all(|(state, _)| state.is_none()) {
+                continue;
+            }
+            let mut variants = HashMap::new();
+            for (state, id) in siblings.iter().rev() {
+                variants.insert(state.unwrap_or(InteractionState::Default), id.clone());
+            }
+            let default = variants
+                .entry(InteractionState::Default)
+                .or_insert_with(|| siblings[0].1.clone())
+                .clone();
+            let triggers: Vec<String> = siblings
+                .iter()
+                .map(|(_, id)| id)
+                .chain([&parent_id])
+                .filter(|id| CLICKABLE.is_match(id))
+                .cloned()
+                .collect();
+            let i = self.groups.groups.len();
+            for trigger in &triggers {
+                self.groups
+                    .trigger_to_groups
+                    .entry(trigger.clone())
+                    .or_default()
+                    .push(i);
+            }
+            self.groups.changed_visibility.extend(
+                siblings
+                    .iter()
+                    .filter(|(_, id)| *id != default)
+                    .map(|(_, id)| (id.clone(), false)),
+            );
+            self.groups.groups.push(VariantGroup {
+                variants,
+                triggers,
+                shown: default,
+                hovered: false,
+                pressed: false,
+                disabled: false,
+            });
+        }
+    }
+    /// Follows the pointer over the clickables, after `LayoutMachine::event_handler`.
+    pub fn event_handler<UserEvent>(
+        &mut self,
+        event: &Event<UserEvent>,
+        layout_machine: &LayoutMachine,
+    ) {
+        self.dispatcher
+            .event_handler(event, layout_machine, &mut self.groups);
+    }
+    /// Shows the `#state=disabled` variants of the elements with the id or name, or their
+    /// defaults again. Pointer events still reach handlers, which can check `state`.
+    pub fn set_disabled(&mut self, id: &str, disabled: bool) {
+        let selector = Selector::Id(id.to_string());
+        for i in 0..self.groups.groups.len() {
+            if self.groups.groups[i].matches(&selector) {
+                self.groups.groups[i].disabled = disabled;
+                self.groups.show_variant(i);
+            }
+        }
+    }
+    /// The state of the first element with the id or name, e.g. of one of its variants.
+    pub fn state(&self, id: &str) -> Option<InteractionState> {
+        let selector = Selector::Id(id.to_string());
+        self.groups
+            .groups
+            .iter()
+            .find(|group| group.matches(&selector))
+            .map(VariantGroup::state)
+    }
+    /// The variants to show or hide since the last call, for `SvgSet::set_visibility`.
+    /// Not for `LayoutMachine::set_visibility`: hidden variants go on taking clicks
+    /// for the element, or the pointer would leave a default it hid by hovering it.
+    pub fn take_changed_visibility(&mut self) -> Vec<(String, bool)> {
+        let changed = std::mem::take(&mut self.groups.changed_visibility);
+        // Only the last change of each id counts
+        let mut seen = HashSet::new();
+        let mut latest: Vec<(String, bool)> = changed
+            .into_iter()
+            .rev()
+            .filter(|(id, _)| seen.insert(id.clone()))
+            .collect();
+        latest.reverse();
+        latest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::responsive::clickable::{Clickable, ClickableBbox};
+    use guppies::glam::Mat4;
+    use guppies::winit::dpi::PhysicalPosition;
+    use guppies::winit::event::{DeviceId, WindowEvent};
+    use guppies::winit::window::WindowId;
+    use salvage::usvg::{Options, Tree};
+
+    fn cursor_moved(x: f64, y: f64) -> Event<()> {
+        // The dummies are only compared, never handed to the platform
+        Event::WindowEvent {
+            window_id: unsafe { WindowId::dummy() },
+            event: WindowEvent::CursorMoved {
+                device_id: unsafe { DeviceId::dummy() },
+                position: PhysicalPosition::new(x, y),
+            },
+        }
+    }
+
+    #[test]
+    fn hovering_a_clickable_default_shows_the_hover_variant_until_leaving() {
+        let xml = "<svg xmlns='http://www.w3.org/2000/svg'>\
+            <rect id='Button #clickable' width='1' height='1'/>\
+            <rect id='Button #state=hover' width='1' height='1'/></svg>";
+        let tree = Tree::from_str(xml, &Options::default().to_ref()).unwrap();
+        let mut states = InteractionStates::default();
+        states.add_node(&tree.root(), &PassDown::default(), None);
+        assert_eq!(
+            states.take_changed_visibility(),
+            [("Button #state=hover".to_string(), false)]
+        );
+        // Only the default takes clicks, from (0, 0) to (2, 2) in the default display
+        let mut layout_machine = LayoutMachine::default();
+        layout_machine.clickables.push(Clickable {
+            bbox: ClickableBbox::Bbox(Mat4::IDENTITY),
+            id: "Button #clickable".to_string(),
+            shape: None,
+            parent: None,
+        });
+        // Enter
+        states.event_handler(&cursor_moved(1., 1.), &layout_machine);
+        assert_eq!(states.state("Button"), Some(InteractionState::Hover));
+        assert_eq!(
+            states.take_changed_visibility(),
+            [
+                ("Button #clickable".to_string(), false),
+                ("Button #state=hover".to_string(), true)
+            ]
+        );
+        // Moves over the hidden default
+        for position in [1.2, 1.4] {
+            states.event_handler(&cursor_moved(position, position), &layout_machine);
+            assert_eq!(states.state("Button"), Some(InteractionState::Hover));
+            assert!(states.take_changed_visibility().is_empty());
+        }
+        // Leave
+        states.event_handler(&cursor_moved(3., 3.), &layout_machine);
+        assert_eq!(states.state("Button"), Some(InteractionState::Default));
+    }
+}
@@ -3,6 +3,7 @@ use guppies::glam::Mat4;
 use guppies::winit::dpi::PhysicalSize;
 use salvage::usvg::{self};
 use salvage::usvg::{NodeExt, PathBbox};
+use salvage::TextBox;
 
 pub(crate) fn size_to_mat4(size: PhysicalSize<u32>) -> Mat4 {
     Mat4::from_scale([size.width as f32, size.height as f32, 1.].into())
@@ -13,11 +14,45 @@ pub struct Layout {
     pub constraint: Constraint,
     pub bbox: Mat4,
     pub parent: Option<String>,
+    // Dynamic text is wrapped into its box instead of stretched
+    pub wraps_text: bool,
 }
 
 impl Layout {
     pub fn to_mat4(&self, display: Mat4, parent_bbox: Mat4) -> Mat4 {
-        self.constraint.to_mat4(display, self.bbox, parent_bbox)
+        let constraint = match self.wraps_text {
+            true => self.constraint.without_stretch(),
+            false => self.constraint,
+        };
+        constraint.to_mat4(display, self.bbox, parent_bbox)
+    }
+    /// The box the constraint would stretch the element to, in SVG units.
+    /// Assumes the element's own units are the SVG's, i.e. it isn't transformed.
+    pub fn text_box(&self, parent_bbox: Mat4) -> TextBox {
+        let (scale, _, translation) = self.bbox.to_scale_rotation_translation();
+        let x = self.constraint.x.to_transform(self.bbox, parent_bbox);
+        let y = self.constraint.y.to_transform(self.bbox, parent_bbox);
+        let stretch = (x * y).to_scale_rotation_translation().0;
+        TextBox {
+            x: translation.x,
+            width: scale.x * stretch.x,
+            height: Some(scale.y * stretch.y),
+        }
+    }
+    /// Makes the box `width` wide, keeping its edge or center at the text's `text-anchor`,
+    /// so the constraint aligns the text as it is now rather than as it was drawn.
+    pub fn hug_width(&mut self, width: f32, anchor: &str) {
+        let (scale, rotation, translation) = self.bbox.to_scale_rotation_translation();
+        let x = match anchor {
+            "middle" => translation.x + (scale.x - width) / 2.,
+            "end" => translation.x + scale.x - width,
+            _ => translation.x,
+        };
+        self.bbox = Mat4::from_scale_rotation_translation(
+            [width, scale.y, scale.z].into(),
+            rotation,
+            [x, translation.y, translation.z].into(),
+        );
     }
     pub fn new(node: &usvg::Node, constraint: Constraint) -> Self {
         let bbox_mat4 = bbox_to_mat4(
@@ -28,6 +63,7 @@ impl Layout {
             constraint,
             bbox: bbox_mat4,
             parent: None,
+            wraps_text: false,
         };
     }
 }
@@ -39,3 +75,43 @@ pub fn bbox_to_mat4(bbox: PathBbox) -> Mat4 {
         [bbox.x() as f32, bbox.y() as f32, 0.].into(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(x: f32, width: f32) -> Layout {
+        Layout {
+            constraint: Constraint::default(),
+            bbox: Mat4::from_scale_rotation_translation(
+                [width, 10., 1.].into(),
+                Default::default(),
+                [x, 5., 0.].into(),
+            ),
+            parent: None,
+            wraps_text: false,
+        }
+    }
+
+    fn x_and_width(layout: &Layout) -> (f32, f32) {
+        let (scale, _, translation) = layout.bbox.to_scale_rotation_translation();
+        (translation.x, scale.x)
+    }
+
+    #[test]
+    fn hugs_from_the_anchor() {
+        for (anchor, x) in [("start", 20.), ("middle", 35.), ("end", 50.)] {
+            let mut layout = layout(20., 60.);
+            layout.hug_width(30., anchor);
+            assert_eq!(x_and_width(&layout), (x, 30.), "{anchor}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_height() {
+        let mut layout = layout(0., 60.);
+        layout.hug_width(30., "start");
+        let (scale, _, translation) = layout.bbox.to_scale_rotation_translation();
+        assert_eq!((scale.y, translation.y), (10., 5.));
+    }
+}
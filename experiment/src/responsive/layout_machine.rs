@@ -1,25 +1,40 @@
 use super::clickable::Clickable;
 use super::clickable::ClickableBbox;
+use super::clickable::HitShape;
 use super::constraint::Constraint;
 use super::layout::bbox_to_mat4;
 use super::layout::size_to_mat4;
 use super::layout::Layout;
+use super::spatial_index::SpatialIndex;
 use crate::scroll::ScrollState;
 use crate::svg_init::PassDown;
 use crate::svg_init::CLICKABLE_REGEX;
+use crate::svg_init::DYNAMIC_TEXT_REGEX;
 use crate::svg_init::LAYOUT_REGEX;
 use core::fmt::Debug;
 use guppies::glam::Mat4;
+use guppies::glam::Vec2;
 use guppies::glam::Vec3;
 use guppies::glam::Vec4;
+use guppies::primitives::Rect;
+use guppies::primitives::TransformAttributes;
 use guppies::winit::dpi::PhysicalSize;
-use guppies::winit::event::ElementState;
 use guppies::winit::event::Event;
 use guppies::winit::event::WindowEvent;
 use regex::Regex;
+use salvage::measure::TextMetrics;
+use salvage::measure::TextStyle;
+use salvage::svg_set::SvgSet;
 use salvage::usvg::Node;
 use salvage::usvg::NodeExt;
+use salvage::TextBox;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+static CLICKABLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(CLICKABLE_REGEX).unwrap());
+static LAYOUT: LazyLock<Regex> = LazyLock::new(|| Regex::new(LAYOUT_REGEX).unwrap());
+static DYNAMIC_TEXT: LazyLock<Regex> = LazyLock::new(|| Regex::new(DYNAMIC_TEXT_REGEX).unwrap());
 
 pub type ConstraintMap = HashMap<String, Constraint>;
 
@@ -34,33 +49,36 @@ pub struct LayoutMachine {
     pub transforms: Vec<Mat4>,
     pub id_to_transform_index: HashMap<String, usize>,
     pub constraint_map: ConstraintMap,
+    pub text_boxes: HashMap<String, TextBox>,
+    // Looks of the layouts which aren't drawn as they are, see `get_attributes`
+    pub id_to_attributes: HashMap<String, TransformAttributes>,
+    // Ids of `text_boxes` which changed since `take_changed_text_boxes`
+    changed_text_boxes: Vec<String>,
+    // Bounds of `clickables` as of the last layout, see `update_spatial_index`
+    spatial_index: Option<SpatialIndex>,
+    // Ids hidden with `set_visibility`
+    hidden: HashSet<String>,
 }
 
 impl LayoutMachine {
-    pub fn event_handler(&mut self, event: &Event<()>) {
+    pub fn event_handler<UserEvent>(&mut self, event: &Event<UserEvent>) {
         self.scroll_state.event_handler(event);
-        if let guppies::winit::event::Event::WindowEvent { event, .. } = event {
-            match event {
-                WindowEvent::Resized(p) => {
-                    self.resize(p);
-                    let mut transforms = vec![Mat4::IDENTITY, Mat4::IDENTITY];
-                    transforms.append(&mut self.get_transforms());
-                    self.transforms = transforms;
-                }
-
-                WindowEvent::MouseInput {
-                    state: ElementState::Pressed,
-                    ..
-                } => {
-                    let clicked = self.click_detection();
-                    dbg!(&clicked);
-                }
-                _ => {}
-            }
+        if let guppies::winit::event::Event::WindowEvent {
+            event: WindowEvent::Resized(p),
+            ..
+        } = event
+        {
+            self.resize(p);
+            let mut transforms = vec![Mat4::IDENTITY, Mat4::IDENTITY];
+            transforms.append(&mut self.get_transforms());
+            self.transforms = transforms;
+            self.update_text_boxes();
+            self.update_spatial_index();
         }
     }
     pub fn resize(&mut self, p: &PhysicalSize<u32>) {
         self.display_mat4 = Mat4::from_scale([0.5, 0.5, 1.].into()) * size_to_mat4(*p);
+        self.spatial_index = None;
     }
     pub fn get_bbox_for(&self, element_name: String) -> Option<Mat4> {
         self.id_to_layout
@@ -73,7 +91,93 @@ impl LayoutMachine {
             .map(|id| self.calculate_layout(id))
             .collect()
     }
-    fn calculate_layout(&self, id: &String) -> Mat4 {
+    /// Hides, fades or tints the layout with the id and everything which moves with it,
+    /// without tessellating anything again.
+    pub fn set_attributes(&mut self, id: &str, attributes: TransformAttributes) {
+        self.id_to_attributes.insert(id.to_string(), attributes);
+    }
+    /// Keeps the clickable with the id, or the ones in the layout with the id, from taking
+    /// clicks while it's hidden, e.g. with `SvgSet::set_visibility`.
+    pub fn set_visibility(&mut self, id: &str, visible: bool) {
+        match visible {
+            true => self.hidden.remove(id),
+            false => self.hidden.insert(id.to_string()),
+        };
+    }
+    /// Whether the clickable or a layout it's in was hidden with `set_visibility`.
+    fn is_hidden(&self, clickable: &Clickable) -> bool {
+        let mut id = Some(&clickable.id);
+        let mut parent = clickable.parent.as_ref();
+        while let Some(current) = id {
+            if self.hidden.contains(current) {
+                return true;
+            }
+            id = parent;
+            parent = parent
+                .and_then(|parent| self.id_to_layout.get(parent))
+                .and_then(|layout| layout.parent.as_ref());
+        }
+        false
+    }
+    /// The attribute table matching `transforms`, for `GpuRedraw::update_attributes`.
+    pub fn get_attributes(&self) -> Vec<TransformAttributes> {
+        let mut attributes = vec![TransformAttributes::default(); 2];
+        attributes.extend(
+            self.layouts
+                .iter()
+                .map(|id| self.id_to_attributes.get(id).copied().unwrap_or_default()),
+        );
+        attributes
+    }
+    /// Works out the boxes of the dynamic texts with layouts again, e.g. after a resize.
+    pub fn update_text_boxes(&mut self) {
+        for id in &self.layouts {
+            let layout = &self.id_to_layout[id];
+            if !layout.wraps_text {
+                continue;
+            }
+            let parent_bbox = layout
+                .parent
+                .as_ref()
+                .map_or(self.get_display_bbox(), |parent| {
+                    self.calculate_layout_and_bbox(parent).1
+                });
+            let text_box = layout.text_box(parent_bbox);
+            if self.text_boxes.get(id) != Some(&text_box) {
+                self.text_boxes.insert(id.clone(), text_box);
+                self.changed_text_boxes.push(id.clone());
+            }
+        }
+    }
+    /// The text boxes which changed since the last call, for `SvgSet::set_text_box`
+    /// to wrap their texts again.
+    pub fn take_changed_text_boxes(&mut self) -> Vec<(String, TextBox)> {
+        let mut changed = std::mem::take(&mut self.changed_text_boxes);
+        changed.dedup();
+        changed
+            .into_iter()
+            .map(|id| {
+                let text_box = self.text_boxes[&id];
+                (id, text_box)
+            })
+            .collect()
+    }
+    /// Sizes the layout with the id to the width of its text, measured with
+    /// `SvgSet::measure_text`, e.g. after `SvgSet::update_text`. Dynamic text is wrapped
+    /// into its box instead. Takes effect on the next `get_transforms`.
+    pub fn hug_text(&mut self, id: &str, style: &TextStyle, metrics: &TextMetrics) {
+        if let Some(layout) = self.id_to_layout.get_mut(id) {
+            if !layout.wraps_text {
+                layout.hug_width(metrics.width, &style.anchor);
+                self.spatial_index = None;
+            }
+        }
+    }
+    pub(crate) fn calculate_layout(&self, id: &String) -> Mat4 {
+        Mat4::from_scale([2., -2., 1.].into()) * self.calculate_layout_and_bbox(id).0
+    }
+    /// The transform of the layout with the id, and its bbox after the transform.
+    fn calculate_layout_and_bbox(&self, id: &String) -> (Mat4, Mat4) {
         let mut next_parent_name = Some(id);
         let mut parent_layouts = [].to_vec();
         while let Some(current_parent) = next_parent_name {
@@ -84,21 +188,16 @@ impl LayoutMachine {
             next_parent_name = next_parent.parent.as_ref();
             parent_layouts.push(next_parent)
         }
-        Mat4::from_scale([2., -2., 1.].into())
-            * parent_layouts
-                .iter()
-                .rev()
-                .fold(
-                    (Mat4::IDENTITY, self.get_display_bbox()),
-                    |(_parent_result, parent_bbox), layout| {
-                        let layout_result = layout.to_mat4(self.display_mat4, parent_bbox);
-                        (
-                            layout_result,
-                            self.display_mat4 * layout_result * layout.bbox,
-                        )
-                    },
+        parent_layouts.iter().rev().fold(
+            (Mat4::IDENTITY, self.get_display_bbox()),
+            |(_parent_result, parent_bbox), layout| {
+                let layout_result = layout.to_mat4(self.display_mat4, parent_bbox);
+                (
+                    layout_result,
+                    self.display_mat4 * layout_result * layout.bbox,
                 )
-                .0
+            },
+        )
     }
     fn get_display_bbox(&self) -> Mat4 {
         let (scale, rot, _trans) = self.display_mat4.to_scale_rotation_translation();
@@ -113,31 +212,116 @@ impl LayoutMachine {
         )
     }
 
+    /// Indexes where the clickables are in the window, so clicks only test the ones nearby.
+    /// Runs on every resize. Moving layouts, e.g. with `hug_text`, or adding nodes drops it,
+    /// and changes to `id_to_layout` or `constraint_map` made directly need this called
+    /// again; until then, clicks test every clickable.
+    pub fn update_spatial_index(&mut self) {
+        self.spatial_index = Some(self.build_spatial_index());
+    }
+    fn build_spatial_index(&self) -> SpatialIndex {
+        SpatialIndex::new(
+            self.clickables
+                .iter()
+                .map(|clickable| clickable.bounds(self))
+                .collect(),
+        )
+    }
+    /// The index, unless clickables were pushed onto `clickables` since it was built.
+    fn fresh_spatial_index(&self) -> Option<&SpatialIndex> {
+        self.spatial_index
+            .as_ref()
+            .filter(|index| index.len() == self.clickables.len())
+    }
     pub fn click_detection(&self) -> Vec<String> {
-        let click = Vec4::from((self.scroll_state.mouse_position, 1., 1.));
-        let clicked_ids = self
-            .clickables
-            .iter()
-            .filter_map(|clickable| {
-                if clickable.bbox.click_detection(click, &self) {
-                    Some(clickable.id.clone())
-                } else {
-                    None
+        self.clickables_at(self.scroll_state.mouse_position)
+            .into_iter()
+            .map(|clickable| clickable.id.clone())
+            .collect()
+    }
+    /// The clickables which take clicks at `position` in window pixels, see `EventDispatcher`.
+    pub fn clickables_at(&self, position: Vec2) -> Vec<&Clickable> {
+        let click = Vec4::from((position, 1., 1.));
+        let candidates = match self.fresh_spatial_index() {
+            Some(index) => index.query_point(position),
+            None => (0..self.clickables.len()).collect(),
+        };
+        candidates
+            .into_iter()
+            .map(|i| &self.clickables[i])
+            .filter(|clickable| !self.is_hidden(clickable))
+            .filter(|clickable| clickable.click_detection(click, self))
+            .collect()
+    }
+    /// The deepest clickable at `position` in window pixels and the layouts it's in,
+    /// innermost first. Of clickables as deep, the one added last wins, as it's drawn on top.
+    pub fn hit_path(&self, position: Vec2) -> Vec<String> {
+        self.clickables_at(position)
+            .into_iter()
+            .map(|clickable| {
+                let mut path = vec![clickable.id.clone()];
+                let mut parent = clickable.parent.as_ref();
+                while let Some(id) = parent {
+                    path.push(id.clone());
+                    parent = self
+                        .id_to_layout
+                        .get(id)
+                        .and_then(|layout| layout.parent.as_ref());
                 }
+                path
+            })
+            .reduce(|deepest, path| match path.len() >= deepest.len() {
+                true => path,
+                false => deepest,
             })
-            .collect::<Vec<String>>();
-        clicked_ids
+            .unwrap_or_default()
+    }
+    /// Ids of the clickables whose bounds overlap `rect` in window pixels, e.g. for marquee selection.
+    pub fn clickables_in(&self, rect: &Rect) -> Vec<String> {
+        let items = match self.fresh_spatial_index() {
+            Some(index) => index.query_rect(rect),
+            None => self.build_spatial_index().query_rect(rect),
+        };
+        items
+            .into_iter()
+            .map(|i| &self.clickables[i])
+            .filter(|clickable| !self.is_hidden(clickable))
+            .map(|clickable| clickable.id.clone())
+            .collect()
+    }
+    /// Tests clicks on the clickables drawn by `svg_set` against their triangles instead of
+    /// their bboxes, and within `tolerance` pixels of them, e.g. for elements which are only
+    /// stroked. `id_suffix` is the one their nodes were added with.
+    /// Needs calling again when their shapes change, e.g. after `SvgSet::set_path_data`.
+    pub fn use_precise_click_detection(
+        &mut self,
+        svg_set: &SvgSet,
+        id_suffix: Option<&str>,
+        tolerance: f32,
+    ) {
+        for clickable in self.clickables.iter_mut() {
+            let id = match id_suffix {
+                Some(suffix) => clickable.id.strip_suffix(&format!(" {}", suffix)),
+                None => Some(clickable.id.as_str()),
+            };
+            if let Some(range) = id.and_then(|id| svg_set.id_to_geometry_range.get(id)) {
+                clickable.shape = Some(HitShape::from_geometries(
+                    &svg_set.geometries[range.clone()],
+                    tolerance,
+                ));
+            }
+        }
+        self.update_spatial_index();
     }
     pub fn add_node(&mut self, node: &Node, pass_down: &mut PassDown, id_suffix: Option<&str>) {
         if !pass_down.is_include {
             return;
         }
-        let clickable_regex = Regex::new(CLICKABLE_REGEX).unwrap();
-        let layout_regex = Regex::new(LAYOUT_REGEX).unwrap();
+        self.spatial_index = None;
         let id = node.id().to_string();
         let id_with_suffix =
             id.clone() + &id_suffix.map_or("".to_string(), |suffix| " ".to_owned() + suffix);
-        match layout_regex.is_match(&id_with_suffix) {
+        match LAYOUT.is_match(&id_with_suffix) {
             true => {
                 let constraint = self
                     .constraint_map
@@ -145,6 +329,7 @@ impl LayoutMachine {
                     .expect(&(id + "not in constraints.json"))
                     .clone();
                 let mut layout = Layout::new(&node, constraint);
+                layout.wraps_text = DYNAMIC_TEXT.is_match(&id_with_suffix);
 
                 layout.parent = pass_down.parent.clone();
                 let some_id_with_suffix = (!id_with_suffix.is_empty()).then(|| &id_with_suffix);
@@ -154,20 +339,24 @@ impl LayoutMachine {
                         .insert(id_with_suffix.clone(), layout.clone());
                     pass_down.parent = Some(id_with_suffix.clone());
                 };
-                if clickable_regex.is_match(&id_with_suffix) {
+                if CLICKABLE.is_match(&id_with_suffix) {
                     let clickable = Clickable {
                         bbox: ClickableBbox::Layout(id_with_suffix.to_string()),
                         id: id_with_suffix.to_string(),
+                        shape: None,
+                        parent: layout.parent,
                     };
                     self.clickables.push(clickable)
                 }
             }
             false => {
-                if clickable_regex.is_match(&id_with_suffix) {
+                if CLICKABLE.is_match(&id_with_suffix) {
                     let bbox_mat4 = bbox_to_mat4(node.calculate_bbox().unwrap());
                     let clickable = Clickable {
                         bbox: ClickableBbox::Bbox(bbox_mat4),
                         id: id_with_suffix,
+                        shape: None,
+                        parent: pass_down.parent.clone(),
                     };
                     self.clickables.push(clickable)
                 }
@@ -1,5 +1,8 @@
 pub mod clickable;
 pub mod common_constraint;
 pub mod constraint;
+pub mod interaction_state;
 pub mod layout;
 pub mod layout_machine;
+pub mod pointer;
+pub mod spatial_index;
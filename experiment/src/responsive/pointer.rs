@@ -0,0 +1,451 @@
+use super::clickable::ClickableBbox;
+use super::layout_machine::LayoutMachine;
+use crate::scroll::UNMOVED_RADIUS;
+use guppies::glam::Vec2;
+use guppies::winit::event::ElementState;
+use guppies::winit::event::Event;
+use guppies::winit::event::TouchPhase;
+use guppies::winit::event::WindowEvent;
+use std::collections::HashMap;
+
+/// What happened to an element under a pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PointerEventKind {
+    // Pressed and released on the element without moving further than a tap
+    Click,
+    Press,
+    Release,
+    // Enter and leave go to each element the pointer comes in or out of, without propagating
+    Enter,
+    Leave,
+}
+
+/// The mouse, or a finger on a touch screen by its touch id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pointer {
+    Mouse,
+    Touch(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct PointerEvent {
+    pub kind: PointerEventKind,
+    pub pointer: Pointer,
+    // Id of the element the handlers are called for, the target or a layout it's in
+    pub id: String,
+    // Id of the deepest clickable hit, which the event propagates up from
+    pub target: String,
+    // In window pixels
+    pub position: Vec2,
+    // In the element's own units, from the top left of its bbox
+    pub local_position: Vec2,
+    propagation_stopped: bool,
+}
+
+impl PointerEvent {
+    fn new(kind: PointerEventKind, pointer: Pointer, target: &str, position: Vec2) -> Self {
+        Self {
+            kind,
+            pointer,
+            id: target.to_string(),
+            target: target.to_string(),
+            position,
+            local_position: Vec2::ZERO,
+            propagation_stopped: false,
+        }
+    }
+    /// Keeps the event from the elements it would go to next, once the handlers for this
+    /// element ran, e.g. so a click on a list item's delete icon doesn't also select the row.
+    /// In the capture phase, it never reaches the target and doesn't bubble either.
+    pub fn stop_propagation(&mut self) {
+        self.propagation_stopped = true;
+    }
+}
+
+/// Which clickables a handler is for.
+#[derive(Debug, Clone)]
+pub enum Selector {
+    // The whole id, or its name before the tags, e.g. `Menu` for `Menu #transform #clickable`
+    Id(String),
+    // A tag in the id without its `#`, e.g. `clickable`
+    Tag(String),
+}
+
+impl Selector {
+    pub fn matches(&self, id: &str) -> bool {
+        match self {
+            Selector::Id(name) => id == name || id.split(" #").next() == Some(name),
+            // The words after each `#`, up to the next space or `#`
+            Selector::Tag(tag) => id
+                .split('#')
+                .skip(1)
+                .any(|tagged| tagged.split(' ').next() == Some(tag.as_str())),
+        }
+    }
+}
+
+type Handler<State> = Box<dyn FnMut(&mut PointerEvent, &mut State)>;
+
+#[derive(Debug, Clone, Default)]
+struct PointerState {
+    position: Vec2,
+    // The deepest clickable under the pointer and the layouts it's in, see `LayoutMachine::hit_path`
+    hovered: Vec<String>,
+    // Where it was pressed and what was under it then
+    pressed: Option<(Vec2, Vec<String>)>,
+}
+
+/// Turns mouse and touch events into `PointerEvent`s on the clickables of a `LayoutMachine`,
+/// and calls the handlers added for them with the app's `State`.
+/// Clicks, presses and releases go down from the outermost layout to the deepest clickable
+/// hit through the capture handlers, then back up through the others.
+/// Only the deepest clickable under the pointer and the layouts it's in get events, so of
+/// overlapping siblings only the one on top does, see `LayoutMachine::hit_path`.
+/// `LayoutMachine::click_detection` still lists every clickable under the mouse.
+pub struct EventDispatcher<State> {
+    handlers: Vec<(Selector, PointerEventKind, Handler<State>)>,
+    capture_handlers: Vec<(Selector, PointerEventKind, Handler<State>)>,
+    pointers: HashMap<Pointer, PointerState>,
+}
+
+impl<State> Default for EventDispatcher<State> {
+    fn default() -> Self {
+        Self {
+            handlers: vec![],
+            capture_handlers: vec![],
+            pointers: HashMap::new(),
+        }
+    }
+}
+
+impl<State> EventDispatcher<State> {
+    pub fn add_handler(
+        &mut self,
+        selector: Selector,
+        kind: PointerEventKind,
+        handler: impl FnMut(&mut PointerEvent, &mut State) + 'static,
+    ) {
+        self.handlers.push((selector, kind, Box::new(handler)));
+    }
+    /// Adds a handler which gets events on their way down to the target, before the ones
+    /// of `add_handler` get them on their way up, e.g. for a list to take clicks from its rows.
+    pub fn add_capture_handler(
+        &mut self,
+        selector: Selector,
+        kind: PointerEventKind,
+        handler: impl FnMut(&mut PointerEvent, &mut State) + 'static,
+    ) {
+        self.capture_handlers
+            .push((selector, kind, Box::new(handler)));
+    }
+    /// Calls the handlers for what `event` did to the clickables. Goes after
+    /// `LayoutMachine::event_handler`, so clicks are tested against the current layout.
+    pub fn event_handler<UserEvent>(
+        &mut self,
+        event: &Event<UserEvent>,
+        layout_machine: &LayoutMachine,
+        state: &mut State,
+    ) {
+        let event = match event {
+            Event::WindowEvent { event, .. } => event,
+            _ => return,
+        };
+        // Each with the ids it propagates through
+        let mut events: Vec<(PointerEvent, Vec<String>)> = vec![];
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                let position = Vec2::new(position.x as f32, position.y as f32);
+                self.pointer_moved(Pointer::Mouse, position, layout_machine, &mut events);
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.pointer_left(Pointer::Mouse, &mut events);
+            }
+            WindowEvent::MouseInput { state, .. } => {
+                let position = match self.pointers.get(&Pointer::Mouse) {
+                    Some(pointer_state) => pointer_state.position,
+                    // Outside the window, as the cursor left it or hasn't moved in yet
+                    None => return,
+                };
+                match state {
+                    ElementState::Pressed => {
+                        self.pointer_pressed(Pointer::Mouse, position, layout_machine, &mut events)
+                    }
+                    ElementState::Released => {
+                        self.pointer_released(Pointer::Mouse, position, layout_machine, &mut events)
+                    }
+                }
+            }
+            WindowEvent::Touch(touch) => {
+                let pointer = Pointer::Touch(touch.id);
+                let position = Vec2::new(touch.location.x as f32, touch.location.y as f32);
+                match touch.phase {
+                    TouchPhase::Started => {
+                        self.pointer_pressed(pointer, position, layout_machine, &mut events);
+                    }
+                    TouchPhase::Moved => {
+                        self.pointer_moved(pointer, position, layout_machine, &mut events);
+                    }
+                    TouchPhase::Ended => {
+                        self.pointer_released(pointer, position, layout_machine, &mut events);
+                        self.pointer_left(pointer, &mut events);
+                    }
+                    TouchPhase::Cancelled => {
+                        self.pointer_left(pointer, &mut events);
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.dispatch(events, layout_machine, state);
+    }
+    /// Calls the handlers for each event along the ids it propagates through,
+    /// which start at its target.
+    fn dispatch(
+        &mut self,
+        events: Vec<(PointerEvent, Vec<String>)>,
+        layout_machine: &LayoutMachine,
+        state: &mut State,
+    ) {
+        for (mut event, path) in events {
+            // Down to the target through the capture handlers, then back up through the others
+            let capture = path.iter().rev().map(|id| (id, true));
+            let bubble = path.iter().map(|id| (id, false));
+            for (id, captures) in capture.chain(bubble) {
+                event.local_position = local_position(id, event.position, layout_machine);
+                event.id = id.clone();
+                let handlers = match captures {
+                    true => &mut self.capture_handlers,
+                    false => &mut self.handlers,
+                };
+                for (selector, kind, handler) in handlers.iter_mut() {
+                    if *kind == event.kind && selector.matches(&event.id) {
+                        handler(&mut event, state);
+                    }
+                }
+                if event.propagation_stopped {
+                    break;
+                }
+            }
+        }
+    }
+    fn pointer_moved(
+        &mut self,
+        pointer: Pointer,
+        position: Vec2,
+        layout_machine: &LayoutMachine,
+        events: &mut Vec<(PointerEvent, Vec<String>)>,
+    ) {
+        let hovered = layout_machine.hit_path(position);
+        let pointer_state = self.pointers.entry(pointer).or_default();
+        pointer_state.position = position;
+        let left = pointer_state
+            .hovered
+            .iter()
+            .filter(|id| !hovered.contains(id));
+        let entered = hovered
+            .iter()
+            .filter(|id| !pointer_state.hovered.contains(id));
+        for (kind, ids) in [
+            (PointerEventKind::Leave, left.collect::<Vec<_>>()),
+            (PointerEventKind::Enter, entered.collect()),
+        ] {
+            events.extend(ids.into_iter().map(|id| {
+                (
+                    PointerEvent::new(kind, pointer, id, position),
+                    vec![id.clone()],
+                )
+            }));
+        }
+        pointer_state.hovered = hovered;
+    }
+    fn pointer_left(&mut self, pointer: Pointer, events: &mut Vec<(PointerEvent, Vec<String>)>) {
+        if let Some(pointer_state) = self.pointers.remove(&pointer) {
+            events.extend(pointer_state.hovered.iter().map(|id| {
+                (
+                    PointerEvent::new(PointerEventKind::Leave, pointer, id, pointer_state.position),
+                    vec![id.clone()],
+                )
+            }));
+        }
+    }
+    fn pointer_pressed(
+        &mut self,
+        pointer: Pointer,
+        position: Vec2,
+        layout_machine: &LayoutMachine,
+        events: &mut Vec<(PointerEvent, Vec<String>)>,
+    ) {
+        // The layout may have moved since the pointer did
+        self.pointer_moved(pointer, position, layout_machine, events);
+        let pointer_state = self.pointers.entry(pointer).or_default();
+        let path = pointer_state.hovered.clone();
+        if let Some(target) = path.first() {
+            events.push((
+                PointerEvent::new(PointerEventKind::Press, pointer, target, position),
+                path.clone(),
+            ));
+        }
+        pointer_state.pressed = Some((position, path));
+    }
+    fn pointer_released(
+        &mut self,
+        pointer: Pointer,
+        position: Vec2,
+        layout_machine: &LayoutMachine,
+        events: &mut Vec<(PointerEvent, Vec<String>)>,
+    ) {
+        self.pointer_moved(pointer, position, layout_machine, events);
+        let pointer_state = self.pointers.entry(pointer).or_default();
+        let path = pointer_state.hovered.clone();
+        if let Some(target) = path.first() {
+            events.push((
+                PointerEvent::new(PointerEventKind::Release, pointer, target, position),
+                path.clone(),
+            ));
+        }
+        if let Some((pressed_position, pressed)) = pointer_state.pressed.take() {
+            if UNMOVED_RADIUS > position.distance(pressed_position) {
+                // From the deepest element it was both pressed and released on
+                let start = path.iter().position(|id| pressed.contains(id));
+                if let Some(start) = start {
+                    events.push((
+                        PointerEvent::new(PointerEventKind::Click, pointer, &path[start], position),
+                        path[start..].to_vec(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn local_position(id: &str, position: Vec2, layout_machine: &LayoutMachine) -> Vec2 {
+    match layout_machine
+        .clickables
+        .iter()
+        .find(|clickable| clickable.id == id)
+    {
+        Some(clickable) => {
+            clickable
+                .bbox
+                .local_position(clickable.parent.as_ref(), position, layout_machine)
+        }
+        None if layout_machine.id_to_layout.contains_key(id) => {
+            ClickableBbox::Layout(id.to_string()).local_position(None, position, layout_machine)
+        }
+        None => Vec2::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A delete icon in a row of a list
+    const PATH: [&str; 3] = ["Delete #clickable", "Row #layout", "List #layout"];
+
+    fn click() -> Vec<(PointerEvent, Vec<String>)> {
+        let event = PointerEvent::new(PointerEventKind::Click, Pointer::Mouse, PATH[0], Vec2::ZERO);
+        vec![(event, PATH.map(str::to_string).to_vec())]
+    }
+
+    /// A dispatcher which logs the elements the handlers were called for, by phase.
+    fn dispatcher(stop_at: Option<&'static str>) -> EventDispatcher<Vec<String>> {
+        let mut dispatcher = EventDispatcher::default();
+        for name in ["Delete", "Row", "List"] {
+            let log = move |phase: &'static str| {
+                move |event: &mut PointerEvent, log: &mut Vec<String>| {
+                    log.push(format!("{phase} {name}"));
+                    if stop_at == Some(&*format!("{phase} {name}")) {
+                        event.stop_propagation();
+                    }
+                }
+            };
+            let selector = Selector::Id(name.to_string());
+            dispatcher.add_capture_handler(
+                selector.clone(),
+                PointerEventKind::Click,
+                log("capture"),
+            );
+            dispatcher.add_handler(selector, PointerEventKind::Click, log("bubble"));
+        }
+        dispatcher
+    }
+
+    fn dispatch(mut dispatcher: EventDispatcher<Vec<String>>) -> Vec<String> {
+        let mut log = vec![];
+        dispatcher.dispatch(click(), &LayoutMachine::default(), &mut log);
+        log
+    }
+
+    #[test]
+    fn captures_down_then_bubbles_up() {
+        assert_eq!(
+            dispatch(dispatcher(None)),
+            [
+                "capture List",
+                "capture Row",
+                "capture Delete",
+                "bubble Delete",
+                "bubble Row",
+                "bubble List"
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_bubbling() {
+        assert_eq!(
+            dispatch(dispatcher(Some("bubble Delete"))),
+            [
+                "capture List",
+                "capture Row",
+                "capture Delete",
+                "bubble Delete"
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_capturing() {
+        assert_eq!(
+            dispatch(dispatcher(Some("capture Row"))),
+            ["capture List", "capture Row"]
+        );
+    }
+
+    #[test]
+    fn runs_every_handler_of_the_element_which_stops() {
+        let mut dispatcher = dispatcher(Some("bubble Row"));
+        dispatcher.add_handler(
+            Selector::Tag("layout".to_string()),
+            PointerEventKind::Click,
+            |event, log: &mut Vec<String>| log.push(format!("tag {}", event.id)),
+        );
+        assert_eq!(
+            dispatch(dispatcher)[3..],
+            ["bubble Delete", "bubble Row", "tag Row #layout"]
+        );
+    }
+
+    #[test]
+    fn only_calls_handlers_of_the_kind() {
+        let mut dispatcher = EventDispatcher::default();
+        dispatcher.add_handler(
+            Selector::Tag("clickable".to_string()),
+            PointerEventKind::Press,
+            |_, log: &mut Vec<String>| log.push("press".to_string()),
+        );
+        assert!(dispatch(dispatcher).is_empty());
+    }
+
+    #[test]
+    fn matches_tags_as_whole_words() {
+        let tag = |tag: &str| Selector::Tag(tag.to_string());
+        assert!(tag("clickable").matches("Menu #transform #clickable #layout"));
+        assert!(tag("clickable").matches("Menu #transform#clickable"));
+        assert!(tag("layout").matches("ListItem #layout 0"));
+        assert!(!tag("click").matches("Menu #clickable"));
+        assert!(!tag("Menu").matches("Menu #clickable"));
+        assert!(Selector::Id("Menu".to_string()).matches("Menu #clickable"));
+    }
+}
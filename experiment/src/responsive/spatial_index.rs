@@ -0,0 +1,224 @@
+use guppies::glam::Vec2;
+use guppies::primitives::Rect;
+use std::collections::HashMap;
+
+// Items spanning more cells are kept apart and tested on every query, e.g. backgrounds
+const MAX_CELLS_PER_ITEM: usize = 64;
+
+fn contains(bounds: &Rect, point: Vec2) -> bool {
+    let end = bounds.position + bounds.size;
+    bounds.position.x <= point.x
+        && bounds.position.y <= point.y
+        && point.x <= end.x
+        && point.y <= end.y
+}
+
+/// `rect` with its position at the top left, e.g. for a marquee dragged up or left.
+fn normalized(rect: &Rect) -> Rect {
+    let end = rect.position + rect.size;
+    Rect::new(rect.position.min(end), rect.size.abs())
+}
+
+/// How many cells are between the corner cells, which can be more than an i32 holds.
+fn cell_count(((x0, y0), (x1, y1)): ((i32, i32), (i32, i32))) -> i64 {
+    (x1 as i64 - x0 as i64 + 1).saturating_mul(y1 as i64 - y0 as i64 + 1)
+}
+
+fn intersects(bounds: &Rect, other: &Rect) -> bool {
+    let end = bounds.position + bounds.size;
+    let other_end = other.position + other.size;
+    bounds.position.x <= other_end.x
+        && bounds.position.y <= other_end.y
+        && other.position.x <= end.x
+        && other.position.y <= end.y
+}
+
+/// Bounds of items, e.g. clickables in window pixels, bucketed into a uniform grid
+/// so point and rectangle queries only test the items nearby.
+/// Items are referred to by their index in the bounds it was built from,
+/// and ones without bounds are never found.
+#[derive(Debug, Clone, Default)]
+pub struct SpatialIndex {
+    bounds: Vec<Rect>,
+    // Side of a cell, the average size of the items
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+    large: Vec<usize>,
+}
+
+impl SpatialIndex {
+    pub fn new(bounds: Vec<Option<Rect>>) -> Self {
+        let bounds: Vec<Option<Rect>> = bounds
+            .into_iter()
+            .map(|rect| {
+                rect.filter(|rect| rect.position.is_finite() && rect.size.is_finite())
+                    .map(|rect| normalized(&rect))
+            })
+            .collect();
+        let sizes: Vec<f32> = bounds
+            .iter()
+            .flatten()
+            .map(|rect| rect.size.max_element())
+            .collect();
+        let cell_size = (sizes.iter().sum::<f32>() / sizes.len().max(1) as f32).max(1.);
+        let mut index = Self {
+            bounds: vec![],
+            cell_size,
+            cells: HashMap::new(),
+            large: vec![],
+        };
+        for (i, rect) in bounds.iter().enumerate() {
+            let rect = match rect {
+                Some(rect) => rect,
+                None => continue,
+            };
+            let ((x0, y0), (x1, y1)) = index.cell_range(rect);
+            if cell_count(((x0, y0), (x1, y1))) > MAX_CELLS_PER_ITEM as i64 {
+                index.large.push(i);
+                continue;
+            }
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    index.cells.entry((x, y)).or_default().push(i);
+                }
+            }
+        }
+        index.bounds = bounds.into_iter().map(Option::unwrap_or_default).collect();
+        index
+    }
+    /// How many items it was built from.
+    pub fn len(&self) -> usize {
+        self.bounds.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_empty()
+    }
+    fn cell(&self, point: Vec2) -> (i32, i32) {
+        let cell = (point / self.cell_size).floor();
+        (cell.x as i32, cell.y as i32)
+    }
+    fn cell_range(&self, rect: &Rect) -> ((i32, i32), (i32, i32)) {
+        (
+            self.cell(rect.position),
+            self.cell(rect.position + rect.size),
+        )
+    }
+    /// Items whose bounds contain `point`, in order.
+    pub fn query_point(&self, point: Vec2) -> Vec<usize> {
+        if !point.is_finite() {
+            return vec![];
+        }
+        let mut items: Vec<usize> = self
+            .cells
+            .get(&self.cell(point))
+            .into_iter()
+            .flatten()
+            .chain(&self.large)
+            .copied()
+            .filter(|i| contains(&self.bounds[*i], point))
+            .collect();
+        items.sort_unstable();
+        items
+    }
+    /// Items whose bounds intersect `rect`, in order, e.g. for marquee selection.
+    /// `rect` may have a negative size.
+    pub fn query_rect(&self, rect: &Rect) -> Vec<usize> {
+        if !rect.position.is_finite() || !rect.size.is_finite() {
+            return vec![];
+        }
+        let rect = &normalized(rect);
+        let ((x0, y0), (x1, y1)) = self.cell_range(rect);
+        let mut items: Vec<usize> = match cell_count(((x0, y0), (x1, y1))) > self.cells.len() as i64
+        {
+            // Larger than the occupied cells, so walking them is cheaper
+            true => self.cells.values().flatten().copied().collect(),
+            false => (x0..=x1)
+                .flat_map(|x| (y0..=y1).map(move |y| (x, y)))
+                .filter_map(|cell| self.cells.get(&cell))
+                .flatten()
+                .copied()
+                .collect(),
+        };
+        items.extend(&self.large);
+        items.sort_unstable();
+        items.dedup();
+        items.retain(|i| intersects(&self.bounds[*i], rect));
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect::new(Vec2::new(x, y), Vec2::new(width, height))
+    }
+
+    #[test]
+    fn finds_items_at_a_point() {
+        let index = SpatialIndex::new(vec![
+            Some(rect(0., 0., 10., 10.)),
+            Some(rect(5., 5., 10., 10.)),
+            None,
+            Some(rect(100., 100., 10., 10.)),
+        ]);
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.query_point(Vec2::new(7., 7.)), vec![0, 1]);
+        assert_eq!(index.query_point(Vec2::new(2., 2.)), vec![0]);
+        assert_eq!(index.query_point(Vec2::new(105., 105.)), vec![3]);
+        assert!(index.query_point(Vec2::new(50., 50.)).is_empty());
+        assert!(index.query_point(Vec2::NAN).is_empty());
+    }
+
+    #[test]
+    fn finds_items_in_a_rect() {
+        let index = SpatialIndex::new(vec![
+            Some(rect(0., 0., 10., 10.)),
+            Some(rect(20., 0., 10., 10.)),
+            Some(rect(100., 100., 10., 10.)),
+        ]);
+        assert_eq!(index.query_rect(&rect(5., 5., 20., 1.)), vec![0, 1]);
+        assert_eq!(
+            index.query_rect(&rect(-1000., -1000., 3000., 3000.)),
+            vec![0, 1, 2]
+        );
+        assert!(index.query_rect(&rect(40., 40., 10., 10.)).is_empty());
+    }
+
+    #[test]
+    fn keeps_large_items_apart() {
+        let index = SpatialIndex::new(vec![
+            Some(rect(0., 0., 1., 1.)),
+            Some(rect(0., 0., 1000., 1000.)),
+        ]);
+        assert_eq!(index.query_point(Vec2::new(900., 900.)), vec![1]);
+        assert_eq!(index.query_rect(&rect(800., 800., 1., 1.)), vec![1]);
+    }
+
+    #[test]
+    fn normalizes_negative_sizes() {
+        let index = SpatialIndex::new(vec![Some(rect(10., 10., -10., -10.))]);
+        assert_eq!(index.query_point(Vec2::new(5., 5.)), vec![0]);
+        // A marquee dragged up and to the left
+        assert_eq!(index.query_rect(&rect(8., 8., -3., -3.)), vec![0]);
+        assert!(index.query_rect(&rect(30., 30., -10., -10.)).is_empty());
+    }
+
+    #[test]
+    fn huge_bounds_do_not_overflow() {
+        let index = SpatialIndex::new(vec![
+            Some(rect(-1e38, -1e38, 2e38, 2e38)),
+            Some(rect(0., 0., 1., 1.)),
+            Some(rect(0., 0., f32::INFINITY, 1.)),
+        ]);
+        assert_eq!(index.query_point(Vec2::new(0.5, 0.5)), vec![0, 1]);
+        assert_eq!(
+            index.query_rect(&rect(-1e38, -1e38, 2e38, 2e38)),
+            vec![0, 1]
+        );
+        // Spanning every cell an i32 can number, in both directions
+        let index = SpatialIndex::new(vec![Some(rect(0., 0., 1., 1.))]);
+        assert_eq!(index.query_rect(&rect(-1e38, -1e38, 2e38, 2e38)), vec![0]);
+    }
+}
@@ -6,7 +6,7 @@ use guppies::{
     },
 };
 use salvage::svg_set::SvgSet;
-const UNMOVED_RADIUS: f32 = 40.;
+pub(crate) const UNMOVED_RADIUS: f32 = 40.;
 pub fn get_scale(size: PhysicalSize<u32>) -> Mat4 {
     Mat4::from_scale([4.0 / size.width as f32, -4.0 / size.height as f32, 1.0].into())
 }
@@ -18,6 +18,7 @@ pub struct ScrollState {
     pub mouse_position: Vec2,
     pub mouse_down: Option<Vec2>,
     pub display_image_size: Vec2,
+    pub window_size: PhysicalSize<u32>,
 }
 
 impl ScrollState {
@@ -30,7 +31,13 @@ impl ScrollState {
             ..Default::default()
         }
     }
-    pub fn event_handler(&mut self, event: &Event<()>) -> bool {
+    /// Device pixels per SVG unit, e.g. for `SvgSet::set_zoom`.
+    pub fn zoom(&self) -> f32 {
+        // Clip space is 2 units wide
+        let (scale, _rot, _trans) = self.transform.to_scale_rotation_translation();
+        scale.x * self.window_size.width as f32 / 2.
+    }
+    pub fn event_handler<UserEvent>(&mut self, event: &Event<UserEvent>) -> bool {
         if let Event::WindowEvent { event, .. } = event {
             let scroll_state = self;
             match event {
@@ -40,6 +47,7 @@ impl ScrollState {
                     let scale = get_scale(*p).to_scale_rotation_translation().0;
                     scroll_state.transform =
                         Mat4::from_scale_rotation_translation(scale, rot, trans);
+                    scroll_state.window_size = *p;
                 }
                 WindowEvent::CursorMoved { position, .. } => {
                     let new_position = Vec2::new(position.x as f32, position.y as f32);
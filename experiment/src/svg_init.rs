@@ -3,7 +3,7 @@ pub use regex;
 use regex::RegexSet;
 use salvage::{
     geometry::Geometry,
-    usvg::{self, Node, NodeExt},
+    usvg::{Node, NodeExt},
 };
 
 #[derive(Clone, Debug)]
@@ -49,6 +49,7 @@ pub const TRANSFORM_REGEX: &str = r"#transform(?:$| |#)";
 pub const COMPONENT_REGEX: &str = r"#component(?:$| |#)";
 pub const LAYOUT_REGEX: &str = r"#layout(?:$| |#)";
 pub const DYNAMIC_TEXT_REGEX: &str = r"#dynamicText(?:$| |#)";
+pub const STATE_REGEX: &str = r"#state=(\w+)(?:$| |#)";
 
 pub fn get_default_init_callback(
     mut transform_count: u32,
@@ -95,10 +96,7 @@ pub fn get_default_init_callback(
                 false => parent_transform_id,
             };
         let geometry = match is_include {
-            true => match *node.borrow() {
-                usvg::NodeKind::Path(ref p) => Some(Geometry::new(p, transform_id)),
-                _ => None,
-            },
+            true => Geometry::from_node(&node, transform_id),
             false => None,
         };
 
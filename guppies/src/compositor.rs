@@ -0,0 +1,430 @@
+use crate::{
+    primitives::Composite,
+    setup::{stencil_state, STENCIL_FORMAT},
+};
+use std::num::NonZeroU64;
+use wgpu::{
+    BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, Queue, RenderPipeline,
+    SurfaceConfiguration, Texture, TextureFormat, TextureView,
+};
+
+// Bytes of the `Composite` uniform in compositor.wgsl
+const COMPOSITE_SIZE: u64 = 8;
+const INITIAL_COMPOSITES: u64 = 16;
+
+/// Where the passes of one nesting level draw to.
+/// The loads are applied by the next pass, which then sets them to `Load`.
+pub struct RenderTarget<'a> {
+    // Single sampled, this is what layers and backdrops are read from
+    pub view: &'a TextureView,
+    pub texture: Option<&'a Texture>,
+    pub msaa_view: Option<&'a TextureView>,
+    pub stencil_view: &'a TextureView,
+    pub color_load: wgpu::LoadOp<wgpu::Color>,
+    pub stencil_load: wgpu::LoadOp<u32>,
+}
+
+impl<'a> RenderTarget<'a> {
+    pub fn begin_pass<'e>(&mut self, encoder: &'e mut CommandEncoder) -> wgpu::RenderPass<'e>
+    where
+        'a: 'e,
+    {
+        let (view, resolve_target) = match self.msaa_view {
+            Some(msaa_view) => (msaa_view, Some(self.view)),
+            None => (self.view, None),
+        };
+        let pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: self.color_load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: self.stencil_view,
+                depth_ops: None,
+                stencil_ops: Some(wgpu::Operations {
+                    load: self.stencil_load,
+                    store: wgpu::StoreOp::Store,
+                }),
+            }),
+            ..Default::default()
+        });
+        self.color_load = wgpu::LoadOp::Load;
+        self.stencil_load = wgpu::LoadOp::Load;
+        pass
+    }
+}
+
+struct Layer {
+    texture: Texture,
+    view: TextureView,
+    msaa_view: Option<TextureView>,
+    stencil_view: TextureView,
+}
+
+impl Layer {
+    fn new(device: &Device, config: &SurfaceConfiguration, sample_count: u32) -> Self {
+        let create_texture = |label, sample_count, format, usage| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width: config.width,
+                    height: config.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage,
+                view_formats: Default::default(),
+            })
+        };
+        let texture = create_texture(
+            "Layer texture",
+            1,
+            config.format,
+            wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+        );
+        let msaa_view = (sample_count > 1).then(|| {
+            create_texture(
+                "Layer multisampled texture",
+                sample_count,
+                config.format,
+                wgpu::TextureUsages::RENDER_ATTACHMENT,
+            )
+            .create_view(&Default::default())
+        });
+        let stencil_view = create_texture(
+            "Layer stencil texture",
+            sample_count,
+            STENCIL_FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT,
+        )
+        .create_view(&Default::default());
+        Self {
+            view: texture.create_view(&Default::default()),
+            texture,
+            msaa_view,
+            stencil_view,
+        }
+    }
+    fn target(
+        &self,
+        color_load: wgpu::LoadOp<wgpu::Color>,
+        stencil_load: wgpu::LoadOp<u32>,
+    ) -> RenderTarget<'_> {
+        RenderTarget {
+            view: &self.view,
+            texture: Some(&self.texture),
+            msaa_view: self.msaa_view.as_ref(),
+            stencil_view: &self.stencil_view,
+            color_load,
+            stencil_load,
+        }
+    }
+}
+
+/// Renders groups with opacity or a blend mode into intermediate targets and
+/// composites them back, see `DrawCommand::PushLayer`.
+/// The window surface can't be read from, so while any layer is drawn the frame goes
+/// through a scene texture which is blitted onto the surface at the end.
+/// Targets and the bind groups reading them are allocated on first use and dropped on resize.
+pub struct Compositor {
+    composite_pipeline: RenderPipeline,
+    composite_layout: BindGroupLayout,
+    blit_pipeline: RenderPipeline,
+    blit_layout: BindGroupLayout,
+    scene: Option<Layer>,
+    // One per nesting depth
+    layers: Vec<Layer>,
+    // Copy of the target a layer is composited onto
+    backdrop: Option<(Texture, TextureView)>,
+    // Uniforms of every composite of the frame, one per `composite_stride` bytes
+    composite_buffer: Buffer,
+    composite_stride: u64,
+    // Read the layer of each nesting depth, the backdrop and `composite_buffer`
+    composite_bind_groups: Vec<BindGroup>,
+    blit_bind_group: Option<BindGroup>,
+}
+
+impl Compositor {
+    pub fn new(device: &Device, format: TextureFormat, sample_count: u32) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("compositor"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("compositor.wgsl").into()),
+        });
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let composite_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("composite_bind_group_layout"),
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: NonZeroU64::new(COMPOSITE_SIZE),
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let blit_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("blit_bind_group_layout"),
+            entries: &[texture_entry(0)],
+        });
+        let create_pipeline = |layout, entry_point, sample_count, depth_stencil| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(
+                    &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        bind_group_layouts: &[layout],
+                        ..Default::default()
+                    }),
+                ),
+                vertex: wgpu::VertexState {
+                    compilation_options: Default::default(),
+                    module: &shader,
+                    entry_point: "vs_fullscreen",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    compilation_options: Default::default(),
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        // The composite already accounts for what's below
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil,
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+        // Composites respect the clips of the target below, blits go straight to the surface
+        let composite_pipeline = create_pipeline(
+            &composite_layout,
+            "fs_composite",
+            sample_count,
+            Some(stencil_state(wgpu::StencilOperation::Keep)),
+        );
+        let blit_pipeline = create_pipeline(&blit_layout, "fs_blit", 1, None);
+        let composite_stride =
+            COMPOSITE_SIZE.max(device.limits().min_uniform_buffer_offset_alignment as u64);
+        Self {
+            composite_pipeline,
+            composite_layout,
+            blit_pipeline,
+            blit_layout,
+            scene: None,
+            layers: vec![],
+            backdrop: None,
+            composite_buffer: create_composite_buffer(
+                device,
+                INITIAL_COMPOSITES * composite_stride,
+            ),
+            composite_stride,
+            composite_bind_groups: vec![],
+            blit_bind_group: None,
+        }
+    }
+    pub fn resize(&mut self) {
+        self.scene = None;
+        self.layers.clear();
+        self.backdrop = None;
+        self.composite_bind_groups.clear();
+        self.blit_bind_group = None;
+    }
+    /// Allocates the scene and `depth` nested layers if they don't exist yet,
+    /// and uploads the `composites` of the frame in the order they're composited.
+    pub fn prepare(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        config: &SurfaceConfiguration,
+        sample_count: u32,
+        depth: usize,
+        composites: &[Composite],
+    ) {
+        self.scene
+            .get_or_insert_with(|| Layer::new(device, config, sample_count));
+        while self.layers.len() < depth {
+            self.layers.push(Layer::new(device, config, sample_count));
+        }
+        self.backdrop.get_or_insert_with(|| {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Backdrop texture"),
+                size: wgpu::Extent3d {
+                    width: config.width,
+                    height: config.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: config.format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: Default::default(),
+            });
+            let view = texture.create_view(&Default::default());
+            (texture, view)
+        });
+        let size = composites.len() as u64 * self.composite_stride;
+        if size > self.composite_buffer.size() {
+            self.composite_buffer = create_composite_buffer(device, size.next_power_of_two());
+            self.composite_bind_groups.clear();
+        }
+        let mut contents = vec![0; size as usize];
+        for (i, composite) in composites.iter().enumerate() {
+            let start = i * self.composite_stride as usize;
+            contents[start..start + COMPOSITE_SIZE as usize].copy_from_slice(bytemuck::cast_slice(
+                &[composite.opacity.to_bits(), composite.blend_mode as u32],
+            ));
+        }
+        if !contents.is_empty() {
+            queue.write_buffer(&self.composite_buffer, 0, &contents);
+        }
+        let (_, backdrop_view) = self.backdrop.as_ref().unwrap();
+        while self.composite_bind_groups.len() < depth {
+            let layer = &self.layers[self.composite_bind_groups.len()];
+            self.composite_bind_groups
+                .push(device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("composite_bind_group"),
+                    layout: &self.composite_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(&layer.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::TextureView(backdrop_view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                                buffer: &self.composite_buffer,
+                                offset: 0,
+                                size: NonZeroU64::new(COMPOSITE_SIZE),
+                            }),
+                        },
+                    ],
+                }));
+        }
+        let scene = self.scene.as_ref().unwrap();
+        self.blit_bind_group.get_or_insert_with(|| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("blit_bind_group"),
+                layout: &self.blit_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&scene.view),
+                }],
+            })
+        });
+    }
+    /// Panics unless `prepare` was called since the last resize.
+    pub fn scene_target(
+        &self,
+        color_load: wgpu::LoadOp<wgpu::Color>,
+        stencil_load: wgpu::LoadOp<u32>,
+    ) -> RenderTarget<'_> {
+        self.scene
+            .as_ref()
+            .expect("Compositor::prepare allocates the scene")
+            .target(color_load, stencil_load)
+    }
+    /// Target of the layer nested `depth` levels deep, starting at 0, cleared by its first pass.
+    pub fn layer_target(&self, depth: usize) -> RenderTarget<'_> {
+        self.layers[depth].target(
+            wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            wgpu::LoadOp::Clear(0),
+        )
+    }
+    /// Composites the layer nested `depth` levels deep onto `target` inside the clips
+    /// open on `target`, with the `index`th of the composites given to `prepare`.
+    pub fn composite(
+        &self,
+        encoder: &mut CommandEncoder,
+        depth: usize,
+        index: usize,
+        target: &mut RenderTarget,
+        clip_depth: u32,
+    ) {
+        let (backdrop, _) = self
+            .backdrop
+            .as_ref()
+            .expect("Compositor::prepare allocates the backdrop");
+        let target_texture = target
+            .texture
+            .expect("layers are only composited onto the scene or other layers");
+        encoder.copy_texture_to_texture(
+            target_texture.as_image_copy(),
+            backdrop.as_image_copy(),
+            target_texture.size(),
+        );
+        let offset = index as u64 * self.composite_stride;
+        let mut pass = target.begin_pass(encoder);
+        pass.set_pipeline(&self.composite_pipeline);
+        pass.set_bind_group(0, &self.composite_bind_groups[depth], &[offset as u32]);
+        pass.set_stencil_reference(clip_depth);
+        pass.draw(0..3, 0..1);
+    }
+    /// Copies the scene onto `view`, i.e. the frame.
+    pub fn blit(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        let bind_group = self
+            .blit_bind_group
+            .as_ref()
+            .expect("Compositor::prepare allocates the scene");
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            ..Default::default()
+        });
+        pass.set_pipeline(&self.blit_pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_composite_buffer(device: &Device, size: u64) -> Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Composite Buffer"),
+        size,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
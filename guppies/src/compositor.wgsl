@@ -0,0 +1,72 @@
+// Composites a layer onto a copy of what's below it, see compositor.rs
+struct Composite {
+    opacity: f32,
+    blend_mode: u32,
+};
+
+@group(0) @binding(0) var layer: texture_2d<f32>;
+@group(0) @binding(1) var backdrop: texture_2d<f32>;
+@group(0) @binding(2) var<uniform> composite: Composite;
+
+// One triangle covering the whole target
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) i: u32) -> @builtin(position) vec4<f32> {
+    let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+// Matches guppies' BlendMode
+const MULTIPLY: u32 = 1u;
+const SCREEN: u32 = 2u;
+const OVERLAY: u32 = 3u;
+const DARKEN: u32 = 4u;
+const LIGHTEN: u32 = 5u;
+
+// Separable blend functions of the W3C compositing spec, on straight colours
+fn blend(cb: vec3<f32>, cs: vec3<f32>) -> vec3<f32> {
+    switch composite.blend_mode {
+        case MULTIPLY: {
+            return cb * cs;
+        }
+        case SCREEN: {
+            return cb + cs - cb * cs;
+        }
+        case OVERLAY: {
+            let multiply = 2.0 * cb * cs;
+            let screen = 1.0 - 2.0 * (1.0 - cb) * (1.0 - cs);
+            return select(screen, multiply, cb <= vec3<f32>(0.5));
+        }
+        case DARKEN: {
+            return min(cb, cs);
+        }
+        case LIGHTEN: {
+            return max(cb, cs);
+        }
+        default: {
+            return cs;
+        }
+    }
+}
+
+fn unpremultiply(c: vec4<f32>) -> vec3<f32> {
+    if c.a == 0.0 {
+        return vec3<f32>(0.0);
+    }
+    return c.rgb / c.a;
+}
+
+@fragment
+fn fs_composite(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let xy = vec2<u32>(position.xy);
+    // Both are premultiplied
+    let source = textureLoad(layer, xy, 0) * composite.opacity;
+    let below = textureLoad(backdrop, xy, 0);
+    let mixed = blend(unpremultiply(below), unpremultiply(source));
+    let rgb = source.rgb * (1.0 - below.a) + below.rgb * (1.0 - source.a) + source.a * below.a * mixed;
+    return vec4<f32>(rgb, source.a + below.a * (1.0 - source.a));
+}
+
+@fragment
+fn fs_blit(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    return textureLoad(layer, vec2<u32>(position.xy), 0);
+}
@@ -0,0 +1,74 @@
+use winit::dpi::Size;
+
+/// Window and surface options used when `Guppy` starts.
+/// Build it with the `with_*` methods, starting from `GuppyConfig::default()`.
+#[derive(Clone, Debug)]
+pub struct GuppyConfig {
+    pub title: String,
+    pub inner_size: Option<Size>,
+    pub min_inner_size: Option<Size>,
+    pub max_inner_size: Option<Size>,
+    pub resizable: bool,
+    pub clear_color: wgpu::Color,
+    // Falls back to 1 when the adapter can't multisample the surface format
+    pub sample_count: u32,
+    // Falls back to AutoVsync when the surface doesn't support it
+    pub present_mode: wgpu::PresentMode,
+    // Picks an sRGB surface format when true, a linear one otherwise
+    pub srgb: bool,
+}
+
+impl Default for GuppyConfig {
+    fn default() -> Self {
+        Self {
+            title: "SVG-GUI".to_string(),
+            inner_size: None,
+            min_inner_size: None,
+            max_inner_size: None,
+            resizable: true,
+            clear_color: wgpu::Color::WHITE,
+            sample_count: 4,
+            present_mode: wgpu::PresentMode::AutoVsync,
+            srgb: true,
+        }
+    }
+}
+
+impl GuppyConfig {
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+    pub fn with_inner_size(mut self, size: impl Into<Size>) -> Self {
+        self.inner_size = Some(size.into());
+        self
+    }
+    pub fn with_min_inner_size(mut self, size: impl Into<Size>) -> Self {
+        self.min_inner_size = Some(size.into());
+        self
+    }
+    pub fn with_max_inner_size(mut self, size: impl Into<Size>) -> Self {
+        self.max_inner_size = Some(size.into());
+        self
+    }
+    pub fn with_resizable(mut self, resizable: bool) -> Self {
+        self.resizable = resizable;
+        self
+    }
+    pub fn with_clear_color(mut self, clear_color: wgpu::Color) -> Self {
+        self.clear_color = clear_color;
+        self
+    }
+    pub fn with_sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+    pub fn with_present_mode(mut self, present_mode: wgpu::PresentMode) -> Self {
+        self.present_mode = present_mode;
+        self
+    }
+    pub fn with_srgb(mut self, srgb: bool) -> Self {
+        self.srgb = srgb;
+        self
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{
+    setup::{Redraw, RedrawMachine},
+    GpuRedraw, GuppyConfig, TableOverflow,
+};
+use bytemuck::{Pod, Zeroable};
+use std::array;
+use std::fmt::Debug;
+use winit::dpi::PhysicalSize;
+
+/// Renders `GpuRedraw` layers into an offscreen texture instead of a window.
+/// Useful for snapshot tests where no display is available.
+pub struct Headless<const COUNT: usize> {
+    redraw_machine: RedrawMachine<'static>,
+    redraws: [Redraw; COUNT],
+}
+
+impl<const COUNT: usize> Headless<COUNT> {
+    pub fn new(size: PhysicalSize<u32>) -> Self {
+        Self::with_config(size, &GuppyConfig::default())
+    }
+    /// Uses the surface options of `config`; window options are ignored.
+    pub fn with_config(size: PhysicalSize<u32>, config: &GuppyConfig) -> Self {
+        let redraw_machine = pollster::block_on(RedrawMachine::new_headless(size, config));
+        let redraws = array::from_fn(|i| {
+            Redraw::new(&redraw_machine, &Default::default(), &Default::default(), i)
+        });
+        Self {
+            redraw_machine,
+            redraws,
+        }
+    }
+    pub fn size(&self) -> PhysicalSize<u32> {
+        let config = &self.redraw_machine.config;
+        PhysicalSize::new(config.width, config.height)
+    }
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        self.redraw_machine.resize(size);
+    }
+    /// Draws one frame and returns it as tightly packed RGBA8 rows, top row first.
+    pub fn render<Vert: Pod + Zeroable + Debug + Clone + Default>(
+        &mut self,
+        gpu_redraws: &mut [GpuRedraw<Vert>; COUNT],
+    ) -> Result<Vec<u8>, TableOverflow> {
+        let Self {
+            redraw_machine,
+            redraws,
+        } = self;
+        redraws
+            .iter_mut()
+            .zip(gpu_redraws.iter_mut())
+            .for_each(|(redraw, new_redraw)| {
+                if let Some(shader) = new_redraw.shader.take() {
+                    redraw.update_shader(&shader, redraw_machine);
+                }
+                new_redraw.dirty = false;
+            });
+        let mut frame = redraw_machine.get_frame();
+        redraw_machine.redraw(gpu_redraws, redraws, &mut frame)?;
+        redraw_machine.submit(frame);
+        Ok(redraw_machine
+            .read_offscreen()
+            .expect("Headless RedrawMachine always has an offscreen texture"))
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{primitives::RasterImage, texel_table::TableOverflow};
+use wgpu::{Device, Extent3d, Queue, Sampler, Texture, TextureView};
+
+const INITIAL_SIZE: u32 = 1024;
+
+/// One texture holding every raster image of a layer.
+/// Images are packed into shelves from left to right, and the atlas doubles in size
+/// (up to `max_texture_dimension_2d`) when they don't fit.
+#[derive(Debug)]
+pub struct ImageAtlas {
+    texture: Texture,
+    view: TextureView,
+    sampler: Sampler,
+    // Where the images of the last `write` went
+    origins: Vec<(u32, u32)>,
+}
+
+impl ImageAtlas {
+    pub fn new(device: &Device) -> Self {
+        let (texture, view) = Self::create_texture(device, INITIAL_SIZE);
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("image atlas"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        Self {
+            texture,
+            view,
+            sampler,
+            origins: vec![],
+        }
+    }
+    fn create_texture(device: &Device, size: u32) -> (Texture, TextureView) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("image atlas"),
+            size: Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: Default::default(),
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
+    pub fn texture_binding_type() -> wgpu::BindingType {
+        wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        }
+    }
+    pub fn sampler_binding_type() -> wgpu::BindingType {
+        wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering)
+    }
+    pub fn view(&self) -> &TextureView {
+        &self.view
+    }
+    pub fn sampler(&self) -> &Sampler {
+        &self.sampler
+    }
+    /// Packs `images`, returning their `(u0, v0, u1, v1)` rects in the atlas as the texels
+    /// of the image table. Uploads the ones at the indices in `dirty` and the ones which
+    /// moved, or all of them into a new texture.
+    /// The bool is true when the texture was recreated and the bind group has to be rebuilt.
+    pub fn write(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        images: &[RasterImage],
+        dirty: &[usize],
+    ) -> Result<(Vec<[f32; 4]>, bool), TableOverflow> {
+        let max_size = device.limits().max_texture_dimension_2d;
+        let mut size = self.texture.width();
+        let origins = loop {
+            match pack(images, size) {
+                Some(origins) => break origins,
+                None if size < max_size => size = (size * 2).min(max_size),
+                None => {
+                    return Err(TableOverflow {
+                        table: "image atlas",
+                        texels: images
+                            .iter()
+                            .map(|image| (image.width * image.height) as usize)
+                            .sum(),
+                        max_texels: (max_size * max_size) as usize,
+                    })
+                }
+            }
+        };
+        let regrown = size != self.texture.width();
+        if regrown {
+            (self.texture, self.view) = Self::create_texture(device, size);
+        }
+        let rects = images
+            .iter()
+            .zip(&origins)
+            .enumerate()
+            .map(|(i, (image, &(x, y)))| {
+                let upload = regrown || dirty.contains(&i) || self.origins.get(i) != Some(&(x, y));
+                if upload && image.width > 0 && image.height > 0 {
+                    queue.write_texture(
+                        wgpu::ImageCopyTexture {
+                            origin: wgpu::Origin3d { x, y, z: 0 },
+                            ..self.texture.as_image_copy()
+                        },
+                        &image.rgba,
+                        wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: Some(image.width * 4),
+                            rows_per_image: None,
+                        },
+                        Extent3d {
+                            width: image.width,
+                            height: image.height,
+                            depth_or_array_layers: 1,
+                        },
+                    );
+                }
+                let size = size as f32;
+                [
+                    x as f32 / size,
+                    y as f32 / size,
+                    (x + image.width) as f32 / size,
+                    (y + image.height) as f32 / size,
+                ]
+            })
+            .collect();
+        self.origins = origins;
+        Ok((rects, regrown))
+    }
+}
+
+/// Top left corner of every image in a `size` by `size` atlas, or None when they don't fit.
+fn pack(images: &[RasterImage], size: u32) -> Option<Vec<(u32, u32)>> {
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+    images
+        .iter()
+        .map(|image| {
+            if image.width > size {
+                return None;
+            }
+            if x + image.width > size {
+                (x, y, shelf_height) = (0, y + shelf_height, 0);
+            }
+            if y + image.height > size {
+                return None;
+            }
+            let origin = (x, y);
+            x += image.width;
+            shelf_height = shelf_height.max(image.height);
+            Some(origin)
+        })
+        .collect()
+}
@@ -1,28 +1,47 @@
+mod compositor;
+mod config;
+pub mod headless;
+mod image_atlas;
 pub mod primitives;
 mod setup;
+mod texel_table;
 pub use bytemuck;
 use bytemuck::{Pod, Zeroable};
+pub use config::GuppyConfig;
 pub use glam;
-use primitives::{Triangles, Vertex};
+use primitives::{DrawCommand, RasterImage, Triangles, Vertex};
 use setup::{Redraw, RedrawMachine};
 use std::array;
 use std::fmt::Debug;
 use std::sync::Arc;
 use std::time::Instant;
+pub use texel_table::TableOverflow;
 pub use wgpu;
 pub use winit;
-use winit::event_loop::EventLoopWindowTarget;
+use winit::event_loop::{EventLoopBuilder, EventLoopProxy, EventLoopWindowTarget};
 use winit::window::{Window, WindowBuilder, WindowId};
 use winit::{
     event::{Event, WindowEvent},
     event_loop::EventLoop,
 };
 
-fn init_window(event_loop: &EventLoopWindowTarget<()>) -> winit::window::Window {
-    let window = WindowBuilder::new()
-        .with_title("SVG-GUI")
-        .build(&event_loop)
-        .unwrap();
+fn init_window<UserEvent>(
+    event_loop: &EventLoopWindowTarget<UserEvent>,
+    config: &GuppyConfig,
+) -> winit::window::Window {
+    let mut window_builder = WindowBuilder::new()
+        .with_title(&config.title)
+        .with_resizable(config.resizable);
+    if let Some(size) = config.inner_size {
+        window_builder = window_builder.with_inner_size(size);
+    }
+    if let Some(size) = config.min_inner_size {
+        window_builder = window_builder.with_min_inner_size(size);
+    }
+    if let Some(size) = config.max_inner_size {
+        window_builder = window_builder.with_max_inner_size(size);
+    }
+    let window = window_builder.build(&event_loop).unwrap();
     #[cfg(target_arch = "wasm32")]
     {
         std::panic::set_hook(Box::new(console_error_panic_hook::hook));
@@ -47,16 +66,88 @@ fn init_window(event_loop: &EventLoopWindowTarget<()>) -> winit::window::Window
 #[derive(Debug, Default)]
 pub struct GpuRedraw<T: Pod + Zeroable + Debug + Clone + Default = Vertex> {
     texture: Vec<u8>,
+    attributes: Vec<u8>,
+    gradients: Vec<u8>,
+    images: Vec<RasterImage>,
     triangles: Triangles<T>,
+    // Empty draws every triangle without clipping
+    draw_commands: Vec<DrawCommand>,
     shader: Option<Vec<u32>>,
+    // (index, vertex) positions from which the GPU buffers are stale
+    dirty_triangles: Option<(usize, usize)>,
+    // Indices of the images to upload again when the image atlas has to be written
+    dirty_images: Option<Vec<usize>>,
+    // Whether this layer needs to be drawn again
+    dirty: bool,
+}
+
+impl<T: Pod + Zeroable + Debug + Clone + Default> GpuRedraw<T> {
+    /// Asks for another frame even though nothing changed, e.g. while an animation is running.
+    pub fn request_redraw(&mut self) {
+        self.dirty = true;
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
 }
 
 impl GpuRedraw {
     pub fn update_spirv_shader(&mut self, shader: Vec<u32>) {
         self.shader = Some(shader);
+        self.dirty = true;
     }
     pub fn update_texture(&mut self, textures: Vec<u8>) {
-        self.texture = textures;
+        if self.texture != textures {
+            self.texture = textures;
+            self.dirty = true;
+        }
+    }
+    /// Replaces the `TransformAttributes` table, indexed by `Vertex::transform_id` like
+    /// the transforms, e.g. to hide, fade or tint an element without touching its triangles.
+    /// Transform ids past the end of the table are drawn as they are.
+    pub fn update_attributes(&mut self, attributes: Vec<u8>) {
+        if self.attributes != attributes {
+            self.attributes = attributes;
+            self.dirty = true;
+        }
+    }
+    /// Replaces the gradient table which `Vertex::gradient_id` points into.
+    pub fn update_gradients(&mut self, gradients: Vec<u8>) {
+        if self.gradients != gradients {
+            self.gradients = gradients;
+            self.dirty = true;
+        }
+    }
+    /// Replaces the image table which `Vertex::image_id` points into.
+    /// Only the images which don't share the pixels of the one they replace are
+    /// uploaded again, see `RasterImage::shares_pixels`.
+    pub fn update_images(&mut self, images: Vec<RasterImage>) {
+        let changed: Vec<usize> = images
+            .iter()
+            .enumerate()
+            .filter(|(i, image)| {
+                !self
+                    .images
+                    .get(*i)
+                    .is_some_and(|old| old.shares_pixels(image))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if !changed.is_empty() || self.images.len() != images.len() {
+            self.images = images;
+            self.dirty_images
+                .get_or_insert_with(Vec::new)
+                .extend(changed);
+            self.dirty = true;
+        }
+    }
+    /// Replaces how the triangles are drawn, e.g. to clip some of them.
+    /// An empty list draws every triangle in order.
+    pub fn update_draw_commands(&mut self, draw_commands: Vec<DrawCommand>) {
+        if self.draw_commands != draw_commands {
+            self.draw_commands = draw_commands;
+            self.dirty = true;
+        }
     }
     pub fn update_triangles(&mut self, triangles: Triangles, offset: usize) {
         let v_i = {
@@ -66,44 +157,102 @@ impl GpuRedraw {
                 0 as usize
             }
         };
+        let unchanged = self.triangles.indices.get(offset..) == Some(&triangles.indices[..])
+            && self
+                .triangles
+                .vertices
+                .get(v_i..)
+                .map(bytemuck::cast_slice::<Vertex, u8>)
+                == Some(bytemuck::cast_slice(&triangles.vertices));
+        if unchanged {
+            return;
+        }
+        self.dirty = true;
         self.triangles.indices.splice(offset.., triangles.indices);
         self.triangles.vertices.splice(v_i.., triangles.vertices);
+        self.dirty_triangles = Some(match self.dirty_triangles {
+            Some((i, v)) => (i.min(offset), v.min(v_i)),
+            None => (offset, v_i),
+        });
     }
 }
 
-pub struct Guppy<const COUNT: usize, Vert>
+type RenderLoopFn<const COUNT: usize, Vert, UserEvent> =
+    Box<dyn FnMut(&Event<UserEvent>, &mut [GpuRedraw<Vert>; COUNT])>;
+type TableOverflowFn<const COUNT: usize, Vert> =
+    Box<dyn FnMut(&TableOverflow, &mut [GpuRedraw<Vert>; COUNT])>;
+
+pub struct Guppy<const COUNT: usize, Vert, UserEvent: 'static = ()>
 where
     Vert: Pod + Zeroable + Debug + Clone + Default,
 {
     init: [GpuRedraw<Vert>; COUNT],
-    functions: Vec<Box<dyn FnMut(&Event<()>, &mut [GpuRedraw<Vert>; COUNT])>>,
+    config: GuppyConfig,
+    event_loop: EventLoop<UserEvent>,
+    functions: Vec<RenderLoopFn<COUNT, Vert, UserEvent>>,
+    table_overflow_fn: Option<TableOverflowFn<COUNT, Vert>>,
 }
 
 impl<const COUNT: usize, Vert: Pod + Zeroable + Debug + Clone + Default> Guppy<COUNT, Vert> {
-    pub fn register<F: FnMut(&Event<()>, &mut [GpuRedraw<Vert>; COUNT]) + 'static>(
-        &mut self,
-        f: F,
-    ) {
-        self.functions.push(Box::new(f));
+    pub fn new(init: [GpuRedraw<Vert>; COUNT], config: GuppyConfig) -> Self {
+        Self::with_user_event(init, config)
     }
-    pub fn new(init: [GpuRedraw<Vert>; COUNT]) -> Self {
+}
+
+impl<const COUNT: usize, Vert: Pod + Zeroable + Debug + Clone + Default, UserEvent: 'static>
+    Guppy<COUNT, Vert, UserEvent>
+{
+    /// Like `new`, but registered functions also receive `Event::UserEvent`
+    /// for every message sent through `create_proxy`.
+    pub fn with_user_event(init: [GpuRedraw<Vert>; COUNT], config: GuppyConfig) -> Self {
+        let event_loop = EventLoopBuilder::<UserEvent>::with_user_event()
+            .build()
+            .expect("event loop initialization failed");
         Self {
             init,
+            config,
+            event_loop,
             functions: Vec::default(),
+            table_overflow_fn: None,
         }
     }
+    /// Returns a handle which can wake the loop from other threads
+    /// and deliver `UserEvent`s to the registered functions.
+    pub fn create_proxy(&self) -> EventLoopProxy<UserEvent> {
+        self.event_loop.create_proxy()
+    }
+    pub fn register<F: FnMut(&Event<UserEvent>, &mut [GpuRedraw<Vert>; COUNT]) + 'static>(
+        &mut self,
+        f: F,
+    ) {
+        self.functions.push(Box::new(f));
+    }
+    /// Called instead of drawing a frame whose tables don't fit on the device,
+    /// e.g. to drop some content. The error is only logged otherwise.
+    pub fn on_table_overflow<F: FnMut(&TableOverflow, &mut [GpuRedraw<Vert>; COUNT]) + 'static>(
+        &mut self,
+        f: F,
+    ) {
+        self.table_overflow_fn = Some(Box::new(f));
+    }
     pub fn start(self) {
-        render_loop(self.functions);
+        render_loop(
+            self.event_loop,
+            self.functions,
+            self.table_overflow_fn,
+            self.config,
+        );
     }
 }
 
-pub fn render_loop<const COUNT: usize, Vert>(
-    mut render_loop_fn: Vec<Box<dyn FnMut(&Event<()>, &mut [GpuRedraw<Vert>; COUNT])>>,
+pub fn render_loop<const COUNT: usize, Vert, UserEvent: 'static>(
+    event_loop: EventLoop<UserEvent>,
+    mut render_loop_fn: Vec<RenderLoopFn<COUNT, Vert, UserEvent>>,
+    mut table_overflow_fn: Option<TableOverflowFn<COUNT, Vert>>,
+    config: GuppyConfig,
 ) where
     Vert: Pod + Zeroable + Debug + Clone + Default,
 {
-    let event_loop = EventLoop::new();
-
     // Type definition is required for android build
     let mut window: Option<Arc<Window>> = None;
     let mut gpu_redraw: Option<[GpuRedraw<Vert>; COUNT]> = None;
@@ -113,8 +262,7 @@ pub fn render_loop<const COUNT: usize, Vert>(
     let mut last_frame_inst = Instant::now();
     #[cfg(not(target_arch = "wasm32"))]
     let (mut frame_count, mut accum_time) = (0, 0.0);
-    let _ = event_loop
-        .expect("event loop initialization failed")
+    event_loop
         .run(move |event, event_loop| {
             // FIXME: why do some OS not redraw automatically without explicit call
             #[cfg(any(target_os = "ios", target_os = "android"))]
@@ -126,6 +274,15 @@ pub fn render_loop<const COUNT: usize, Vert>(
                 redraws.as_mut(),
                 redraw_machine.as_ref(),
             ) {
+                // Cleared before the callbacks see the frame, so anything they change
+                // or request while handling it schedules the next one
+                if let Event::WindowEvent {
+                    event: WindowEvent::RedrawRequested,
+                    ..
+                } = event
+                {
+                    gpu_redraw.iter_mut().for_each(|layer| layer.dirty = false);
+                }
                 render_loop_fn.iter_mut().for_each(|func| {
                     func(&event, gpu_redraw);
                 });
@@ -141,14 +298,14 @@ pub fn render_loop<const COUNT: usize, Vert>(
             match event {
                 #[cfg(target_os = "android")]
                 Event::Resumed => {
-                    init_window(event_loop);
+                    init_window(event_loop, &config);
                 }
                 #[cfg(not(target_os = "android"))]
                 Event::NewEvents(start_cause) => match start_cause {
                     winit::event::StartCause::Init => {
-                        let new_window = Arc::new(init_window(event_loop));
+                        let new_window = Arc::new(init_window(event_loop, &config));
                         let new_redraw_machine =
-                            pollster::block_on(RedrawMachine::new(new_window.clone()));
+                            pollster::block_on(RedrawMachine::new(new_window.clone(), &config));
                         redraws = Some(array::from_fn(|i| {
                             Redraw::new(
                                 &new_redraw_machine,
@@ -185,35 +342,40 @@ pub fn render_loop<const COUNT: usize, Vert>(
                         event_loop.exit();
                     }
                     WindowEvent::Resized(p) => match redraw_machine.as_mut() {
-                        Some(redraw_machine) => redraw_machine.resize(p),
+                        Some(redraw_machine) => {
+                            redraw_machine.resize(p);
+                            if let Some(window) = window.as_ref() {
+                                window.request_redraw();
+                            }
+                        }
                         _ => {}
                     },
                     WindowEvent::RedrawRequested => {
-                        if let (
-                            Some(window),
-                            Some(gpu_redraw),
-                            Some(redraws),
-                            Some(redraw_machine),
-                        ) = (
-                            window.as_mut(),
+                        if let (Some(gpu_redraw), Some(redraws), Some(redraw_machine)) = (
                             gpu_redraw.as_mut(),
                             redraws.as_mut(),
                             redraw_machine.as_mut(),
                         ) {
                             let mut frame = redraw_machine.get_frame();
-                            redraw_machine.redraw(gpu_redraw, redraws, &mut frame);
-                            redraw_machine.submit(frame);
-                            window.request_redraw();
+                            match redraw_machine.redraw(gpu_redraw, redraws, &mut frame) {
+                                Ok(()) => redraw_machine.submit(frame),
+                                // Drawing with a truncated table would scramble the scene,
+                                // so the frame is dropped
+                                Err(err) => match table_overflow_fn.as_mut() {
+                                    Some(f) => f(&err, gpu_redraw),
+                                    None => log::error!("{err}"),
+                                },
+                            }
                             #[cfg(not(target_arch = "wasm32"))]
                             {
                                 accum_time += last_frame_inst.elapsed().as_secs_f32();
                                 last_frame_inst = Instant::now();
                                 frame_count += 1;
                                 if frame_count == 100 {
-                                    // println!(
-                                    //     "Avg frame time {}ms",
-                                    //     accum_time * 1000.0 / frame_count as f32
-                                    // );
+                                    log::debug!(
+                                        "Avg frame time {}ms",
+                                        accum_time * 1000.0 / frame_count as f32
+                                    );
                                     accum_time = 0.0;
                                     frame_count = 0;
                                 }
@@ -224,5 +386,12 @@ pub fn render_loop<const COUNT: usize, Vert>(
                 },
                 _ => {}
             }
-        });
+            // Only draw again when a layer changed or an animation asked for it
+            if let (Some(window), Some(gpu_redraw)) = (window.as_ref(), gpu_redraw.as_ref()) {
+                if gpu_redraw.iter().any(GpuRedraw::is_dirty) {
+                    window.request_redraw();
+                }
+            }
+        })
+        .unwrap_or_else(|err| log::error!("event loop exited with {err}"));
 }
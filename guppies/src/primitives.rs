@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, ops::Range, sync::Arc};
 
 use bytemuck::{Pod, Zeroable};
 use glam::{DVec2, Vec2, Vec4};
@@ -14,6 +14,42 @@ pub struct Triangles<T: Pod + Zeroable + Debug + Clone + Default = Vertex> {
     pub indices: Indices,
 }
 
+/// A range of `Triangles::indices` and how it's drawn, see `GpuRedraw::update_draw_commands`.
+/// Clips nest: triangles drawn after a `PushClip` only show inside its shape
+/// (and inside every enclosing clip) until the matching `PopClip`.
+/// Layers are drawn into an intermediate target and composited onto what's below
+/// once complete, e.g. so overlapping children of a semi-transparent group don't show through.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DrawCommand {
+    Draw(Range<u32>),
+    // Only writes the shape into the stencil buffer, no colour is drawn
+    PushClip(Range<u32>),
+    // Has to cover the same shape as the matching `PushClip`
+    PopClip(Range<u32>),
+    PushLayer,
+    PopLayer(Composite),
+}
+
+/// How a layer is combined with what's below it, see `DrawCommand::PopLayer`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Composite {
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
+}
+
+/// `mix-blend-mode` values which can be composited.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Normal = 0,
+    Multiply = 1,
+    Screen = 2,
+    Overlay = 3,
+    Darken = 4,
+    Lighten = 5,
+}
+
 pub type Size = Vec2;
 pub type Position = Vec2;
 
@@ -38,12 +74,92 @@ impl Rect {
     }
 }
 
+/// Decoded pixels of an embedded bitmap, packed into the image atlas by guppies.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RasterImage {
+    pub width: u32,
+    pub height: u32,
+    // Non-premultiplied sRGB, four bytes per pixel, row by row
+    pub rgba: Arc<Vec<u8>>,
+}
+
+impl RasterImage {
+    /// Whether both are the same size and point to the same pixels, which can't change
+    /// while shared, e.g. a page of a glyph atlas which got no glyphs since.
+    pub fn shares_pixels(&self, other: &RasterImage) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && Arc::ptr_eq(&self.rgba, &other.rgba)
+    }
+}
+
+/// How everything drawn with one transform id looks, see `GpuRedraw::update_attributes`.
+/// Build it with the `with_*` methods, starting from `TransformAttributes::default()`,
+/// which draws the triangles as they are.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TransformAttributes {
+    // Multiplies the colour of every vertex, straight alpha
+    tint: [f32; 4],
+    // Multiplies the alpha on top of the tint
+    opacity: f32,
+    // 0 hides the triangles, 1 shows them
+    visibility: f32,
+    _padding: [f32; 2],
+}
+
+impl Default for TransformAttributes {
+    fn default() -> Self {
+        Self {
+            tint: [1.; 4],
+            opacity: 1.,
+            visibility: 1.,
+            _padding: [0.; 2],
+        }
+    }
+}
+
+impl TransformAttributes {
+    pub fn with_tint(mut self, tint: [f32; 4]) -> Self {
+        self.tint = tint;
+        self
+    }
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity;
+        self
+    }
+    pub fn with_visible(mut self, visible: bool) -> Self {
+        self.visibility = match visible {
+            true => 1.,
+            false => 0.,
+        };
+        self
+    }
+    pub fn tint(&self) -> [f32; 4] {
+        self.tint
+    }
+    pub fn opacity(&self) -> f32 {
+        self.opacity
+    }
+    pub fn is_visible(&self) -> bool {
+        self.visibility != 0.
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: [f32; 3],
     pub transform_id: u32,
     pub color: [f32; 4],
+    // Position in the gradient's own coordinate system
+    pub gradient_position: [f32; 2],
+    // 0 for plain colours, otherwise the gradient's index in the gradient table + 1
+    pub gradient_id: u32,
+    // Position inside the raster image, (0, 0) being its top left and (1, 1) its bottom right
+    pub uv: [f32; 2],
+    // 0 when no image is sampled, otherwise the image's index in the image table + 1
+    pub image_id: u32,
 }
 impl From<&DVec2> for Vertex {
     fn from(v: &DVec2) -> Self {
@@ -69,6 +185,7 @@ impl From<(&DVec2, &Vec4, u32)> for Vertex {
             position: [(v.x) as f32, (v.y) as f32, 0.0],
             color: [c.x, c.y, c.z, c.w],
             transform_id,
+            ..Default::default()
         }
     }
 }
@@ -1,55 +1,75 @@
 use crate::{
-    primitives::{Indices, Vertex, Vertices},
-    GpuRedraw,
+    compositor::{Compositor, RenderTarget},
+    image_atlas::ImageAtlas,
+    primitives::{Composite, DrawCommand, Index, Indices, TransformAttributes, Vertex, Vertices},
+    texel_table::{TableOverflow, TexelTable},
+    GpuRedraw, GuppyConfig,
 };
 use bytemuck::{Pod, Zeroable};
 use core::fmt::Debug;
 use glam::Mat4;
 use std::{borrow::Cow, sync::Arc};
 use wgpu::{
-    util::DeviceExt, BindGroup, Buffer, CommandEncoder, Device, Extent3d, PipelineLayout,
-    RenderPipeline, Surface, SurfaceConfiguration, SurfaceTexture, Texture, TextureFormat,
+    util::DeviceExt, BindGroup, BindGroupLayout, Buffer, CommandEncoder, Device, PipelineLayout,
+    Queue, RenderPipeline, Surface, SurfaceConfiguration, SurfaceTexture, Texture, TextureFormat,
     TextureView,
 };
 use winit::{dpi::PhysicalSize, window::Window};
 
-const TRANSFORM_TEXTURE_SIZE: Extent3d = Extent3d {
-    width: 4 * 2048,
-    height: 1,
-    depth_or_array_layers: 1,
-};
-
 #[derive(Debug)]
 pub struct Redraw {
     pub transform: Mat4,
     pub render_pipeline: RenderPipeline,
+    // Draws what's inside layers, see `create_layer_pipeline`
+    pub layer_pipeline: RenderPipeline,
+    // Increment and decrement the stencil inside clip shapes, see `DrawCommand`
+    pub push_clip_pipeline: RenderPipeline,
+    pub pop_clip_pipeline: RenderPipeline,
     pub bind_group: BindGroup,
     pub uniform_buffer: Buffer,
     pub vertex_buffer: Buffer,
     pub index_buffer: Buffer,
-    pub transform_texture: Texture,
+    pub transform_table: TexelTable,
+    // `TransformAttributes` of every transform id
+    pub attribute_table: TexelTable,
+    pub gradient_table: TexelTable,
+    // (u0, v0, u1, v1) of every image in `image_atlas`
+    pub image_table: TexelTable,
+    pub image_atlas: ImageAtlas,
+    pub bind_group_layout: BindGroupLayout,
     pub pipeline_layout: PipelineLayout,
 }
 
 pub struct RedrawMachine<'a> {
     pub queue: wgpu::Queue,
     pub device: Device,
-    pub surface: Surface<'a>,
+    pub surface: Option<Surface<'a>>,
     pub surface_format: TextureFormat,
     pub config: SurfaceConfiguration,
+    // Render target used instead of the surface when running headless
+    pub offscreen_texture: Option<Texture>,
+    pub clear_color: wgpu::Color,
+    pub sample_count: u32,
+    // Whether transforms and gradients go through storage buffers rather than textures
+    pub storage_buffer: bool,
+    // Only rebuilt on resize, None when sample_count is 1
+    pub msaa_view: Option<TextureView>,
+    // Counts the clips covering each sample, rebuilt on resize
+    pub stencil_view: TextureView,
+    pub compositor: Compositor,
 }
 pub struct Reframe {
     pub view: TextureView,
-    pub frame: SurfaceTexture,
+    pub frame: Option<SurfaceTexture>,
     pub encoder: CommandEncoder,
 }
 impl<'a> RedrawMachine<'a> {
     pub fn redraw<Vert: Pod + Zeroable + Debug + Clone + Default>(
-        &self,
+        &mut self,
         gpu_redraws: &mut [GpuRedraw<Vert>],
-        redraws: &[Redraw],
+        redraws: &mut [Redraw],
         reframe: &mut Reframe,
-    ) {
+    ) -> Result<(), TableOverflow> {
         let Reframe {
             view,
             frame,
@@ -58,99 +78,219 @@ impl<'a> RedrawMachine<'a> {
         let RedrawMachine {
             queue,
             device,
-            surface,
-            surface_format,
             config,
+            msaa_view,
+            stencil_view,
+            clear_color,
+            sample_count,
+            compositor,
+            ..
         } = self;
-        let msaa_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Multisampled frame descriptor"),
-            size: wgpu::Extent3d {
-                width: config.width,
-                height: config.height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: SAMPLE_COUNT,
-            dimension: wgpu::TextureDimension::D2,
-            format: config.format,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
-            view_formats: Default::default(),
-        });
-        let x = msaa_texture.as_image_copy();
-        let msaa_texture = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        redraws
+        let layer_depth = gpu_redraws
             .iter()
+            .map(|gpu_redraw| layer_depth(&gpu_redraw.draw_commands))
+            .max()
+            .unwrap_or_default();
+        // Layers read back what's below them, which the surface doesn't allow
+        let through_scene = layer_depth > 0;
+        if through_scene {
+            let composites: Vec<Composite> = gpu_redraws
+                .iter()
+                .flat_map(|gpu_redraw| &gpu_redraw.draw_commands)
+                .filter_map(|command| match command {
+                    DrawCommand::PopLayer(composite) => Some(*composite),
+                    _ => None,
+                })
+                .collect();
+            compositor.prepare(
+                device,
+                queue,
+                config,
+                *sample_count,
+                layer_depth,
+                &composites,
+            );
+        }
+        let compositor = &*compositor;
+        // Index of the next `PopLayer` among the composites given to `prepare`
+        let mut composite_index = 0;
+        redraws
+            .iter_mut()
             .zip(gpu_redraws.iter_mut())
             .enumerate()
-            .for_each(|(i, (redraw, gpu_redraw))| {
-                gpu_redraw.texture.resize(8192 * 16, 0);
+            .try_for_each(|(i, (redraw, gpu_redraw))| {
                 let Redraw {
                     transform,
                     render_pipeline,
+                    layer_pipeline,
+                    push_clip_pipeline,
+                    pop_clip_pipeline,
                     bind_group,
                     uniform_buffer,
-                    transform_texture,
+                    vertex_buffer,
+                    index_buffer,
+                    transform_table,
+                    attribute_table,
+                    gradient_table,
+                    image_table,
+                    image_atlas,
+                    bind_group_layout,
                     ..
                 } = redraw;
 
-                let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Index Buffer"),
-                    contents: bytemuck::cast_slice(&gpu_redraw.triangles.indices),
-                    usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-                });
-                let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("SVG-GUI Vertex Buffer"),
-                    contents: (bytemuck::cast_slice(&gpu_redraw.triangles.vertices)),
-                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-                });
-                {
-                    let load_color = match i == 0 {
-                        true => wgpu::LoadOp::Clear(wgpu::Color::WHITE),
-                        false => wgpu::LoadOp::Load,
-                    };
+                // Written before the pass so a regrown table is bound right away
+                let transforms_regrown =
+                    transform_table.write(device, queue, &gpu_redraw.texture)?;
+                let attributes_regrown =
+                    attribute_table.write(device, queue, &gpu_redraw.attributes)?;
+                let gradients_regrown =
+                    gradient_table.write(device, queue, &gpu_redraw.gradients)?;
+                let mut images_regrown = false;
+                if let Some(dirty_images) = &gpu_redraw.dirty_images {
+                    let (rects, atlas_regrown) =
+                        image_atlas.write(device, queue, &gpu_redraw.images, dirty_images)?;
+                    let rects_regrown =
+                        image_table.write(device, queue, bytemuck::cast_slice(&rects))?;
+                    images_regrown = atlas_regrown || rects_regrown;
+                    gpu_redraw.dirty_images = None;
+                }
+                if transforms_regrown || attributes_regrown || gradients_regrown || images_regrown {
+                    *bind_group = create_bind_group(
+                        device,
+                        bind_group_layout,
+                        uniform_buffer,
+                        [
+                            transform_table,
+                            attribute_table,
+                            gradient_table,
+                            image_table,
+                        ],
+                        image_atlas,
+                    );
+                }
 
-                    let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                        label: None,
-                        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                            view: &msaa_texture,
-                            resolve_target: Some(view),
-                            ops: wgpu::Operations {
-                                load: load_color,
-                                store: wgpu::StoreOp::Store,
-                            },
-                        })],
-                        depth_stencil_attachment: None,
-                        ..Default::default()
-                    });
-                    rpass.set_pipeline(render_pipeline);
-                    rpass.set_bind_group(0, bind_group, &[]);
-                    rpass.set_vertex_buffer(0, vertex_buffer.slice(..));
-                    rpass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                    rpass.draw_indexed(0..(gpu_redraw.triangles.indices.len() as u32), 0, 0..1);
+                if let Some((index_start, vertex_start)) = gpu_redraw.dirty_triangles.take() {
+                    upload_from(
+                        device,
+                        queue,
+                        vertex_buffer,
+                        bytemuck::cast_slice(&gpu_redraw.triangles.vertices),
+                        vertex_start * std::mem::size_of::<Vert>(),
+                    );
+                    upload_from(
+                        device,
+                        queue,
+                        index_buffer,
+                        bytemuck::cast_slice(&gpu_redraw.triangles.indices),
+                        index_start * std::mem::size_of::<Index>(),
+                    );
+                }
+                let index_count = gpu_redraw.triangles.indices.len() as u32;
+                let draw_all = [DrawCommand::Draw(0..index_count)];
+                let draw_commands = match gpu_redraw.draw_commands.is_empty() {
+                    true => &draw_all[..],
+                    false => &gpu_redraw.draw_commands[..],
+                };
+                let color_load = match i == 0 {
+                    true => wgpu::LoadOp::Clear(*clear_color),
+                    false => wgpu::LoadOp::Load,
+                };
+                let root = match through_scene {
+                    true => compositor.scene_target(color_load, wgpu::LoadOp::Clear(0)),
+                    false => RenderTarget {
+                        view,
+                        texture: None,
+                        msaa_view: msaa_view.as_ref(),
+                        stencil_view,
+                        color_load,
+                        stencil_load: wgpu::LoadOp::Clear(0),
+                    },
+                };
+                // Open layers, innermost last, each with the number of clips open on it
+                let mut targets = vec![(root, 0)];
+                let mut rest = draw_commands;
+                loop {
+                    let run_length = rest
+                        .iter()
+                        .position(|command| {
+                            matches!(command, DrawCommand::PushLayer | DrawCommand::PopLayer(_))
+                        })
+                        .unwrap_or(rest.len());
+                    let (run, next) = rest.split_at(run_length);
+                    let pipeline = match targets.len() > 1 {
+                        true => &*layer_pipeline,
+                        false => &*render_pipeline,
+                    };
+                    let (target, clip_depth) = targets.last_mut().unwrap();
+                    let pending_clear = matches!(target.color_load, wgpu::LoadOp::Clear(_))
+                        || matches!(target.stencil_load, wgpu::LoadOp::Clear(_));
+                    if !run.is_empty() || pending_clear {
+                        let mut rpass = target.begin_pass(encoder);
+                        rpass.set_bind_group(0, bind_group, &[]);
+                        rpass.set_vertex_buffer(0, vertex_buffer.slice(..));
+                        rpass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                        draw_run(
+                            &mut rpass,
+                            [pipeline, push_clip_pipeline, pop_clip_pipeline],
+                            run,
+                            clip_depth,
+                            index_count,
+                        );
+                    }
+                    match next.first() {
+                        Some(DrawCommand::PushLayer) => {
+                            targets.push((compositor.layer_target(targets.len() - 1), 0))
+                        }
+                        Some(DrawCommand::PopLayer(_)) => {
+                            composite_index += 1;
+                            if targets.len() > 1 {
+                                targets.pop();
+                                let depth = targets.len() - 1;
+                                let (target, clip_depth) = targets.last_mut().unwrap();
+                                compositor.composite(
+                                    encoder,
+                                    depth,
+                                    composite_index - 1,
+                                    target,
+                                    *clip_depth,
+                                );
+                            }
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                    rest = &next[1..];
                 }
                 queue.write_buffer(
                     uniform_buffer,
                     0,
                     bytemuck::cast_slice(&[Uniform {
                         transform: *transform,
+                        attribute_count: (gpu_redraw.attributes.len()
+                            / std::mem::size_of::<TransformAttributes>())
+                            as u32,
+                        ..Default::default()
                     }]),
                 );
-                queue.write_texture(
-                    transform_texture.as_image_copy(),
-                    &gpu_redraw.texture,
-                    wgpu::ImageDataLayout::default(),
-                    TRANSFORM_TEXTURE_SIZE,
-                );
-            });
+                Ok(())
+            })?;
+        if through_scene {
+            compositor.blit(&mut reframe.encoder, &reframe.view);
+        }
+        Ok(())
     }
     pub fn get_frame(&self) -> Reframe {
-        let frame = self
-            .surface
-            .get_current_texture()
-            .expect("Failed to acquire next swap chain texture");
-        let view = frame
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        let frame = self.surface.as_ref().map(|surface| {
+            surface
+                .get_current_texture()
+                .expect("Failed to acquire next swap chain texture")
+        });
+        let texture = match (&frame, &self.offscreen_texture) {
+            (Some(frame), _) => &frame.texture,
+            (None, Some(offscreen_texture)) => offscreen_texture,
+            (None, None) => panic!("RedrawMachine has neither a surface nor an offscreen texture"),
+        };
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
@@ -163,45 +303,98 @@ impl<'a> RedrawMachine<'a> {
 
     pub fn submit(&self, reframe: Reframe) {
         self.queue.submit(Some(reframe.encoder.finish()));
-        reframe.frame.present();
+        if let Some(frame) = reframe.frame {
+            frame.present();
+        }
     }
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
         // Reconfigure the surface with the new size
         self.config.width = size.width;
         self.config.height = size.height;
-        self.surface.configure(&self.device, &self.config);
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+        if self.offscreen_texture.is_some() {
+            self.offscreen_texture = Some(create_offscreen_texture(&self.device, &self.config));
+        }
+        self.msaa_view = create_msaa_view(&self.device, &self.config, self.sample_count);
+        self.stencil_view = create_stencil_view(&self.device, &self.config, self.sample_count);
+        self.compositor.resize();
     }
-    pub async fn new(window: Arc<Window>) -> Self {
+    /// Copies the offscreen texture into a tightly packed RGBA buffer.
+    /// Returns `None` when rendering to a window surface.
+    pub fn read_offscreen(&self) -> Option<Vec<u8>> {
+        let texture = self.offscreen_texture.as_ref()?;
+        let (width, height) = (self.config.width, self.config.height);
+        let unpadded_bytes_per_row = width * 4;
+        let padded_bytes_per_row = unpadded_bytes_per_row
+            .div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+            * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let readback_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Offscreen readback buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &readback_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = readback_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("Failed to map offscreen readback buffer")
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        let padded = slice.get_mapped_range();
+        let rgba = padded
+            .chunks(padded_bytes_per_row as usize)
+            .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+            .copied()
+            .collect();
+        drop(padded);
+        readback_buffer.unmap();
+        Some(rgba)
+    }
+    pub async fn new(window: Arc<Window>, guppy_config: &GuppyConfig) -> Self {
         let size = window.inner_size();
         let instance = wgpu::Instance::default();
         let surface = instance
             .create_surface(window)
             .expect("Surface creation failed");
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                force_fallback_adapter: false,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .expect("Failed to find an appropriate adapter");
+        let (adapter, device, queue, storage_buffer) =
+            request_device(&instance, Some(&surface)).await;
 
         let surface_capabilities = surface.get_capabilities(&adapter);
-        let surface_format = surface_capabilities.formats.first().unwrap().clone();
-        // Create the logical device and command queue
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: Some("SVG-GUI DeviceDescriptor"),
-                    required_features: wgpu::Features::empty(),
-                    // Make sure we use the texture resolution limits from the adapter, so we can support images the size of the surface.
-                    required_limits: wgpu::Limits::downlevel_webgl2_defaults()
-                        .using_resolution(adapter.limits()),
-                },
-                None,
-            )
-            .await
-            .expect("Failed to create device");
+        let surface_format = surface_capabilities
+            .formats
+            .iter()
+            .find(|format| format.is_srgb() == guppy_config.srgb)
+            .or(surface_capabilities.formats.first())
+            .unwrap()
+            .clone();
+        let present_mode = match guppy_config.present_mode {
+            mode @ (wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync) => mode,
+            mode if surface_capabilities.present_modes.contains(&mode) => mode,
+            mode => {
+                log::warn!("{mode:?} is not supported by the surface, using AutoVsync");
+                wgpu::PresentMode::AutoVsync
+            }
+        };
+        let sample_count =
+            supported_sample_count(&adapter, &device, surface_format, guppy_config.sample_count);
 
         let config = wgpu::SurfaceConfiguration {
             desired_maximum_frame_latency: 2,
@@ -209,47 +402,402 @@ impl<'a> RedrawMachine<'a> {
             format: surface_format,
             width: size.width,
             height: size.height,
+            present_mode,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: Default::default(),
+        };
+        let msaa_view = create_msaa_view(&device, &config, sample_count);
+        let stencil_view = create_stencil_view(&device, &config, sample_count);
+        let compositor = Compositor::new(&device, surface_format, sample_count);
+        RedrawMachine {
+            msaa_view,
+            stencil_view,
+            compositor,
+            device,
+            surface: Some(surface),
+            queue,
+            config,
+            surface_format,
+            offscreen_texture: None,
+            clear_color: guppy_config.clear_color,
+            sample_count,
+            storage_buffer,
+        }
+    }
+    /// Creates a machine which renders into an offscreen texture instead of a window.
+    /// Falls back to wgpu's software adapter when no hardware adapter is available.
+    pub async fn new_headless(
+        size: PhysicalSize<u32>,
+        guppy_config: &GuppyConfig,
+    ) -> RedrawMachine<'static> {
+        let instance = wgpu::Instance::default();
+        let (adapter, device, queue, storage_buffer) = request_device(&instance, None).await;
+        let surface_format = match guppy_config.srgb {
+            true => TextureFormat::Rgba8UnormSrgb,
+            false => TextureFormat::Rgba8Unorm,
+        };
+        let sample_count =
+            supported_sample_count(&adapter, &device, surface_format, guppy_config.sample_count);
+        let config = wgpu::SurfaceConfiguration {
+            desired_maximum_frame_latency: 2,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: surface_format,
+            width: size.width,
+            height: size.height,
             present_mode: wgpu::PresentMode::AutoVsync,
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: Default::default(),
         };
+        let offscreen_texture = create_offscreen_texture(&device, &config);
+        let msaa_view = create_msaa_view(&device, &config, sample_count);
+        let stencil_view = create_stencil_view(&device, &config, sample_count);
+        let compositor = Compositor::new(&device, surface_format, sample_count);
         RedrawMachine {
+            msaa_view,
+            stencil_view,
+            compositor,
             device,
-            surface,
+            surface: None,
             queue,
             config,
             surface_format,
+            offscreen_texture: Some(offscreen_texture),
+            clear_color: guppy_config.clear_color,
+            sample_count,
+            storage_buffer,
         }
     }
 }
 
-const SAMPLE_COUNT: u32 = 4;
+async fn request_device(
+    instance: &wgpu::Instance,
+    compatible_surface: Option<&Surface<'_>>,
+) -> (wgpu::Adapter, Device, Queue, bool) {
+    let request_adapter = |force_fallback_adapter| {
+        instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            force_fallback_adapter,
+            compatible_surface,
+        })
+    };
+    let adapter = match request_adapter(false).await {
+        Some(adapter) => adapter,
+        None => request_adapter(true)
+            .await
+            .expect("Failed to find an appropriate adapter"),
+    };
+
+    // WebGL2 can't read storage buffers from the vertex stage.
+    // The fragment stage reads both the gradient and the image table.
+    let adapter_limits = adapter.limits();
+    let storage_buffer = adapter
+        .get_downlevel_capabilities()
+        .flags
+        .contains(wgpu::DownlevelFlags::VERTEX_STORAGE)
+        && adapter_limits.max_storage_buffers_per_shader_stage >= 2;
+    // Make sure we use the texture resolution limits from the adapter, so we can support images the size of the surface.
+    let mut required_limits =
+        wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter_limits.clone());
+    if storage_buffer {
+        required_limits.max_storage_buffers_per_shader_stage = 2;
+        required_limits.max_storage_buffer_binding_size =
+            adapter_limits.max_storage_buffer_binding_size;
+        required_limits.max_buffer_size = adapter_limits.max_buffer_size;
+    }
+
+    // Create the logical device and command queue
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("SVG-GUI DeviceDescriptor"),
+                required_features: wgpu::Features::empty(),
+                required_limits,
+            },
+            None,
+        )
+        .await
+        .expect("Failed to create device");
+    (adapter, device, queue, storage_buffer)
+}
+
+/// Returns `requested` if the device can multisample `format` that many times, otherwise 1.
+fn supported_sample_count(
+    adapter: &wgpu::Adapter,
+    device: &Device,
+    format: TextureFormat,
+    requested: u32,
+) -> u32 {
+    let features = match device
+        .features()
+        .contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+    {
+        true => adapter.get_texture_format_features(format),
+        false => format.guaranteed_format_features(device.features()),
+    };
+    if requested == 1 || features.flags.sample_count_supported(requested) {
+        requested
+    } else {
+        log::warn!("{requested}x MSAA is not supported for {format:?}, disabling MSAA");
+        1
+    }
+}
+
+fn create_msaa_view(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+) -> Option<TextureView> {
+    if sample_count == 1 {
+        return None;
+    }
+    let view = device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some("Multisampled frame descriptor"),
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: Default::default(),
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default());
+    Some(view)
+}
+
+fn create_stencil_view(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+) -> TextureView {
+    device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some("Clip stencil texture"),
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: STENCIL_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: Default::default(),
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+/// Samples are drawn when the stencil equals the number of clips open on the target.
+fn draw_run<'a>(
+    rpass: &mut wgpu::RenderPass<'a>,
+    [render_pipeline, push_clip_pipeline, pop_clip_pipeline]: [&'a RenderPipeline; 3],
+    draw_commands: &[DrawCommand],
+    clip_depth: &mut u32,
+    index_count: u32,
+) {
+    for draw_command in draw_commands {
+        let (pipeline, range) = match draw_command {
+            DrawCommand::Draw(range) => (render_pipeline, range),
+            DrawCommand::PushClip(range) => (push_clip_pipeline, range),
+            DrawCommand::PopClip(range) => (pop_clip_pipeline, range),
+            DrawCommand::PushLayer | DrawCommand::PopLayer(_) => continue,
+        };
+        rpass.set_pipeline(pipeline);
+        rpass.set_stencil_reference(*clip_depth);
+        rpass.draw_indexed(
+            range.start.min(index_count)..range.end.min(index_count),
+            0,
+            0..1,
+        );
+        match draw_command {
+            DrawCommand::PushClip(_) => *clip_depth += 1,
+            DrawCommand::PopClip(_) => *clip_depth = clip_depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+}
+
+/// How deeply layers nest in `draw_commands`.
+fn layer_depth(draw_commands: &[DrawCommand]) -> usize {
+    draw_commands
+        .iter()
+        .fold((0usize, 0), |(depth, max), command| match command {
+            DrawCommand::PushLayer => (depth + 1, max.max(depth + 1)),
+            DrawCommand::PopLayer(_) => (depth.saturating_sub(1), max),
+            _ => (depth, max),
+        })
+        .1
+}
+
+/// Only the stencil is used, samples pass where it equals the reference (the clip depth)
+/// and `pass_op` is applied to it.
+pub fn stencil_state(pass_op: wgpu::StencilOperation) -> wgpu::DepthStencilState {
+    let face = wgpu::StencilFaceState {
+        compare: wgpu::CompareFunction::Equal,
+        fail_op: wgpu::StencilOperation::Keep,
+        depth_fail_op: wgpu::StencilOperation::Keep,
+        pass_op,
+    };
+    wgpu::DepthStencilState {
+        format: STENCIL_FORMAT,
+        depth_write_enabled: false,
+        depth_compare: wgpu::CompareFunction::Always,
+        stencil: wgpu::StencilState {
+            front: face,
+            back: face,
+            read_mask: !0,
+            write_mask: !0,
+        },
+        bias: Default::default(),
+    }
+}
+
+/// Draws the clip shapes of `DrawCommand::PushClip` and `DrawCommand::PopClip` into
+/// the stencil only. Samples already outside the enclosing clips fail the stencil test,
+/// so nested clips intersect.
+fn create_clip_pipeline(
+    redraw_machine: &RedrawMachine,
+    pipeline_layout: &PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    pass_op: wgpu::StencilOperation,
+) -> RenderPipeline {
+    redraw_machine
+        .device
+        .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("clip pipeline"),
+            layout: Some(pipeline_layout),
+            vertex: wgpu::VertexState {
+                compilation_options: Default::default(),
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &VERTEX_ATTRIBUTES,
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                compilation_options: Default::default(),
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: redraw_machine.surface_format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::empty(),
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(stencil_state(pass_op)),
+            multisample: wgpu::MultisampleState {
+                count: redraw_machine.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+}
+
+/// Draws into layers, which have to end up premultiplied for the compositor.
+/// Outside of them colours are blended as they are, which the scene was always drawn with.
+fn create_layer_pipeline(
+    redraw_machine: &RedrawMachine,
+    pipeline_layout: &PipelineLayout,
+    vertex_shader: &wgpu::ShaderModule,
+    (fragment_shader, entry_point): (&wgpu::ShaderModule, &str),
+    blend: wgpu::BlendState,
+) -> RenderPipeline {
+    redraw_machine
+        .device
+        .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("layer pipeline"),
+            layout: Some(pipeline_layout),
+            vertex: wgpu::VertexState {
+                compilation_options: Default::default(),
+                module: vertex_shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &VERTEX_ATTRIBUTES,
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                compilation_options: Default::default(),
+                module: fragment_shader,
+                entry_point,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: redraw_machine.surface_format,
+                    blend: Some(blend),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(stencil_state(wgpu::StencilOperation::Keep)),
+            multisample: wgpu::MultisampleState {
+                count: redraw_machine.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+}
+
+/// Uploads `contents[start..]` into `buffer`, reusing it across frames.
+/// The buffer is regrown to the next power of two when `contents` no longer fits,
+/// in which case everything is uploaded again.
+fn upload_from(device: &Device, queue: &Queue, buffer: &mut Buffer, contents: &[u8], start: usize) {
+    let required = contents.len() as wgpu::BufferAddress;
+    let start = if required > buffer.size() {
+        *buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SVG-GUI Growable Buffer"),
+            size: required
+                .next_power_of_two()
+                .max(wgpu::COPY_BUFFER_ALIGNMENT),
+            usage: buffer.usage(),
+            mapped_at_creation: false,
+        });
+        0
+    } else {
+        start.min(contents.len())
+    };
+    if start < contents.len() {
+        queue.write_buffer(buffer, start as wgpu::BufferAddress, &contents[start..]);
+    }
+}
+
+fn create_offscreen_texture(device: &Device, config: &SurfaceConfiguration) -> Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen frame texture"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: config.usage,
+        view_formats: Default::default(),
+    })
+}
 
 fn get_uniform_buffer(
     device: &Device,
     contents: &[u8],
-) -> (
-    wgpu::Buffer,
-    wgpu::BindGroup,
-    wgpu::BindGroupLayout,
-    wgpu::Texture,
-) {
+    storage_buffer: bool,
+) -> (wgpu::Buffer, wgpu::BindGroupLayout) {
     let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: None,
         contents,
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
-    let transform_texture = device.create_texture(&wgpu::TextureDescriptor {
-        label: Some("transform texture"),
-        size: TRANSFORM_TEXTURE_SIZE,
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D1,
-        format: wgpu::TextureFormat::Rgba32Float,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-        view_formats: Default::default(),
-    });
     let uniform_bind_group_layout =
         device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
@@ -266,19 +814,55 @@ fn get_uniform_buffer(
                 wgpu::BindGroupLayoutEntry {
                     binding: 1,
                     visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                        view_dimension: wgpu::TextureViewDimension::D1,
-                        multisampled: false,
-                    },
+                    ty: TexelTable::binding_type(storage_buffer),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: TexelTable::binding_type(storage_buffer),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: ImageAtlas::texture_binding_type(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: ImageAtlas::sampler_binding_type(),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: TexelTable::binding_type(storage_buffer),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: TexelTable::binding_type(storage_buffer),
                     count: None,
                 },
             ],
             label: Some("uniform_bind_group_layout"),
         });
 
-    let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &uniform_bind_group_layout,
+    (uniform_buffer, uniform_bind_group_layout)
+}
+
+fn create_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    uniform_buffer: &Buffer,
+    [transform_table, attribute_table, gradient_table, image_table]: [&TexelTable; 4],
+    image_atlas: &ImageAtlas,
+) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
         entries: &[
             wgpu::BindGroupEntry {
                 binding: 0,
@@ -286,19 +870,40 @@ fn get_uniform_buffer(
             },
             wgpu::BindGroupEntry {
                 binding: 1,
-                resource: wgpu::BindingResource::TextureView(
-                    &transform_texture.create_view(&wgpu::TextureViewDescriptor::default()),
-                ),
+                resource: transform_table.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: gradient_table.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(image_atlas.view()),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::Sampler(image_atlas.sampler()),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: image_table.binding_resource(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: attribute_table.binding_resource(),
             },
         ],
         label: Some("uniform_bind_group"),
-    });
-    (
-        uniform_buffer,
-        uniform_bind_group,
-        uniform_bind_group_layout,
-        transform_texture,
-    )
+    })
+}
+
+/// shader.wgsl with the table lookups matching how the tables are stored.
+fn default_shader_source(storage_buffer: bool) -> String {
+    [
+        TexelTable::shader_prelude(storage_buffer),
+        include_str!("shader.wgsl"),
+    ]
+    .concat()
 }
 
 impl Redraw {
@@ -306,11 +911,13 @@ impl Redraw {
         let RedrawMachine {
             device,
             surface_format,
+            sample_count,
+            storage_buffer,
             ..
         } = redraw_machine;
         let default_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader.wgsl"))),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(default_shader_source(*storage_buffer))),
         });
         let custom_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: None,
@@ -321,17 +928,17 @@ impl Redraw {
             label: None,
             layout: Some(&self.pipeline_layout),
             vertex: wgpu::VertexState {
-                compilation_options:Default::default(),
+                compilation_options: Default::default(),
                 module: &default_shader,
                 entry_point: "vs_main",
                 buffers: &[wgpu::VertexBufferLayout {
                     array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
                     step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &wgpu::vertex_attr_array![0 => Float32x3, 1 => Uint32, 2 => Float32x4],
+                    attributes: &VERTEX_ATTRIBUTES,
                 }],
             },
             fragment: Some(wgpu::FragmentState {
-                compilation_options:Default::default(),
+                compilation_options: Default::default(),
                 module: &custom_shader,
                 entry_point: "fs_main",
                 targets: &[Some(wgpu::ColorTargetState {
@@ -341,15 +948,23 @@ impl Redraw {
                 })],
             }),
             primitive: wgpu::PrimitiveState::default(),
-            depth_stencil: None,
+            depth_stencil: Some(stencil_state(wgpu::StencilOperation::Keep)),
             multisample: wgpu::MultisampleState {
-                count: SAMPLE_COUNT,
+                count: *sample_count,
                 mask: !0,
                 alpha_to_coverage_enabled: false,
             },
             multiview: None,
         });
         self.render_pipeline = render_pipeline;
+        // Straight colours blended over a cleared layer come out premultiplied
+        self.layer_pipeline = create_layer_pipeline(
+            redraw_machine,
+            &self.pipeline_layout,
+            &default_shader,
+            (&custom_shader, "fs_main"),
+            wgpu::BlendState::ALPHA_BLENDING,
+        );
     }
 
     pub fn new(
@@ -364,59 +979,82 @@ impl Redraw {
             surface,
             config,
             surface_format,
+            sample_count,
+            storage_buffer,
+            ..
         } = redraw_machine;
         // Load the shaders from disk
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader.wgsl"))),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(default_shader_source(*storage_buffer))),
         });
 
-        let (uniform_buffer, uniform_bind_group, uniform_bind_group_layout, transform_texture) =
-            get_uniform_buffer(
-                &device,
-                bytemuck::cast_slice(&[Uniform {
-                    transform: Mat4::IDENTITY,
-                }]),
-            );
+        let (uniform_buffer, uniform_bind_group_layout) = get_uniform_buffer(
+            &device,
+            bytemuck::cast_slice(&[Uniform {
+                transform: Mat4::IDENTITY,
+                ..Default::default()
+            }]),
+            *storage_buffer,
+        );
+        let transform_table = TexelTable::new(device, *storage_buffer, "transform");
+        let attribute_table = TexelTable::new(device, *storage_buffer, "attribute");
+        let gradient_table = TexelTable::new(device, *storage_buffer, "gradient");
+        let image_table = TexelTable::new(device, *storage_buffer, "image");
+        let image_atlas = ImageAtlas::new(device);
+        let uniform_bind_group = create_bind_group(
+            device,
+            &uniform_bind_group_layout,
+            &uniform_buffer,
+            [
+                &transform_table,
+                &attribute_table,
+                &gradient_table,
+                &image_table,
+            ],
+            &image_atlas,
+        );
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             bind_group_layouts: &[&uniform_bind_group_layout],
             ..Default::default()
         });
 
         let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: None,
-        layout: Some(&pipeline_layout),
-        vertex: wgpu::VertexState {
-            compilation_options:Default::default(),
-            module: &shader,
-            entry_point: "vs_main",
-            buffers: &[wgpu::VertexBufferLayout {
-                array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
-                step_mode: wgpu::VertexStepMode::Vertex,
-                attributes: &wgpu::vertex_attr_array![0 => Float32x3, 1 => Uint32, 2 => Float32x4],
-            }],
-        },
-        fragment: Some(wgpu::FragmentState {
-            compilation_options:Default::default(),
-            module: &shader,
-            entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: *surface_format,
-                blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
-        }),
-        primitive: wgpu::PrimitiveState::default(),
-        depth_stencil: None,
-        multisample: wgpu::MultisampleState {
-            count: SAMPLE_COUNT,
-            mask: !0,
-            alpha_to_coverage_enabled: false,
-        },
-        multiview: None,
-    });
+            label: None,
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                compilation_options: Default::default(),
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &VERTEX_ATTRIBUTES,
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                compilation_options: Default::default(),
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: *surface_format,
+                    blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(stencil_state(wgpu::StencilOperation::Keep)),
+            multisample: wgpu::MultisampleState {
+                count: *sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        });
 
-        surface.configure(&device, &config);
+        if let Some(surface) = surface {
+            surface.configure(&device, &config);
+        }
         let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Index Buffer"),
             contents: bytemuck::cast_slice(indices),
@@ -428,22 +1066,65 @@ impl Redraw {
             contents: (bytemuck::cast_slice(vertices)),
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
         });
+        let layer_pipeline = create_layer_pipeline(
+            redraw_machine,
+            &pipeline_layout,
+            &shader,
+            (&shader, "fs_layer"),
+            wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+        );
+        let push_clip_pipeline = create_clip_pipeline(
+            redraw_machine,
+            &pipeline_layout,
+            &shader,
+            wgpu::StencilOperation::IncrementClamp,
+        );
+        let pop_clip_pipeline = create_clip_pipeline(
+            redraw_machine,
+            &pipeline_layout,
+            &shader,
+            wgpu::StencilOperation::DecrementClamp,
+        );
         Redraw {
             render_pipeline,
+            layer_pipeline,
+            push_clip_pipeline,
+            pop_clip_pipeline,
             bind_group: uniform_bind_group,
             uniform_buffer,
             vertex_buffer,
             index_buffer,
             transform: Mat4::IDENTITY,
-            transform_texture,
+            transform_table,
+            attribute_table,
+            gradient_table,
+            image_table,
+            image_atlas,
+            bind_group_layout: uniform_bind_group_layout,
             pipeline_layout,
         }
     }
 }
 
+// Only the stencil half is used, Depth24PlusStencil8 is the most widely supported stencil format
+pub const STENCIL_FORMAT: TextureFormat = TextureFormat::Depth24PlusStencil8;
+
+const VERTEX_ATTRIBUTES: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+    0 => Float32x3,
+    1 => Uint32,
+    2 => Float32x4,
+    3 => Float32x2,
+    4 => Uint32,
+    5 => Float32x2,
+    6 => Uint32
+];
+
 // Default scene has all values set to zero
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Uniform {
     pub transform: Mat4,
+    // Transform ids from here on have no attributes and are drawn as they are
+    pub attribute_count: u32,
+    pub _padding: [u32; 3],
 }
@@ -2,44 +2,181 @@ struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) transforms: u32,
     @location(2) color: vec4<f32>,
+    @location(3) gradient_position: vec2<f32>,
+    @location(4) gradient_id: u32,
+    @location(5) uv: vec2<f32>,
+    @location(6) image_id: u32,
 };
 struct Uniform {
-    transform: mat4x4<f32>
+    transform: mat4x4<f32>,
+    attribute_count: u32,
 };
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(0) color: vec4<f32>
+    @location(0) color: vec4<f32>,
+    @location(1) gradient_position: vec2<f32>,
+    @location(2) @interpolate(flat) gradient_id: u32,
+    @location(3) uv: vec2<f32>,
+    @location(4) @interpolate(flat) image_id: u32,
 };
 
 
 @group(0) @binding(0) var<uniform> u: Uniform;
-@group(0) @binding(1) var transform_texture : texture_1d<f32>;
+@group(0) @binding(3) var image_atlas: texture_2d<f32>;
+@group(0) @binding(4) var image_sampler: sampler;
+
+// load_*_texel are declared by the texel table prelude
+fn get_transform(id: u32) -> mat4x4<f32> {
+    let i = id * 4u;
+    return mat4x4<f32>(
+        load_transform_texel(i),
+        load_transform_texel(i + 1u),
+        load_transform_texel(i + 2u),
+        load_transform_texel(i + 3u),
+    );
+}
+
+// The attribute table holds two texels per transform id, see guppies' TransformAttributes:
+// the tint, then (opacity, visibility, _, _)
+fn attributed_color(id: u32, color: vec4<f32>) -> vec4<f32> {
+    if id >= u.attribute_count {
+        return color;
+    }
+    let tint = load_attribute_texel(id * 2u);
+    let opacity = load_attribute_texel(id * 2u + 1u).x;
+    return color * tint * vec4<f32>(1.0, 1.0, 1.0, opacity);
+}
+
+fn is_hidden(id: u32) -> bool {
+    return id < u.attribute_count && load_attribute_texel(id * 2u + 1u).y == 0.0;
+}
 
 @vertex
 fn vs_main(
     model: VertexInput,
 ) -> VertexOutput {
     var out: VertexOutput;
-    out.color = model.color;
+    // Every vertex of a hidden triangle lands on the same point, so nothing is rasterized
+    if is_hidden(model.transforms) {
+        out.clip_position = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        return out;
+    }
+    out.color = attributed_color(model.transforms, model.color);
+    out.gradient_position = model.gradient_position;
+    out.gradient_id = model.gradient_id;
+    out.uv = model.uv;
+    out.image_id = model.image_id;
 
-    var g_t1 = textureLoad(transform_texture, 0, 0);
-    var g_t2 = textureLoad(transform_texture, 1, 0);
-    var g_t3 = textureLoad(transform_texture, 2, 0);
-    var g_t4 = textureLoad(transform_texture, 3, 0);
-
-    var transform_id = i32(model.transforms) * 4;
-    var t1 = textureLoad(transform_texture, transform_id, 0);
-    var t2 = textureLoad(transform_texture, transform_id + 1, 0);
-    var t3 = textureLoad(transform_texture, transform_id + 2, 0);
-    var t4 = textureLoad(transform_texture, transform_id + 3, 0);
-
-    var texture_transform = mat4x4<f32>(t1, t2, t3, t4);
-    var global_texture_transform = mat4x4<f32>(g_t1, g_t2, g_t3, g_t4);
+    var texture_transform = get_transform(model.transforms);
+    var global_texture_transform = get_transform(0u);
     out.clip_position = global_texture_transform * texture_transform * vec4<f32>(model.position, 1.0);
     return out;
 }
 
+// Gradient layout in the gradient table, see salvage's paint module:
+// (kind, spread, stop count, _), (x1, y1, x2, y2) or (cx, cy, r, _), (fx, fy, _, _),
+// then (offset, _, _, _), (r, g, b, a) for every stop.
+const LINEAR_GRADIENT: u32 = 0u;
+const SPREAD_REFLECT: u32 = 1u;
+const SPREAD_REPEAT: u32 = 2u;
+
+fn gradient_offset(header: u32, p: vec2<f32>) -> f32 {
+    let kind = u32(load_gradient_texel(header).x);
+    let geometry = load_gradient_texel(header + 1u);
+    if kind == LINEAR_GRADIENT {
+        let direction = geometry.zw - geometry.xy;
+        let length_squared = dot(direction, direction);
+        if length_squared == 0.0 {
+            return 0.0;
+        }
+        return dot(p - geometry.xy, direction) / length_squared;
+    }
+    // Two point conical gradient from the focal point to the circle
+    let center = geometry.xy;
+    let r = geometry.z;
+    let focal = load_gradient_texel(header + 2u).xy;
+    let d = center - focal;
+    let q = p - focal;
+    let a = dot(d, d) - r * r;
+    let b = dot(q, d);
+    let c = dot(q, q);
+    if abs(a) < 0.0001 {
+        return c / (2.0 * b);
+    }
+    return (b - sqrt(max(b * b - a * c, 0.0))) / a;
+}
+
+fn gradient_color(header: u32, p: vec2<f32>) -> vec4<f32> {
+    let info = load_gradient_texel(header);
+    let spread = u32(info.y);
+    let stop_count = u32(info.z);
+    var t = gradient_offset(header, p);
+    if spread == SPREAD_REPEAT {
+        t = fract(t);
+    } else if spread == SPREAD_REFLECT {
+        t = 1.0 - abs(fract(t * 0.5) * 2.0 - 1.0);
+    } else {
+        t = clamp(t, 0.0, 1.0);
+    }
+    let stops = header + 3u;
+    var previous_offset = load_gradient_texel(stops).x;
+    var previous_color = load_gradient_texel(stops + 1u);
+    if t <= previous_offset {
+        return previous_color;
+    }
+    for (var i = 1u; i < stop_count; i++) {
+        let offset = load_gradient_texel(stops + i * 2u).x;
+        let color = load_gradient_texel(stops + i * 2u + 1u);
+        if t <= offset {
+            let span = offset - previous_offset;
+            if span <= 0.0 {
+                return color;
+            }
+            return mix(previous_color, color, (t - previous_offset) / span);
+        }
+        previous_offset = offset;
+        previous_color = color;
+    }
+    return previous_color;
+}
+
+// The image table holds one (u0, v0, u1, v1) rect in the atlas per image
+fn image_color(id: u32, uv: vec2<f32>) -> vec4<f32> {
+    let rect = load_image_texel(id);
+    // Keep bilinear filtering from bleeding into the neighbouring images
+    let half_texel = 0.5 / vec2<f32>(textureDimensions(image_atlas));
+    let atlas_uv = clamp(mix(rect.xy, rect.zw, uv), rect.xy + half_texel, rect.zw - half_texel);
+    // Sampled in non-uniform control flow, and the atlas has no mipmaps anyway
+    return textureSampleLevel(image_atlas, image_sampler, atlas_uv, 0.0);
+}
+
+fn premultiply(color: vec4<f32>) -> vec4<f32> {
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
+
+fn fill_color(in: VertexOutput) -> vec4<f32> {
+    // gradient_id 0 means a plain colour fill, otherwise it's the gradient's header index + 1
+    if in.gradient_id == 0u {
+        return in.color;
+    }
+    return gradient_color(in.gradient_id - 1u, in.gradient_position) * in.color;
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return in.color;
+    // image_id 0 means no image, otherwise it's the image's index + 1
+    if in.image_id != 0u {
+        // Images are stored straight alpha, the blend state expects premultiplied
+        return premultiply(image_color(in.image_id - 1u, in.uv) * in.color);
+    }
+    return fill_color(in);
+}
+
+// Draws into layers, which the compositor expects premultiplied throughout
+@fragment
+fn fs_layer(in: VertexOutput) -> @location(0) vec4<f32> {
+    if in.image_id != 0u {
+        return premultiply(image_color(in.image_id - 1u, in.uv) * in.color);
+    }
+    return premultiply(fill_color(in));
 }
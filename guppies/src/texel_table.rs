@@ -0,0 +1,204 @@
+use std::{error::Error, fmt::Display};
+use wgpu::{Buffer, Device, Extent3d, Queue, Texture, TextureView};
+
+// Bytes in one Rgba32Float texel
+const TEXEL_SIZE: usize = 16;
+// Texels in one row of the fallback texture
+const TEXTURE_WIDTH: u32 = 1024;
+const INITIAL_TEXELS: usize = 1024;
+
+/// Returned when a table doesn't fit into the largest
+/// storage buffer or texture the device can allocate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableOverflow {
+    pub table: &'static str,
+    pub texels: usize,
+    pub max_texels: usize,
+}
+
+impl Display for TableOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the {} table needs {} texels but this device can only hold {}",
+            self.table, self.texels, self.max_texels
+        )
+    }
+}
+
+impl Error for TableOverflow {}
+
+/// A growable table of `vec4<f32>` read by the shaders, e.g. transforms (four texels each)
+/// or gradients.
+/// A storage buffer is used where the adapter allows one, otherwise (e.g. WebGL2)
+/// a 2D texture which is `TEXTURE_WIDTH` texels wide.
+#[derive(Debug)]
+pub enum TexelTable {
+    Buffer(&'static str, Buffer),
+    Texture(&'static str, Texture, TextureView),
+}
+
+impl TexelTable {
+    pub fn new(device: &Device, storage_buffer: bool, table: &'static str) -> Self {
+        Self::with_capacity(device, storage_buffer, table, INITIAL_TEXELS)
+    }
+    fn with_capacity(
+        device: &Device,
+        storage_buffer: bool,
+        table: &'static str,
+        texels: usize,
+    ) -> Self {
+        match storage_buffer {
+            true => Self::Buffer(
+                table,
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(table),
+                    size: (texels * TEXEL_SIZE) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+            ),
+            false => {
+                let texture = device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some(table),
+                    size: Extent3d {
+                        width: TEXTURE_WIDTH,
+                        height: (texels as u32).div_ceil(TEXTURE_WIDTH).max(1),
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                    view_formats: Default::default(),
+                });
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                Self::Texture(table, texture, view)
+            }
+        }
+    }
+    pub fn binding_type(storage_buffer: bool) -> wgpu::BindingType {
+        match storage_buffer {
+            true => wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            false => wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+        }
+    }
+    /// WGSL which declares the table bindings along with
+    /// `fn load_transform_texel(i: u32) -> vec4<f32>`, `fn load_gradient_texel(i: u32) -> vec4<f32>`,
+    /// `fn load_image_texel(i: u32) -> vec4<f32>` and `fn load_attribute_texel(i: u32) -> vec4<f32>`.
+    pub fn shader_prelude(storage_buffer: bool) -> &'static str {
+        match storage_buffer {
+            true => include_str!("texel_table_buffer.wgsl"),
+            false => include_str!("texel_table_texture.wgsl"),
+        }
+    }
+    pub fn binding_resource(&self) -> wgpu::BindingResource<'_> {
+        match self {
+            Self::Buffer(_, buffer) => buffer.as_entire_binding(),
+            Self::Texture(_, _, view) => wgpu::BindingResource::TextureView(view),
+        }
+    }
+    fn max_texels(device: &Device, storage_buffer: bool) -> usize {
+        let limits = device.limits();
+        match storage_buffer {
+            true => {
+                (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size) as usize
+                    / TEXEL_SIZE
+            }
+            false => limits.max_texture_dimension_2d as usize * TEXTURE_WIDTH as usize,
+        }
+    }
+    fn capacity(&self) -> usize {
+        match self {
+            Self::Buffer(_, buffer) => buffer.size() as usize / TEXEL_SIZE,
+            Self::Texture(_, texture, _) => (texture.width() * texture.height()) as usize,
+        }
+    }
+    /// Uploads `bytes` (tightly packed texels), regrowing the table to the next
+    /// power of two when they don't fit.
+    /// Returns true when the table was recreated and the bind group has to be rebuilt.
+    pub fn write(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        bytes: &[u8],
+    ) -> Result<bool, TableOverflow> {
+        let (table, storage_buffer) = match self {
+            Self::Buffer(table, _) => (*table, true),
+            Self::Texture(table, ..) => (*table, false),
+        };
+        let texels = bytes.len() / TEXEL_SIZE;
+        let max_texels = Self::max_texels(device, storage_buffer);
+        if texels > max_texels {
+            return Err(TableOverflow {
+                table,
+                texels,
+                max_texels,
+            });
+        }
+        let regrown = texels > self.capacity();
+        if regrown {
+            let capacity = texels.next_power_of_two().min(max_texels);
+            *self = Self::with_capacity(device, storage_buffer, table, capacity);
+        }
+        match self {
+            Self::Buffer(_, buffer) => {
+                if texels > 0 {
+                    queue.write_buffer(buffer, 0, &bytes[..texels * TEXEL_SIZE]);
+                }
+            }
+            Self::Texture(_, texture, _) => {
+                // Full rows first, then whatever is left of the last row
+                let full_rows = texels as u32 / TEXTURE_WIDTH;
+                let remainder = texels as u32 % TEXTURE_WIDTH;
+                let row_bytes = TEXTURE_WIDTH as usize * TEXEL_SIZE;
+                if full_rows > 0 {
+                    queue.write_texture(
+                        texture.as_image_copy(),
+                        &bytes[..full_rows as usize * row_bytes],
+                        wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: Some(row_bytes as u32),
+                            rows_per_image: None,
+                        },
+                        Extent3d {
+                            width: TEXTURE_WIDTH,
+                            height: full_rows,
+                            depth_or_array_layers: 1,
+                        },
+                    );
+                }
+                if remainder > 0 {
+                    let start = full_rows as usize * row_bytes;
+                    queue.write_texture(
+                        wgpu::ImageCopyTexture {
+                            origin: wgpu::Origin3d {
+                                x: 0,
+                                y: full_rows,
+                                z: 0,
+                            },
+                            ..texture.as_image_copy()
+                        },
+                        &bytes[start..start + remainder as usize * TEXEL_SIZE],
+                        wgpu::ImageDataLayout::default(),
+                        Extent3d {
+                            width: remainder,
+                            height: 1,
+                            depth_or_array_layers: 1,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(regrown)
+    }
+}
@@ -0,0 +1,20 @@
+@group(0) @binding(1) var<storage, read> transform_texels: array<vec4<f32>>;
+@group(0) @binding(2) var<storage, read> gradient_texels: array<vec4<f32>>;
+@group(0) @binding(5) var<storage, read> image_texels: array<vec4<f32>>;
+@group(0) @binding(6) var<storage, read> attribute_texels: array<vec4<f32>>;
+
+fn load_transform_texel(i: u32) -> vec4<f32> {
+    return transform_texels[i];
+}
+
+fn load_gradient_texel(i: u32) -> vec4<f32> {
+    return gradient_texels[i];
+}
+
+fn load_image_texel(i: u32) -> vec4<f32> {
+    return image_texels[i];
+}
+
+fn load_attribute_texel(i: u32) -> vec4<f32> {
+    return attribute_texels[i];
+}
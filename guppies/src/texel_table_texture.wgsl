@@ -0,0 +1,25 @@
+// Tables are stored row by row, four Rgba32Float texels per transform
+@group(0) @binding(1) var transform_texture: texture_2d<f32>;
+@group(0) @binding(2) var gradient_texture: texture_2d<f32>;
+@group(0) @binding(5) var image_texture: texture_2d<f32>;
+@group(0) @binding(6) var attribute_texture: texture_2d<f32>;
+
+fn load_transform_texel(i: u32) -> vec4<f32> {
+    let width = textureDimensions(transform_texture).x;
+    return textureLoad(transform_texture, vec2<u32>(i % width, i / width), 0);
+}
+
+fn load_gradient_texel(i: u32) -> vec4<f32> {
+    let width = textureDimensions(gradient_texture).x;
+    return textureLoad(gradient_texture, vec2<u32>(i % width, i / width), 0);
+}
+
+fn load_image_texel(i: u32) -> vec4<f32> {
+    let width = textureDimensions(image_texture).x;
+    return textureLoad(image_texture, vec2<u32>(i % width, i / width), 0);
+}
+
+fn load_attribute_texel(i: u32) -> vec4<f32> {
+    let width = textureDimensions(attribute_texture).x;
+    return textureLoad(attribute_texture, vec2<u32>(i % width, i / width), 0);
+}
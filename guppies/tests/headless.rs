@@ -0,0 +1,118 @@
+use guppies::{
+    bytemuck,
+    glam::Mat4,
+    headless::Headless,
+    primitives::{BlendMode, Composite, DrawCommand, TransformAttributes, Triangles, Vertex},
+    winit::dpi::PhysicalSize,
+    GpuRedraw,
+};
+
+const WIDTH: u32 = 4;
+const RED: [f32; 4] = [1., 0., 0., 1.];
+const BLUE: [f32; 4] = [0., 0., 1., 1.];
+const WHITE: [f32; 4] = [1.; 4];
+
+/// Two triangles from `left` to `right` over the whole height, in clip space.
+fn rect(left: f32, right: f32, color: [f32; 4], transform_id: u32) -> Triangles {
+    let vertex = |x, y| Vertex {
+        position: [x, y, 0.],
+        transform_id,
+        color,
+        ..Default::default()
+    };
+    Triangles {
+        vertices: vec![
+            vertex(left, -1.),
+            vertex(right, -1.),
+            vertex(right, 1.),
+            vertex(left, 1.),
+        ],
+        indices: vec![0, 1, 2, 0, 2, 3],
+    }
+}
+
+/// The rects one after another, so the indices of the nth start at 6 * n.
+fn rects(rects: Vec<Triangles>) -> GpuRedraw {
+    let mut triangles = Triangles::default();
+    for rect in rects {
+        let offset = triangles.vertices.len() as u32;
+        triangles
+            .indices
+            .extend(rect.indices.iter().map(|index| index + offset));
+        triangles.vertices.extend(rect.vertices);
+    }
+    let mut gpu_redraw = GpuRedraw::default();
+    // The global transform, then one per transform id
+    gpu_redraw.update_texture(bytemuck::cast_slice(&[Mat4::IDENTITY; 3]).to_vec());
+    gpu_redraw.update_triangles(triangles, 0);
+    gpu_redraw
+}
+
+/// The top row of pixels, as RGBA8.
+fn render(gpu_redraw: GpuRedraw) -> Vec<[u8; 4]> {
+    let mut headless = Headless::<1>::new(PhysicalSize::new(WIDTH, WIDTH));
+    let pixels = headless.render(&mut [gpu_redraw]).unwrap();
+    pixels
+        .chunks_exact(4)
+        .take(WIDTH as usize)
+        .map(|pixel| pixel.try_into().unwrap())
+        .collect()
+}
+
+#[test]
+fn draws_over_the_clear_colour() {
+    let pixels = render(rects(vec![rect(-1., 0., RED, 1)]));
+    assert_eq!(
+        pixels,
+        [[255, 0, 0, 255], [255, 0, 0, 255], [255; 4], [255; 4]]
+    );
+}
+
+#[test]
+fn clips_to_the_clip_shape() {
+    let mut gpu_redraw = rects(vec![rect(-1., 0., WHITE, 1), rect(-1., 1., RED, 1)]);
+    gpu_redraw.update_draw_commands(vec![
+        DrawCommand::PushClip(0..6),
+        DrawCommand::Draw(6..12),
+        DrawCommand::PopClip(0..6),
+    ]);
+    assert_eq!(
+        render(gpu_redraw),
+        [[255, 0, 0, 255], [255, 0, 0, 255], [255; 4], [255; 4]]
+    );
+}
+
+#[test]
+fn composites_layers_with_their_opacity() {
+    let mut gpu_redraw = rects(vec![rect(-1., 1., BLUE, 1), rect(-1., 0., RED, 1)]);
+    gpu_redraw.update_draw_commands(vec![
+        DrawCommand::Draw(0..6),
+        DrawCommand::PushLayer,
+        DrawCommand::Draw(6..12),
+        DrawCommand::PopLayer(Composite {
+            opacity: 0.5,
+            blend_mode: BlendMode::Normal,
+        }),
+    ]);
+    let pixels = render(gpu_redraw);
+    // Half red over blue, mixed in linear light
+    let [red, green, blue, alpha] = pixels[0];
+    assert!(red.abs_diff(188) <= 1 && blue.abs_diff(188) <= 1);
+    assert_eq!([green, alpha], [0, 255]);
+    assert_eq!(pixels[3], [0, 0, 255, 255]);
+}
+
+#[test]
+fn tints_and_hides_by_transform_id() {
+    let mut gpu_redraw = rects(vec![rect(-1., 0., WHITE, 1), rect(0., 1., BLUE, 2)]);
+    let attributes = [
+        TransformAttributes::default(),
+        TransformAttributes::default().with_tint(RED),
+        TransformAttributes::default().with_visible(false),
+    ];
+    gpu_redraw.update_attributes(bytemuck::cast_slice(&attributes).to_vec());
+    assert_eq!(
+        render(gpu_redraw),
+        [[255, 0, 0, 255], [255, 0, 0, 255], [255; 4], [255; 4]]
+    );
+}
@@ -0,0 +1,235 @@
+use crate::{paint::find_defs, prepare_triangles_from_path::prepare_triangles_from_path};
+use guppies::primitives::Triangles;
+use lyon::tessellation::TessellationError;
+use std::rc::Rc;
+use usvg::{
+    ClipPath, Color, Fill, FillRule, Group, Mask, Node, NodeExt, NodeKind, Opacity, Paint, Path,
+    PathBbox, PathData, Stroke, Transform, Units,
+};
+
+// Mask content below this luminance times alpha is left out of the stencil
+const MASK_THRESHOLD: f64 = 0.5;
+
+/// Stencil shapes restricting the content of `node`, in the order they are pushed:
+/// its clip path and the clip paths clipping that one, then its mask and the masks
+/// masking that one.
+/// The stencil is binary, so masks are approximated by the mask region intersected
+/// with the mask content whose luminance times alpha is at least `MASK_THRESHOLD`.
+pub fn clip_shapes(
+    node: &Node,
+    group: &Group,
+    transform_id: u32,
+    tolerance: f32,
+) -> Result<Vec<Triangles>, TessellationError> {
+    let defs = match find_defs(node) {
+        Some(defs) => defs,
+        None => return Ok(vec![]),
+    };
+    let find = |id: &str| defs.children().find(|node| &*node.id() == id);
+    let bbox = content_bbox(node);
+    let mut shapes = vec![];
+
+    let mut clip_path = group.clip_path.clone();
+    while let Some(clip_node) = clip_path.as_deref().and_then(find) {
+        clip_path = match *clip_node.borrow() {
+            NodeKind::ClipPath(ref clip) => {
+                shapes.push(clip_path_shape(
+                    &clip_node,
+                    clip,
+                    bbox,
+                    transform_id,
+                    tolerance,
+                )?);
+                clip.clip_path.clone()
+            }
+            _ => None,
+        };
+    }
+
+    let mut mask = group.mask.clone();
+    while let Some(mask_node) = mask.as_deref().and_then(find) {
+        mask = match *mask_node.borrow() {
+            NodeKind::Mask(ref mask) => {
+                shapes.extend(mask_shapes(
+                    &mask_node,
+                    mask,
+                    bbox,
+                    transform_id,
+                    tolerance,
+                )?);
+                mask.mask.clone()
+            }
+            _ => None,
+        };
+    }
+    Ok(shapes)
+}
+
+/// Bounding box of the paths and images below `node`, in the space they are drawn in.
+fn content_bbox(node: &Node) -> Option<PathBbox> {
+    node.descendants()
+        .filter_map(|node| match *node.borrow() {
+            NodeKind::Path(ref path) => path.data.bbox_with_transform(path.transform, None),
+            NodeKind::Image(ref image) => {
+                PathData::from_rect(image.view_box.rect).bbox_with_transform(image.transform, None)
+            }
+            _ => None,
+        })
+        .reduce(|acc, bbox| acc.expand(bbox))
+}
+
+/// Maps `units` to user space. None when the content has no bounding box
+/// for objectBoundingBox units to refer to, in which case nothing is shown.
+fn units_transform(units: Units, bbox: Option<PathBbox>) -> Option<Transform> {
+    match units {
+        Units::UserSpaceOnUse => Some(Transform::default()),
+        Units::ObjectBoundingBox => {
+            let bbox = bbox?;
+            Some(Transform::new(
+                bbox.width(),
+                0.,
+                0.,
+                bbox.height(),
+                bbox.x(),
+                bbox.y(),
+            ))
+        }
+    }
+}
+
+fn clip_path_shape(
+    node: &Node,
+    clip: &ClipPath,
+    bbox: Option<PathBbox>,
+    transform_id: u32,
+    tolerance: f32,
+) -> Result<Triangles, TessellationError> {
+    let mut to_user_space = match units_transform(clip.units, bbox) {
+        Some(to_user_space) => to_user_space,
+        None => return Ok(Triangles::default()),
+    };
+    to_user_space.prepend(&clip.transform);
+    // Only the fill area clips, usvg stores clip-rule as the fill rule
+    shape(node, transform_id, tolerance, |path| {
+        let rule = path.fill.as_ref().map(|fill| fill.rule).unwrap_or_default();
+        stencil_path(path, to_user_space, Some(rule), None)
+    })
+}
+
+fn mask_shapes(
+    node: &Node,
+    mask: &Mask,
+    bbox: Option<PathBbox>,
+    transform_id: u32,
+    tolerance: f32,
+) -> Result<[Triangles; 2], TessellationError> {
+    let region = match units_transform(mask.units, bbox) {
+        Some(to_user_space) => {
+            let path = Path {
+                data: Rc::new(PathData::from_rect(mask.rect)),
+                ..Path::default()
+            };
+            let path = stencil_path(&path, to_user_space, Some(FillRule::NonZero), None);
+            prepare_triangles_from_path(&path, transform_id, None, &mut vec![], tolerance)?
+        }
+        None => Triangles::default(),
+    };
+    let defs = find_defs(node);
+    let visible = |paint: &Paint, opacity: Opacity| {
+        coverage(paint, defs.as_ref()) * opacity.value() >= MASK_THRESHOLD
+    };
+    let content = match units_transform(mask.content_units, bbox) {
+        Some(to_user_space) => shape(node, transform_id, tolerance, |path| {
+            let fill = path
+                .fill
+                .as_ref()
+                .filter(|fill| visible(&fill.paint, fill.opacity));
+            let stroke = path
+                .stroke
+                .as_ref()
+                .filter(|stroke| visible(&stroke.paint, stroke.opacity));
+            stencil_path(path, to_user_space, fill.map(|fill| fill.rule), stroke)
+        })?,
+        None => Triangles::default(),
+    };
+    Ok([region, content])
+}
+
+/// Luminance of the paint, or of its brightest stop for gradients.
+/// Patterns count as fully opaque white.
+fn coverage(paint: &Paint, defs: Option<&Node>) -> f64 {
+    let luminance = |c: &Color| {
+        (0.2125 * c.red as f64 + 0.7154 * c.green as f64 + 0.0721 * c.blue as f64) / u8::MAX as f64
+    };
+    let id = match paint {
+        Paint::Color(c) => return luminance(c),
+        Paint::Link(id) => id,
+    };
+    let server = match defs.and_then(|defs| defs.children().find(|node| &*node.id() == id)) {
+        Some(server) => server,
+        None => return 1.,
+    };
+    let stops = match *server.borrow() {
+        NodeKind::LinearGradient(ref lg) => lg.base.stops.clone(),
+        NodeKind::RadialGradient(ref rg) => rg.base.stops.clone(),
+        _ => return 1.,
+    };
+    stops
+        .iter()
+        .map(|stop| luminance(&stop.color) * stop.opacity.value())
+        .fold(0., f64::max)
+}
+
+/// Tessellates the paths below `node`, each turned into a stencil path by `stencil`,
+/// into one shape.
+fn shape(
+    node: &Node,
+    transform_id: u32,
+    tolerance: f32,
+    stencil: impl Fn(&Path) -> Path,
+) -> Result<Triangles, TessellationError> {
+    node.descendants()
+        .filter_map(|node| match *node.borrow() {
+            NodeKind::Path(ref path) => Some(prepare_triangles_from_path(
+                &stencil(path),
+                transform_id,
+                None,
+                &mut vec![],
+                tolerance,
+            )),
+            _ => None,
+        })
+        .try_fold(Triangles::default(), |mut acc, triangles| {
+            let triangles = triangles?;
+            let offset = acc.vertices.len() as u32;
+            acc.vertices.extend(triangles.vertices);
+            acc.indices
+                .extend(triangles.indices.iter().map(|i| i + offset));
+            Ok(acc)
+        })
+}
+
+/// `path` moved into user space and drawn in a plain colour, since only its coverage
+/// ends up in the stencil. `fill_rule` None leaves out the fill, `stroke` None the stroke.
+fn stencil_path(
+    path: &Path,
+    to_user_space: Transform,
+    fill_rule: Option<FillRule>,
+    stroke: Option<&Stroke>,
+) -> Path {
+    let mut transform = to_user_space;
+    transform.append(&path.transform);
+    Path {
+        transform,
+        fill: fill_rule.map(|rule| Fill {
+            rule,
+            ..Fill::default()
+        }),
+        stroke: stroke.map(|stroke| Stroke {
+            paint: Paint::Color(Color::black()),
+            opacity: Opacity::default(),
+            ..stroke.clone()
+        }),
+        ..path.clone()
+    }
+}
@@ -0,0 +1,66 @@
+use lyon::tessellation::TessellationError;
+use std::fmt::Display;
+
+/// Errors of the fallible `try_` counterparts in salvage.
+#[derive(Debug)]
+pub enum Error {
+    /// The SVG isn't well-formed XML.
+    Xml(roxmltree::Error),
+    /// usvg couldn't make a tree out of the SVG.
+    Svg(usvg::Error),
+    /// No element has the id, or nothing was drawn for it.
+    UnknownId(String),
+    /// The element with the id has no `<text>`.
+    NotText(String),
+    /// The element with the id isn't a `<path>`.
+    NotPath(String),
+    /// lyon couldn't tessellate a path.
+    Tessellation(TessellationError),
+    /// The glyphs of the texts drawn from a glyph atlas don't fit into it.
+    GlyphAtlasFull,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Xml(e) => write!(f, "failed to parse the SVG: {}", e),
+            Error::Svg(e) => write!(f, "failed to convert the SVG: {}", e),
+            Error::UnknownId(id) => write!(f, "no element has the id {:?}", id),
+            Error::NotText(id) => write!(f, "the element {:?} has no text", id),
+            Error::NotPath(id) => write!(f, "the element {:?} isn't a path", id),
+            Error::Tessellation(e) => write!(f, "failed to tessellate a path: {}", e),
+            Error::GlyphAtlasFull => write!(f, "the glyphs don't fit into the glyph atlas"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Xml(e) => Some(e),
+            Error::Svg(e) => Some(e),
+            Error::UnknownId(_) | Error::NotText(_) | Error::NotPath(_) | Error::GlyphAtlasFull => {
+                None
+            }
+            Error::Tessellation(e) => Some(e),
+        }
+    }
+}
+
+impl From<roxmltree::Error> for Error {
+    fn from(e: roxmltree::Error) -> Self {
+        Error::Xml(e)
+    }
+}
+
+impl From<usvg::Error> for Error {
+    fn from(e: usvg::Error) -> Self {
+        Error::Svg(e)
+    }
+}
+
+impl From<TessellationError> for Error {
+    fn from(e: TessellationError) -> Self {
+        Error::Tessellation(e)
+    }
+}
@@ -1,33 +1,36 @@
-use crate::convert_path::convert_path;
-use guppies::{
-    glam::Vec4,
-    primitives::{Index, Vertex},
-};
+use crate::{convert_path::convert_path, paint::VertexPaint};
+use guppies::primitives::{Index, Vertex};
 use lyon::lyon_tessellation::{
-    BuffersBuilder, FillOptions, FillTessellator, FillVertex, VertexBuffers,
+    BuffersBuilder, FillOptions, FillRule, FillTessellator, FillVertex, TessellationError,
+    VertexBuffers,
 };
 use usvg::{self, Path};
 
+pub fn convert_fill(f: &usvg::Fill, tolerance: f32) -> FillOptions {
+    let fill_rule = match f.rule {
+        usvg::FillRule::NonZero => FillRule::NonZero,
+        usvg::FillRule::EvenOdd => FillRule::EvenOdd,
+    };
+
+    FillOptions::tolerance(tolerance).with_fill_rule(fill_rule)
+}
+
 pub fn iterate_fill(
+    f: &usvg::Fill,
     path: &Path,
-    color: &Vec4,
+    paint: &VertexPaint,
     geometry: &mut VertexBuffers<Vertex, Index>,
     id: u32,
-) {
+    tolerance: f32,
+) -> Result<(), TessellationError> {
     let mut fill_tess = FillTessellator::new();
-    fill_tess
-        .tessellate(
-            convert_path(path),
-            &FillOptions::tolerance(0.01),
-            &mut BuffersBuilder::new(geometry, |v: FillVertex| {
-                let position = v.position();
-                let (x, y) = path.transform.apply(position.x as f64, position.y as f64);
-                Vertex {
-                    position: [x as f32, y as f32, 0.],
-                    color: color.to_array(),
-                    transform_id: id,
-                }
-            }),
-        )
-        .expect("Error during tesselation!");
+    fill_tess.tessellate(
+        convert_path(path),
+        &convert_fill(f, tolerance),
+        &mut BuffersBuilder::new(geometry, |v: FillVertex| {
+            let position = v.position();
+            let (x, y) = path.transform.apply(position.x as f64, position.y as f64);
+            paint.vertex(x, y, id)
+        }),
+    )
 }
@@ -0,0 +1,148 @@
+use roxmltree::Document;
+use std::{collections::HashMap, path::Path, sync::Arc};
+use usvg::{fontdb::Source, Options};
+
+const DEFAULT_FONT: &[u8] = include_bytes!("../fallback_font/Roboto-Medium.ttf");
+const DEFAULT_FAMILY: &str = "Roboto Medium";
+
+/// Font families of a `font-family` list, without their quotes.
+pub(crate) fn parse_families(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|family| family.trim().trim_matches(['\'', '"']).to_string())
+        .filter(|family| !family.is_empty())
+        .collect()
+}
+
+pub(crate) fn escape(family: &str) -> String {
+    family
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+/// The fonts which `SvgSet`s set their texts in.
+/// Starts out with the embedded Roboto Medium, which is also the default family.
+///
+/// Glyphs missing from the font of a text, e.g. CJK or emoji, are taken from the
+/// first font loaded which has them, so loading such fonts is enough for them to show.
+#[derive(Debug)]
+pub struct Fonts {
+    options: Options,
+    // Families used in order when a text's own family isn't loaded
+    fallbacks: HashMap<String, Vec<String>>,
+}
+
+impl Default for Fonts {
+    fn default() -> Self {
+        let mut options = Options::default();
+        options
+            .fontdb
+            .load_font_source(Source::Binary(Arc::new(DEFAULT_FONT)));
+        options.font_family = DEFAULT_FAMILY.to_string();
+        options.keep_named_groups = true;
+        Self {
+            options,
+            fallbacks: HashMap::new(),
+        }
+    }
+}
+
+impl Fonts {
+    /// Loads the faces of a font file, or all of a collection.
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.options.fontdb.load_font_file(path)
+    }
+    /// Loads the faces of a font, e.g. one embedded with `include_bytes!`.
+    pub fn load_data(&mut self, data: Vec<u8>) {
+        self.options.fontdb.load_font_data(data)
+    }
+    /// Loads the fonts installed on the system, e.g. the Noto CJK and emoji fonts of
+    /// Linux distributions. Finds none on platforms fontdb doesn't know, e.g. Android.
+    pub fn load_system_fonts(&mut self) {
+        self.options.fontdb.load_system_fonts()
+    }
+    /// Family of texts which don't set one, or whose families aren't loaded.
+    pub fn set_default_family(&mut self, family: &str) {
+        self.options.font_family = family.to_string();
+    }
+    /// Sets texts in the first loaded of `fallbacks` when `family` isn't loaded.
+    pub fn set_fallbacks(&mut self, family: &str, fallbacks: &[&str]) {
+        self.fallbacks.insert(
+            family.to_string(),
+            fallbacks.iter().map(|family| family.to_string()).collect(),
+        );
+    }
+    pub fn usvg_options(&self) -> &Options {
+        &self.options
+    }
+    pub fn into_usvg_options(self) -> Options {
+        self.options
+    }
+    /// `families` followed by the fallbacks of each which aren't in the list yet.
+    pub fn with_fallbacks(&self, families: &[String]) -> Vec<String> {
+        let mut with_fallbacks = families.to_vec();
+        for fallback in families
+            .iter()
+            .flat_map(|family| self.fallbacks.get(family))
+        {
+            for family in fallback {
+                if !with_fallbacks.contains(family) {
+                    with_fallbacks.push(family.clone());
+                }
+            }
+        }
+        with_fallbacks
+    }
+    /// Fallbacks to append to the `font-family` list `value`, if any.
+    fn fallback_suffix(&self, value: &str) -> Option<String> {
+        let families = parse_families(value);
+        let fallbacks = &self.with_fallbacks(&families)[families.len()..];
+        (!fallbacks.is_empty()).then(|| {
+            fallbacks
+                .iter()
+                .map(|family| format!(", {}", escape(family)))
+                .collect()
+        })
+    }
+    /// `xml` with the fallbacks of the families in its `font-family` attributes and
+    /// `style` declarations appended to them, as usvg only takes the families in the SVG.
+    pub fn apply_fallbacks(&self, xml: &str) -> Result<String, roxmltree::Error> {
+        if self.fallbacks.is_empty() {
+            return Ok(xml.to_string());
+        }
+        let document = Document::parse(xml)?;
+        let mut insertions: Vec<(usize, String)> = vec![];
+        for a in document.descendants().flat_map(|node| node.attributes()) {
+            let range = a.value_range();
+            match a.name() {
+                "font-family" => {
+                    if let Some(suffix) = self.fallback_suffix(&xml[range.clone()]) {
+                        insertions.push((range.end, suffix));
+                    }
+                }
+                "style" => {
+                    let mut start = range.start;
+                    for declaration in xml[range].split(';') {
+                        if let Some((key, value)) = declaration.split_once(':') {
+                            if key.trim() == "font-family" {
+                                if let Some(suffix) = self.fallback_suffix(value) {
+                                    insertions.push((start + declaration.trim_end().len(), suffix));
+                                }
+                            }
+                        }
+                        start += declaration.len() + 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut xml = xml.to_string();
+        // From the end, so the positions before stay where they were
+        for (position, suffix) in insertions.iter().rev() {
+            xml.insert_str(*position, suffix);
+        }
+        Ok(xml)
+    }
+}
@@ -1,43 +1,285 @@
-use crate::prepare_triangles_from_path::prepare_triangles_from_path;
-use guppies::primitives::{Indices, Triangles};
-use usvg::{Path, Tree};
+use crate::{
+    clip::clip_shapes,
+    error::Error,
+    layer::layer_composite,
+    paint::{find_defs, is_defs},
+    prepare_triangles_from_image::prepare_triangles_from_image,
+    prepare_triangles_from_path::prepare_triangles_from_path,
+};
+use guppies::{
+    glam::Vec4,
+    primitives::{BlendMode, Composite, DrawCommand, Indices, RasterImage, Triangles},
+};
+use std::{collections::HashMap, sync::Arc};
+use usvg::{Group, Image, Node, NodeExt, Path, Tree};
+
+/// How far tessellated curves may stray from the real ones, in SVG units,
+/// unless a level of detail asks for another tolerance.
+pub const DEFAULT_TOLERANCE: f32 = 0.01;
 
 #[derive(Clone, Debug, Default)]
 pub struct Geometry {
     pub triangles: Triangles,
+    // Gradient table referenced by `Vertex::gradient_id`, pass it to `GpuRedraw::update_gradients`
+    pub gradients: Vec<Vec4>,
+    // Image table referenced by `Vertex::image_id`, pass it to `GpuRedraw::update_images`
+    pub images: Vec<RasterImage>,
+    // Pass it to `GpuRedraw::update_draw_commands`, empty draws every triangle
+    pub draw_commands: Vec<DrawCommand>,
     pub id: String,
+    // The transform id when it's what `from_node` builds for the node with the id, which
+    // `SvgSet` can then build again for other levels of detail. Cleared by `extend`
+    pub node_transform_id: Option<u32>,
 }
 impl Geometry {
+    /// Panics when a path can't be tessellated, see `from_subtree`.
+    /// Blend modes are lost along with the markup, so every element blends normally.
     pub fn from_tree(tree: Tree, transform_id: u32) -> Self {
-        let geometry = tree
-            .root()
-            .descendants()
-            .into_iter()
-            .filter_map(|node| {
-                if let usvg::NodeKind::Path(ref p) = *node.borrow() {
-                    Some(Geometry::new(p, transform_id))
-                } else {
-                    None
-                }
-            })
-            .fold(Geometry::default(), |acc, curr| acc.extend(&curr));
-        geometry
+        Self::from_subtree(
+            &tree.root(),
+            transform_id,
+            DEFAULT_TOLERANCE,
+            &HashMap::new(),
+        )
+        .expect("Error during tessellation!")
+    }
+    /// `node` and everything below it, in document order.
+    /// `blend_modes` are those of the markup the tree was parsed from, see `blend_modes`.
+    pub fn from_subtree(
+        node: &Node,
+        transform_id: u32,
+        tolerance: f32,
+        blend_modes: &HashMap<String, BlendMode>,
+    ) -> Result<Self, Error> {
+        let geometry = Geometry::from_node_with_tolerance(node, transform_id, tolerance)?;
+        let clip_pop = geometry.as_ref().and_then(Geometry::clip_pop);
+        let geometry = match is_defs(node) {
+            true => geometry.unwrap_or_default(),
+            false => node
+                .children()
+                .try_fold(geometry.unwrap_or_default(), |acc, child| {
+                    Ok::<_, Error>(acc.extend(&Self::from_subtree(
+                        &child,
+                        transform_id,
+                        tolerance,
+                        blend_modes,
+                    )?))
+                })?,
+        };
+        let geometry = match clip_pop {
+            Some(clip_pop) => geometry.extend(&clip_pop),
+            None => geometry,
+        };
+        let blend_mode = blend_modes.get(&*node.id()).copied().unwrap_or_default();
+        Ok(match layer_composite(node, blend_mode) {
+            Some(composite) if !geometry.triangles.indices.is_empty() => Geometry::push_layer()
+                .extend(&geometry)
+                .extend(&Geometry::pop_layer(composite)),
+            _ => geometry,
+        })
+    }
+    /// Returns None for nodes which don't draw anything themselves, e.g. groups without
+    /// clip path or mask.
+    /// A clipped group returns its clip shapes, which must be followed by the group's
+    /// content and then by `clip_pop`.
+    /// Panics when the path can't be tessellated, see `try_from_node`.
+    pub fn from_node(node: &Node, transform_id: u32) -> Option<Self> {
+        Self::try_from_node(node, transform_id).expect("Error during tessellation!")
+    }
+    /// `from_node` which fails instead of panicking when the path can't be tessellated.
+    pub fn try_from_node(node: &Node, transform_id: u32) -> Result<Option<Self>, Error> {
+        Self::from_node_with_tolerance(node, transform_id, DEFAULT_TOLERANCE)
+    }
+    /// `try_from_node` with curves flattened to within `tolerance`.
+    pub fn from_node_with_tolerance(
+        node: &Node,
+        transform_id: u32,
+        tolerance: f32,
+    ) -> Result<Option<Self>, Error> {
+        Ok(match *node.borrow() {
+            usvg::NodeKind::Path(ref p) => Some(Geometry::new(
+                p,
+                transform_id,
+                find_defs(node).as_ref(),
+                tolerance,
+            )?),
+            usvg::NodeKind::Image(ref image) => Geometry::from_image(image, transform_id),
+            usvg::NodeKind::Group(ref group) => {
+                Geometry::from_clip(node, group, transform_id, tolerance)?
+            }
+            _ => None,
+        })
+    }
+    fn commands_or_draw_all(&self) -> Vec<DrawCommand> {
+        match self.draw_commands.is_empty() {
+            true if self.triangles.indices.is_empty() => vec![],
+            true => vec![DrawCommand::Draw(0..self.triangles.indices.len() as u32)],
+            false => self.draw_commands.clone(),
+        }
+    }
+    /// Draws what follows into a layer of its own until `pop_layer`.
+    pub fn push_layer() -> Self {
+        Self {
+            draw_commands: vec![DrawCommand::PushLayer],
+            ..Default::default()
+        }
+    }
+    /// Composites the layer opened by the matching `push_layer`.
+    pub fn pop_layer(composite: Composite) -> Self {
+        Self {
+            draw_commands: vec![DrawCommand::PopLayer(composite)],
+            ..Default::default()
+        }
     }
     pub fn extend(mut self, other: &Self) -> Self {
+        self.node_transform_id = None;
+        if !self.draw_commands.is_empty() || !other.draw_commands.is_empty() {
+            let i_len = self.triangles.indices.len() as u32;
+            let mut draw_commands = self.commands_or_draw_all();
+            for command in other.commands_or_draw_all() {
+                let command = match command {
+                    DrawCommand::Draw(r) => DrawCommand::Draw(r.start + i_len..r.end + i_len),
+                    DrawCommand::PushClip(r) => {
+                        DrawCommand::PushClip(r.start + i_len..r.end + i_len)
+                    }
+                    DrawCommand::PopClip(r) => DrawCommand::PopClip(r.start + i_len..r.end + i_len),
+                    layer => layer,
+                };
+                // Consecutive draws are merged so unclipped content stays one draw call
+                match (draw_commands.last_mut(), command) {
+                    (_, DrawCommand::Draw(r)) if r.is_empty() => {}
+                    (Some(DrawCommand::Draw(last)), DrawCommand::Draw(r))
+                        if last.end == r.start =>
+                    {
+                        last.end = r.end
+                    }
+                    (_, command) => draw_commands.push(command),
+                }
+            }
+            self.draw_commands = draw_commands;
+        }
         let v_len = self.triangles.vertices.len() as u32;
         let other_indices_with_offset: Indices =
             other.triangles.indices.iter().map(|i| i + v_len).collect();
+        let gradients_len = self.gradients.len() as u32;
+        // Images both sides share, e.g. a glyph atlas, are only kept once
+        let image_ids: Vec<u32> = other
+            .images
+            .iter()
+            .map(|image| {
+                let shared = self.images.iter().position(|own| {
+                    Arc::ptr_eq(&own.rgba, &image.rgba) && own.width == image.width
+                });
+                match shared {
+                    Some(index) => index as u32 + 1,
+                    None => {
+                        self.images.push(image.clone());
+                        self.images.len() as u32
+                    }
+                }
+            })
+            .collect();
         self.triangles
             .vertices
-            .extend(other.triangles.vertices.iter());
+            .extend(other.triangles.vertices.iter().map(|v| {
+                let mut v = *v;
+                if v.gradient_id != 0 {
+                    v.gradient_id += gradients_len;
+                }
+                if v.image_id != 0 {
+                    v.image_id = image_ids[v.image_id as usize - 1];
+                }
+                v
+            }));
         self.triangles.indices.extend(other_indices_with_offset);
+        self.gradients.extend(other.gradients.iter());
         self
     }
-    pub fn new(p: &Path, transform_id: u32) -> Self {
-        let triangles = prepare_triangles_from_path(p, transform_id);
-        Self {
+    /// `defs` is the tree's defs node, which gradient paints are looked up in.
+    pub fn new(
+        p: &Path,
+        transform_id: u32,
+        defs: Option<&Node>,
+        tolerance: f32,
+    ) -> Result<Self, Error> {
+        let mut gradients = vec![];
+        let triangles =
+            prepare_triangles_from_path(p, transform_id, defs, &mut gradients, tolerance)?;
+        Ok(Self {
             triangles,
+            gradients,
+            images: vec![],
+            draw_commands: vec![],
             id: p.id.to_owned(),
+            node_transform_id: Some(transform_id),
+        })
+    }
+    /// Returns None when the group is neither clipped nor masked.
+    pub fn from_clip(
+        node: &Node,
+        group: &Group,
+        transform_id: u32,
+        tolerance: f32,
+    ) -> Result<Option<Self>, Error> {
+        if group.clip_path.is_none() && group.mask.is_none() {
+            return Ok(None);
         }
+        let geometry = clip_shapes(node, group, transform_id, tolerance)?
+            .into_iter()
+            .fold(
+                Geometry {
+                    id: group.id.to_owned(),
+                    node_transform_id: Some(transform_id),
+                    ..Default::default()
+                },
+                |mut acc, shape| {
+                    let (v_len, i_len) = (
+                        acc.triangles.vertices.len() as u32,
+                        acc.triangles.indices.len() as u32,
+                    );
+                    acc.draw_commands.push(DrawCommand::PushClip(
+                        i_len..i_len + shape.indices.len() as u32,
+                    ));
+                    acc.triangles.vertices.extend(shape.vertices);
+                    acc.triangles
+                        .indices
+                        .extend(shape.indices.iter().map(|i| i + v_len));
+                    acc
+                },
+            );
+        Ok(Some(geometry))
+    }
+    /// The geometry undoing the clips pushed by this one, which goes after the clipped content.
+    /// Returns None when nothing is pushed.
+    pub fn clip_pop(&self) -> Option<Self> {
+        let draw_commands: Vec<DrawCommand> = self
+            .draw_commands
+            .iter()
+            .rev()
+            .filter_map(|command| match command {
+                DrawCommand::PushClip(r) => Some(DrawCommand::PopClip(r.clone())),
+                _ => None,
+            })
+            .collect();
+        if draw_commands.is_empty() {
+            return None;
+        }
+        Some(Self {
+            triangles: self.triangles.clone(),
+            draw_commands,
+            ..Default::default()
+        })
+    }
+    /// Returns None when the image is hidden or can't be decoded.
+    pub fn from_image(image: &Image, transform_id: u32) -> Option<Self> {
+        let (triangles, raster_image) = prepare_triangles_from_image(image, transform_id)?;
+        Some(Self {
+            triangles,
+            gradients: vec![],
+            images: vec![raster_image],
+            draw_commands: vec![],
+            id: image.id.to_owned(),
+            node_transform_id: Some(transform_id),
+        })
     }
 }
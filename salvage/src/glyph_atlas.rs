@@ -0,0 +1,354 @@
+use crate::measure::{find_face, find_fallback_face, TextStyle};
+use ab_glyph_rasterizer::{point, Point, Rasterizer};
+use guppies::primitives::{Indices, RasterImage, Triangles, Vertex};
+use std::{collections::HashMap, sync::Arc};
+use ttf_parser::{Face, GlyphId, OutlineBuilder};
+use usvg::{fontdb::ID, Options, Transform};
+
+// Width and height of each image of the atlas, so adding a glyph only copies one of them
+const PAGE_SIZE: u32 = 512;
+// Glyphs of every size visited stay in the atlas, until it would need more pages than this
+const MAX_PAGES: usize = 64;
+// Larger glyphs are magnified from this size instead of filling up the atlas
+const MAX_PIXELS_PER_EM: f32 = 256.;
+// Empty pixels between glyphs, so bilinear filtering doesn't pick up the neighbours
+const PADDING: u32 = 1;
+
+/// A glyph of a font rasterized at a size in pixels per em.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    face: ID,
+    glyph: u16,
+    pixels_per_em: u32,
+}
+
+/// Where a glyph is in the atlas, and where its bitmap goes relative to the pen.
+#[derive(Clone, Copy, Debug, Default)]
+struct AtlasGlyph {
+    page: usize,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    // Pixels from the pen to the left edge, and from the baseline up to the top edge
+    left: f32,
+    top: f32,
+}
+
+/// The font and glyph a character is drawn with, and its advance in ems.
+#[derive(Clone, Copy, Debug)]
+struct CharGlyph {
+    face: ID,
+    glyph: GlyphId,
+    advance: f32,
+}
+
+/// Draws the outline of a glyph into a rasterizer, flipping it to grow downwards.
+struct GlyphRasterizer {
+    rasterizer: Rasterizer,
+    scale: f32,
+    left: f32,
+    top: f32,
+    start: Point,
+    last: Point,
+}
+
+impl GlyphRasterizer {
+    fn point(&self, x: f32, y: f32) -> Point {
+        point(x * self.scale - self.left, self.top - y * self.scale)
+    }
+}
+
+impl OutlineBuilder for GlyphRasterizer {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.start = self.point(x, y);
+        self.last = self.start;
+    }
+    fn line_to(&mut self, x: f32, y: f32) {
+        let p = self.point(x, y);
+        self.rasterizer.draw_line(self.last, p);
+        self.last = p;
+    }
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (p1, p) = (self.point(x1, y1), self.point(x, y));
+        self.rasterizer.draw_quad(self.last, p1, p);
+        self.last = p;
+    }
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (p1, p2, p) = (self.point(x1, y1), self.point(x2, y2), self.point(x, y));
+        self.rasterizer.draw_cubic(self.last, p1, p2, p);
+        self.last = p;
+    }
+    fn close(&mut self) {
+        if self.last != self.start {
+            self.rasterizer.draw_line(self.last, self.start);
+        }
+        self.last = self.start;
+    }
+}
+
+/// Coverage of `glyph` at `pixels_per_em`, with the offsets of `AtlasGlyph`.
+/// None for glyphs without an outline, e.g. spaces.
+fn rasterize(face: &Face, glyph: GlyphId, pixels_per_em: u32) -> Option<(AtlasGlyph, Vec<u8>)> {
+    let bbox = face.glyph_bounding_box(glyph)?;
+    let scale = pixels_per_em as f32 / face.units_per_em() as f32;
+    let left = (bbox.x_min as f32 * scale).floor();
+    let top = (bbox.y_max as f32 * scale).ceil();
+    let width = ((bbox.x_max as f32 * scale).ceil() - left) as u32;
+    let height = (top - (bbox.y_min as f32 * scale).floor()) as u32;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let mut builder = GlyphRasterizer {
+        rasterizer: Rasterizer::new(width as usize, height as usize),
+        scale,
+        left,
+        top,
+        start: point(0., 0.),
+        last: point(0., 0.),
+    };
+    face.outline_glyph(glyph, &mut builder)?;
+    let mut coverage = vec![0; (width * height) as usize];
+    builder.rasterizer.for_each_pixel(|i, alpha| {
+        coverage[i] = (alpha.clamp(0., 1.) * 255.) as u8;
+    });
+    let glyph = AtlasGlyph {
+        width,
+        height,
+        left,
+        top,
+        ..Default::default()
+    };
+    Some((glyph, coverage))
+}
+
+/// How a text drawn from the atlas looks and where it goes.
+#[derive(Clone, Debug)]
+pub struct GlyphText {
+    pub style: TextStyle,
+    // Start of the first line on its baseline, in the text's user units
+    pub x: f32,
+    pub y: f32,
+    pub line_height: f32,
+    // From the text's user units to the SVG's
+    pub transform: Transform,
+    pub color: [f32; 4],
+    pub transform_id: u32,
+    pub visible: bool,
+}
+
+/// Glyphs rasterized once into pages of images, which texts are drawn from as textured
+/// quads, so changing a text doesn't tessellate its outlines again.
+/// The pages are white with the coverage of the glyphs in their alpha, and the quads
+/// tint them with the colour of their text. Glyphs stay where they are when pages
+/// are added, so only the pages which got new glyphs have to be uploaded again.
+#[derive(Clone, Debug)]
+pub struct GlyphAtlas {
+    pages: Vec<RasterImage>,
+    glyphs: HashMap<GlyphKey, Option<AtlasGlyph>>,
+    chars: HashMap<(ID, char), Option<CharGlyph>>,
+    // Where the next glyph goes on the last page, and the height of the shelf it goes on
+    cursor: (u32, u32),
+    shelf_height: u32,
+}
+
+impl Default for GlyphAtlas {
+    fn default() -> Self {
+        Self {
+            pages: vec![blank_image(PAGE_SIZE)],
+            glyphs: HashMap::new(),
+            chars: HashMap::new(),
+            cursor: (PADDING, PADDING),
+            shelf_height: 0,
+        }
+    }
+}
+
+fn blank_image(size: u32) -> RasterImage {
+    RasterImage {
+        width: size,
+        height: size,
+        rgba: Arc::new(
+            [255, 255, 255, 0]
+                .into_iter()
+                .cycle()
+                .take((size * size * 4) as usize)
+                .collect(),
+        ),
+    }
+}
+
+impl GlyphAtlas {
+    /// The image table of texts laid out with the atlas, see `Geometry::images`.
+    pub fn images(&self) -> &[RasterImage] {
+        &self.pages
+    }
+    /// Forgets every glyph, e.g. when the atlas is full of sizes no longer drawn.
+    pub fn clear(&mut self) {
+        *self = Self {
+            chars: std::mem::take(&mut self.chars),
+            ..Default::default()
+        };
+    }
+    fn char_glyph(&mut self, opt: &Options, face: ID, c: char) -> Option<CharGlyph> {
+        let char_glyph = |face: ID| {
+            opt.fontdb
+                .with_face_data(face, |data, index| {
+                    let face_data = Face::from_slice(data, index).ok()?;
+                    let glyph = face_data.glyph_index(c)?;
+                    let advance = face_data.glyph_hor_advance(glyph)? as f32
+                        / face_data.units_per_em() as f32;
+                    Some(CharGlyph {
+                        face,
+                        glyph,
+                        advance,
+                    })
+                })
+                .flatten()
+        };
+        *self
+            .chars
+            .entry((face, c))
+            .or_insert_with(|| char_glyph(face).or_else(|| char_glyph(find_fallback_face(opt, c)?)))
+    }
+    /// Places the glyph in the atlas, rasterizing it the first time.
+    /// Returns Err(()) when the atlas is full.
+    fn glyph(&mut self, opt: &Options, key: GlyphKey) -> Result<Option<AtlasGlyph>, ()> {
+        if let Some(glyph) = self.glyphs.get(&key) {
+            return Ok(*glyph);
+        }
+        let rasterized = opt
+            .fontdb
+            .with_face_data(key.face, |data, index| {
+                let face = Face::from_slice(data, index).ok()?;
+                rasterize(&face, GlyphId(key.glyph), key.pixels_per_em)
+            })
+            .flatten();
+        let glyph = match rasterized {
+            Some((glyph, coverage)) => Some(self.insert(glyph, &coverage)?),
+            None => None,
+        };
+        self.glyphs.insert(key, glyph);
+        Ok(glyph)
+    }
+    /// Copies the coverage of `glyph` onto a shelf, adding a page when it's out of room.
+    /// Only the page it goes on is copied, and only if it was handed out since.
+    fn insert(&mut self, mut glyph: AtlasGlyph, coverage: &[u8]) -> Result<AtlasGlyph, ()> {
+        if glyph.width + PADDING * 2 > PAGE_SIZE || glyph.height + PADDING * 2 > PAGE_SIZE {
+            return Err(());
+        }
+        if self.cursor.0 + glyph.width + PADDING > PAGE_SIZE {
+            self.cursor = (PADDING, self.cursor.1 + self.shelf_height + PADDING);
+            self.shelf_height = 0;
+        }
+        if self.cursor.1 + glyph.height + PADDING > PAGE_SIZE {
+            if self.pages.len() == MAX_PAGES {
+                return Err(());
+            }
+            self.pages.push(blank_image(PAGE_SIZE));
+            (self.cursor, self.shelf_height) = ((PADDING, PADDING), 0);
+        }
+        glyph.page = self.pages.len() - 1;
+        (glyph.x, glyph.y) = self.cursor;
+        let rgba = Arc::make_mut(&mut self.pages[glyph.page].rgba);
+        for row in 0..glyph.height {
+            for column in 0..glyph.width {
+                let pixel = ((glyph.y + row) * PAGE_SIZE + glyph.x + column) as usize;
+                rgba[pixel * 4 + 3] = coverage[(row * glyph.width + column) as usize];
+            }
+        }
+        self.cursor.0 += glyph.width + PADDING;
+        self.shelf_height = self.shelf_height.max(glyph.height);
+        Ok(glyph)
+    }
+    /// Quads drawing `text` as `glyph_text`, sampling the page of each glyph from `images`.
+    /// Lines are aligned by `text-anchor` around `x` when given, or the text's own start.
+    /// `zoom` is the device pixels per SVG unit the glyphs are rasterized for.
+    /// Returns None when the atlas is full, see `clear`.
+    pub fn layout(
+        &mut self,
+        opt: &Options,
+        glyph_text: &GlyphText,
+        text: &str,
+        x: Option<f32>,
+        zoom: f32,
+    ) -> Option<Triangles> {
+        let GlyphText { style, .. } = glyph_text;
+        let face = find_face(opt, style)?;
+        let (a, b, c, d) = (
+            glyph_text.transform.a,
+            glyph_text.transform.b,
+            glyph_text.transform.c,
+            glyph_text.transform.d,
+        );
+        let scale = (a * d - b * c).abs().sqrt() as f32;
+        let pixels_per_em = (style.size * scale * zoom)
+            .round()
+            .clamp(1., MAX_PIXELS_PER_EM) as u32;
+        // From pixels of the rasterized glyphs to the text's user units
+        let unit = style.size / pixels_per_em as f32;
+        let size = PAGE_SIZE as f32;
+        let mut triangles = Triangles::default();
+        for (i, line) in text.split('\n').enumerate() {
+            let glyphs: Vec<Option<CharGlyph>> = line
+                .chars()
+                .map(|c| self.char_glyph(opt, face, c))
+                .collect();
+            let width: f32 = glyphs
+                .iter()
+                .map(|glyph| glyph.map_or(0., |glyph| glyph.advance) * style.size)
+                .sum::<f32>()
+                + line.chars().count() as f32 * style.letter_spacing;
+            let start = x.unwrap_or(glyph_text.x);
+            let mut pen = match style.anchor.as_str() {
+                "middle" => start - width / 2.,
+                "end" => start - width,
+                _ => start,
+            };
+            let baseline = glyph_text.y + i as f32 * glyph_text.line_height;
+            for char_glyph in glyphs.into_iter().flatten() {
+                let key = GlyphKey {
+                    face: char_glyph.face,
+                    glyph: char_glyph.glyph.0,
+                    pixels_per_em,
+                };
+                if let Some(glyph) = self.glyph(opt, key).ok()? {
+                    let (left, top) = (pen + glyph.left * unit, baseline - glyph.top * unit);
+                    let (right, bottom) = (
+                        left + glyph.width as f32 * unit,
+                        top + glyph.height as f32 * unit,
+                    );
+                    let (u0, v0) = (glyph.x as f32 / size, glyph.y as f32 / size);
+                    let (u1, v1) = (
+                        (glyph.x + glyph.width) as f32 / size,
+                        (glyph.y + glyph.height) as f32 / size,
+                    );
+                    let v_len = triangles.vertices.len() as u32;
+                    let corners = [
+                        (left, top, u0, v0),
+                        (right, top, u1, v0),
+                        (right, bottom, u1, v1),
+                        (left, bottom, u0, v1),
+                    ];
+                    triangles
+                        .vertices
+                        .extend(corners.into_iter().map(|(x, y, u, v)| {
+                            let (x, y) = glyph_text.transform.apply(x as f64, y as f64);
+                            Vertex {
+                                position: [x as f32, y as f32, 0.],
+                                transform_id: glyph_text.transform_id,
+                                color: glyph_text.color,
+                                uv: [u, v],
+                                image_id: glyph.page as u32 + 1,
+                                ..Default::default()
+                            }
+                        }));
+                    let indices: Indices = [0, 1, 2, 0, 2, 3].iter().map(|i| i + v_len).collect();
+                    triangles.indices.extend(indices);
+                }
+                pen += char_glyph.advance * style.size + style.letter_spacing;
+            }
+        }
+        Some(triangles)
+    }
+}
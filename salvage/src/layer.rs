@@ -0,0 +1,45 @@
+use guppies::primitives::{BlendMode, Composite};
+use roxmltree::Document;
+use std::collections::HashMap;
+use usvg::{Node, NodeKind};
+
+/// `mix-blend-mode` of every element with an id, as usvg drops the property.
+/// Elements without an id don't survive as separate nodes anyway.
+pub fn blend_modes(document: &Document) -> HashMap<String, BlendMode> {
+    document
+        .descendants()
+        .filter_map(|node| {
+            let id = node.attribute("id")?;
+            let declared = node.attribute("mix-blend-mode").or_else(|| {
+                node.attribute("style")?
+                    .split(';')
+                    .filter_map(|declaration| declaration.split_once(':'))
+                    .find(|(name, _)| name.trim() == "mix-blend-mode")
+                    .map(|(_, value)| value)
+            })?;
+            let blend_mode = match declared.trim() {
+                "multiply" => BlendMode::Multiply,
+                "screen" => BlendMode::Screen,
+                "overlay" => BlendMode::Overlay,
+                "darken" => BlendMode::Darken,
+                "lighten" => BlendMode::Lighten,
+                _ => return None,
+            };
+            Some((id.to_string(), blend_mode))
+        })
+        .collect()
+}
+
+/// How `node` is composited when it needs a layer of its own,
+/// i.e. a group with opacity or any element with a blend mode.
+pub fn layer_composite(node: &Node, blend_mode: BlendMode) -> Option<Composite> {
+    let opacity = match *node.borrow() {
+        NodeKind::Group(ref group) => group.opacity.value() as f32,
+        NodeKind::Path(_) | NodeKind::Image(_) => 1.,
+        _ => return None,
+    };
+    (opacity < 1. || blend_mode != BlendMode::Normal).then_some(Composite {
+        opacity,
+        blend_mode,
+    })
+}
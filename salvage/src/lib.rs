@@ -1,7 +1,22 @@
+mod clip;
 mod convert_path;
+mod error;
 mod fill;
+pub mod fonts;
 pub mod geometry;
+mod glyph_atlas;
+mod layer;
+mod lod;
+pub mod measure;
+mod paint;
+mod prepare_triangles_from_image;
 mod prepare_triangles_from_path;
 mod stroke;
+mod style;
 pub mod svg_set;
+mod text;
+pub use error::Error;
+pub use layer::blend_modes;
+pub use roxmltree;
+pub use text::TextBox;
 pub use usvg;
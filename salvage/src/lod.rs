@@ -0,0 +1,79 @@
+use crate::geometry::Geometry;
+use std::collections::HashMap;
+use usvg::Node;
+
+// Curves are flattened until they stray at most this many device pixels
+const PIXEL_TOLERANCE: f32 = 0.1;
+// Tolerances stay within 2^-16 and 2^16 SVG units
+const MAX_LEVEL: i32 = 16;
+
+/// How a geometry of an `SvgSet` is built again at another tolerance.
+#[derive(Clone, Debug)]
+pub enum GeometrySource {
+    /// `Geometry::from_node` of the node with the transform id.
+    Node(Node, u32),
+    /// `Geometry::from_subtree` of the node with the transform id, e.g. replaced text.
+    Subtree(Node, u32),
+    /// `Geometry::clip_pop` of the geometry at the index.
+    ClipPop(usize),
+    /// The node drew nothing with the transform id, e.g. a hidden path, until it's restyled.
+    Blank(Node, u32),
+    /// `Geometry::pop_layer` of the node's layer.
+    Layer(Node),
+    /// Isn't built again, e.g. the `push_layer` of layers, or a geometry which the callback
+    /// of `SvgSet::new` didn't get from `Geometry::from_node`.
+    Fixed,
+}
+
+/// Level of detail for `zoom` device pixels per SVG unit.
+/// Each level doubles the tolerance of the one below, so the zoom has to
+/// double or halve before another tessellation is needed.
+pub fn lod_level(zoom: f32) -> i32 {
+    (PIXEL_TOLERANCE / zoom)
+        .log2()
+        .floor()
+        .clamp(-MAX_LEVEL as f32, MAX_LEVEL as f32) as i32
+}
+
+pub fn tolerance(level: i32) -> f32 {
+    2_f32.powi(level)
+}
+
+/// The largest zoom, in device pixels per SVG unit, which `level` is used for.
+pub fn max_zoom(level: i32) -> f32 {
+    PIXEL_TOLERANCE / tolerance(level)
+}
+
+/// Builds `geometries` again from their `sources` at `tolerance`.
+/// Geometries which can't be rebuilt, e.g. when tessellation fails at this tolerance,
+/// are kept as they are.
+pub fn retessellate(
+    geometries: &[Geometry],
+    sources: &[GeometrySource],
+    tolerance: f32,
+) -> Vec<Geometry> {
+    let mut retessellated: Vec<Geometry> = Vec::with_capacity(geometries.len());
+    for (geometry, source) in geometries.iter().zip(sources) {
+        let new_geometry = match source {
+            GeometrySource::Node(node, transform_id) => {
+                Geometry::from_node_with_tolerance(node, *transform_id, tolerance)
+                    .ok()
+                    .flatten()
+            }
+            GeometrySource::Subtree(node, transform_id) => {
+                // Replaced text, whose blend mode is drawn around it
+                Geometry::from_subtree(node, *transform_id, tolerance, &HashMap::new()).ok()
+            }
+            GeometrySource::ClipPop(index) => retessellated[*index].clip_pop(),
+            GeometrySource::Blank(..) | GeometrySource::Layer(_) | GeometrySource::Fixed => None,
+        };
+        retessellated.push(match new_geometry {
+            Some(new_geometry) => Geometry {
+                id: geometry.id.clone(),
+                ..new_geometry
+            },
+            None => geometry.clone(),
+        });
+    }
+    retessellated
+}
@@ -0,0 +1,194 @@
+use crate::fonts::parse_families;
+use roxmltree::Node;
+use usvg::{
+    fontdb::{Family, Query, Stretch, Style, Weight, ID},
+    Options,
+};
+
+// Font size of text which doesn't set its own
+const DEFAULT_FONT_SIZE: f32 = 12.;
+// Proportions of the em box assumed when no font is found
+const FALLBACK_ASCENT: f32 = 0.8;
+const FALLBACK_DESCENT: f32 = 0.2;
+const FALLBACK_ADVANCE: f32 = 0.5;
+
+/// Looks `name` up on `node` and its ancestors, in `style` declarations first.
+fn inherited<'a>(node: &Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.ancestors().filter(Node::is_element).find_map(|node| {
+        node.attribute("style")
+            .and_then(|style| {
+                style.split(';').find_map(|declaration| {
+                    let (key, value) = declaration.split_once(':')?;
+                    (key.trim() == name).then(|| value.trim())
+                })
+            })
+            .or_else(|| node.attribute(name))
+    })
+}
+
+fn parse_length(value: &str) -> Option<f32> {
+    value.trim().trim_end_matches("px").parse().ok()
+}
+
+/// The font properties of text which decide how large it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextStyle {
+    /// `font-family`s in order of preference, before the default of the `Options`.
+    pub families: Vec<String>,
+    pub size: f32,
+    pub weight: u16,
+    pub italic: bool,
+    pub letter_spacing: f32,
+    /// `text-anchor`, which doesn't change the size but where the text goes.
+    pub anchor: String,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            families: vec![],
+            size: DEFAULT_FONT_SIZE,
+            weight: 400,
+            italic: false,
+            letter_spacing: 0.,
+            anchor: "start".to_string(),
+        }
+    }
+}
+
+/// How large a string renders, in the user units the text is set in, i.e. scaled by the font size.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TextMetrics {
+    /// Sum of the advances of the characters and the letter spacing after each.
+    pub width: f32,
+    /// Height of the font above the baseline.
+    pub ascent: f32,
+    /// Depth of the font below the baseline, as a positive number.
+    pub descent: f32,
+    /// Distance between the baselines of consecutive lines the font recommends.
+    pub line_height: f32,
+}
+
+impl TextStyle {
+    /// The style `node` inherits, e.g. from its `<text>` and that one's groups.
+    pub(crate) fn from_node(node: &Node) -> Self {
+        let families = inherited(node, "font-family")
+            .map(parse_families)
+            .unwrap_or_default();
+        let weight = match inherited(node, "font-weight") {
+            Some("bold" | "bolder") => 700,
+            Some("lighter") => 300,
+            Some(weight) => weight.parse().unwrap_or(400),
+            None => 400,
+        };
+        Self {
+            families,
+            size: inherited(node, "font-size")
+                .and_then(parse_length)
+                .unwrap_or(DEFAULT_FONT_SIZE),
+            weight,
+            italic: matches!(inherited(node, "font-style"), Some("italic" | "oblique")),
+            letter_spacing: inherited(node, "letter-spacing")
+                .and_then(parse_length)
+                .unwrap_or(0.),
+            anchor: inherited(node, "text-anchor")
+                .unwrap_or("start")
+                .to_string(),
+        }
+    }
+}
+
+/// Advance of `c` in the first font of `opt` which has it, where usvg takes
+/// glyphs missing from the font of a text from.
+fn fallback_advance(opt: &Options, c: char, size: f32) -> Option<f32> {
+    let id = find_fallback_face(opt, c)?;
+    opt.fontdb
+        .with_face_data(id, |data, index| {
+            let face = ttf_parser::Face::from_slice(data, index).ok()?;
+            let advance = face.glyph_hor_advance(face.glyph_index(c)?)?;
+            Some(advance as f32 * size / face.units_per_em() as f32)
+        })
+        .flatten()
+}
+
+/// The first font of `opt` which has a glyph for `c`.
+pub(crate) fn find_fallback_face(opt: &Options, c: char) -> Option<ID> {
+    opt.fontdb.faces().iter().map(|face| face.id).find(|id| {
+        opt.fontdb
+            .with_face_data(*id, |data, index| {
+                ttf_parser::Face::from_slice(data, index)
+                    .ok()
+                    .and_then(|face| face.glyph_index(c))
+                    .is_some()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// The font usvg would set text in `style` in.
+pub(crate) fn find_face(opt: &Options, style: &TextStyle) -> Option<ID> {
+    let mut families: Vec<Family> = style
+        .families
+        .iter()
+        .map(|family| match family.as_str() {
+            "serif" => Family::Serif,
+            "sans-serif" => Family::SansSerif,
+            "cursive" => Family::Cursive,
+            "fantasy" => Family::Fantasy,
+            "monospace" => Family::Monospace,
+            family => Family::Name(family),
+        })
+        .collect();
+    families.push(Family::Name(&opt.font_family));
+    opt.fontdb.query(&Query {
+        families: &families,
+        weight: Weight(style.weight),
+        stretch: Stretch::Normal,
+        style: match style.italic {
+            true => Style::Italic,
+            false => Style::Normal,
+        },
+    })
+}
+
+/// Measures `text` set in `style` with the font usvg would pick from the `fontdb` of `opt`,
+/// e.g. the one of `get_usvg_options`.
+/// Families missing from it fall back to the `font_family` of `opt`, and characters
+/// missing from the font to other fonts, like usvg does.
+/// Kerning and shaping are left out, so the width is close to but not always exactly
+/// the rendered one.
+pub fn measure_text(opt: &Options, style: &TextStyle, text: &str) -> TextMetrics {
+    let characters = text.chars().count() as f32;
+    let metrics = find_face(opt, style).and_then(|id| {
+        opt.fontdb.with_face_data(id, |data, index| {
+            let face = ttf_parser::Face::from_slice(data, index).ok()?;
+            let scale = style.size / face.units_per_em() as f32;
+            let advance = |glyph| face.glyph_hor_advance(glyph).unwrap_or_default() as f32 * scale;
+            let width = text
+                .chars()
+                .map(|c| match face.glyph_index(c) {
+                    Some(glyph) => advance(glyph),
+                    None => fallback_advance(opt, c, style.size)
+                        .unwrap_or_else(|| advance(Default::default())),
+                })
+                .sum::<f32>();
+            let (ascender, descender) = (face.ascender() as f32, face.descender() as f32);
+            Some(TextMetrics {
+                width,
+                ascent: ascender * scale,
+                descent: -descender * scale,
+                line_height: (ascender - descender + face.line_gap() as f32) * scale,
+            })
+        })
+    });
+    let metrics = metrics.flatten().unwrap_or(TextMetrics {
+        width: characters * style.size * FALLBACK_ADVANCE,
+        ascent: style.size * FALLBACK_ASCENT,
+        descent: style.size * FALLBACK_DESCENT,
+        line_height: style.size * (FALLBACK_ASCENT + FALLBACK_DESCENT),
+    });
+    TextMetrics {
+        width: metrics.width + characters * style.letter_spacing,
+        ..metrics
+    }
+}
@@ -0,0 +1,164 @@
+use guppies::{
+    glam::{DAffine2, DVec2, Vec4},
+    primitives::Vertex,
+};
+use usvg::{BaseGradient, Node, NodeExt, NodeKind, Opacity, Paint, Path, SpreadMethod, Units};
+
+pub const FALLBACK_COLOR: Vec4 = Vec4::ONE;
+
+// See the gradient layout in guppies' shader.wgsl
+const LINEAR_GRADIENT: f32 = 0.;
+const RADIAL_GRADIENT: f32 = 1.;
+
+/// Straight RGBA of a plain colour paint, None for gradients and patterns.
+pub fn solid_color(paint: &Paint, opacity: Opacity) -> Option<[f32; 4]> {
+    match paint {
+        Paint::Color(c) => Some([
+            c.red as f32 / u8::MAX as f32,
+            c.green as f32 / u8::MAX as f32,
+            c.blue as f32 / u8::MAX as f32,
+            opacity.value() as f32,
+        ]),
+        Paint::Link(_) => None,
+    }
+}
+
+/// Colour of every vertex of one fill or stroke.
+/// Gradients are appended to the gradient table, and each vertex carries
+/// its position in the gradient's coordinate system so the shader can evaluate the stops.
+pub struct VertexPaint {
+    color: Vec4,
+    gradient_id: u32,
+    // Maps user space (after the path transform) to gradient space
+    to_gradient: DAffine2,
+}
+
+impl VertexPaint {
+    pub fn new(
+        paint: &Paint,
+        opacity: Opacity,
+        path: &Path,
+        defs: Option<&Node>,
+        gradients: &mut Vec<Vec4>,
+    ) -> Self {
+        let opacity = opacity.value() as f32;
+        let color = |c: &usvg::Color| {
+            Vec4::new(
+                c.red as f32 / u8::MAX as f32,
+                c.green as f32 / u8::MAX as f32,
+                c.blue as f32 / u8::MAX as f32,
+                opacity,
+            )
+        };
+        let solid = |color| Self {
+            color,
+            gradient_id: 0,
+            to_gradient: DAffine2::IDENTITY,
+        };
+        let id = match paint {
+            Paint::Color(c) => return solid(color(c)),
+            Paint::Link(id) => id,
+        };
+        let server = defs.and_then(|defs| defs.children().find(|node| &*node.id() == id));
+        let server = match server {
+            Some(server) => server,
+            None => return solid(FALLBACK_COLOR),
+        };
+        let (base, kind, geometry, focal) = match *server.borrow() {
+            NodeKind::LinearGradient(ref lg) => (
+                lg.base.clone(),
+                LINEAR_GRADIENT,
+                Vec4::new(lg.x1 as f32, lg.y1 as f32, lg.x2 as f32, lg.y2 as f32),
+                Vec4::ZERO,
+            ),
+            NodeKind::RadialGradient(ref rg) => (
+                rg.base.clone(),
+                RADIAL_GRADIENT,
+                Vec4::new(rg.cx as f32, rg.cy as f32, rg.r.value() as f32, 0.),
+                Vec4::new(rg.fx as f32, rg.fy as f32, 0., 0.),
+            ),
+            // Patterns aren't supported
+            _ => return solid(FALLBACK_COLOR),
+        };
+        let to_gradient = match gradient_to_user_space(&base, path) {
+            Some(to_user_space) => to_user_space.inverse(),
+            None => return solid(FALLBACK_COLOR),
+        };
+        let spread = match base.spread_method {
+            SpreadMethod::Pad => 0.,
+            SpreadMethod::Reflect => 1.,
+            SpreadMethod::Repeat => 2.,
+        };
+        let gradient_id = gradients.len() as u32 + 1;
+        gradients.extend([
+            Vec4::new(kind, spread, base.stops.len() as f32, 0.),
+            geometry,
+            focal,
+        ]);
+        gradients.extend(base.stops.iter().flat_map(|stop| {
+            let c = stop.color;
+            [
+                Vec4::new(stop.offset.value() as f32, 0., 0., 0.),
+                Vec4::new(
+                    c.red as f32 / u8::MAX as f32,
+                    c.green as f32 / u8::MAX as f32,
+                    c.blue as f32 / u8::MAX as f32,
+                    stop.opacity.value() as f32,
+                ),
+            ]
+        }));
+        Self {
+            // Stops carry the colour, fill or stroke opacity is applied on top
+            color: Vec4::new(1., 1., 1., opacity),
+            gradient_id,
+            to_gradient,
+        }
+    }
+    /// `(x, y)` is in user space, i.e. the path transform is already applied.
+    pub fn vertex(&self, x: f64, y: f64, transform_id: u32) -> Vertex {
+        let gradient_position = self.to_gradient.transform_point2(DVec2::new(x, y));
+        Vertex {
+            position: [x as f32, y as f32, 0.],
+            color: self.color.to_array(),
+            transform_id,
+            gradient_position: gradient_position.as_vec2().to_array(),
+            gradient_id: self.gradient_id,
+            ..Default::default()
+        }
+    }
+}
+
+/// Returns None when the gradient can't be drawn,
+/// e.g. objectBoundingBox units on a path without area.
+fn gradient_to_user_space(base: &BaseGradient, path: &Path) -> Option<DAffine2> {
+    let affine = |t: &usvg::Transform| DAffine2::from_cols_array(&[t.a, t.b, t.c, t.d, t.e, t.f]);
+    let bbox = match base.units {
+        Units::UserSpaceOnUse => DAffine2::IDENTITY,
+        Units::ObjectBoundingBox => {
+            let bbox = path.data.bbox()?;
+            if bbox.width() == 0. || bbox.height() == 0. {
+                return None;
+            }
+            DAffine2::from_scale_angle_translation(
+                DVec2::new(bbox.width(), bbox.height()),
+                0.,
+                DVec2::new(bbox.x(), bbox.y()),
+            )
+        }
+    };
+    let to_user_space = affine(&path.transform) * bbox * affine(&base.transform);
+    (to_user_space.matrix2.determinant() != 0.).then_some(to_user_space)
+}
+
+/// The `defs` node holding the paint servers of the tree `node` belongs to.
+pub fn find_defs(node: &Node) -> Option<Node> {
+    node.ancestors()
+        .last()
+        .and_then(|root| root.first_child())
+        .filter(|defs| matches!(*defs.borrow(), NodeKind::Defs))
+}
+
+/// Children of the defs node are only drawn through the nodes referencing them.
+pub fn is_defs(node: &Node) -> bool {
+    matches!(*node.borrow(), NodeKind::Defs)
+}
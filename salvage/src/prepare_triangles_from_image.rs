@@ -0,0 +1,74 @@
+use guppies::primitives::{RasterImage, Triangles, Vertex};
+use std::sync::Arc;
+use usvg::{utils::aligned_pos, Image, ImageKind, Visibility};
+
+/// Decodes the embedded bitmap and places it as a textured quad
+/// inside the image's viewport, honouring `preserveAspectRatio`.
+/// The quad samples image 0, `Geometry::extend` offsets it.
+/// Returns None for hidden, nested SVG or undecodable images.
+pub fn prepare_triangles_from_image(
+    image: &Image,
+    transform_id: u32,
+) -> Option<(Triangles, RasterImage)> {
+    if image.visibility != Visibility::Visible {
+        return None;
+    }
+    let (data, format) = match &image.kind {
+        ImageKind::PNG(data) => (data, image::ImageFormat::Png),
+        ImageKind::JPEG(data) => (data, image::ImageFormat::Jpeg),
+        ImageKind::GIF(data) => (data, image::ImageFormat::Gif),
+        ImageKind::SVG(_) => return None,
+    };
+    let rgba = image::load_from_memory_with_format(data, format)
+        .ok()?
+        .to_rgba8();
+    let (width, height) = rgba.dimensions();
+
+    let view_box = &image.view_box;
+    let rect = view_box.rect;
+    let size = usvg::Size::new(width as f64, height as f64)?.fit_view_box(view_box);
+    let (x, y) = aligned_pos(
+        view_box.aspect.align,
+        rect.x(),
+        rect.y(),
+        rect.width() - size.width(),
+        rect.height() - size.height(),
+    );
+    // `slice` lets the image overflow the viewport, so crop it back to the viewport
+    let (left, top) = (rect.x().max(x), rect.y().max(y));
+    let (right, bottom) = (
+        rect.right().min(x + size.width()),
+        rect.bottom().min(y + size.height()),
+    );
+    if left >= right || top >= bottom {
+        return None;
+    }
+    let vertices = [(left, top), (right, top), (right, bottom), (left, bottom)]
+        .into_iter()
+        .map(|(corner_x, corner_y)| {
+            let (tx, ty) = image.transform.apply(corner_x, corner_y);
+            Vertex {
+                position: [tx as f32, ty as f32, 0.],
+                transform_id,
+                color: [1., 1., 1., 1.],
+                uv: [
+                    ((corner_x - x) / size.width()) as f32,
+                    ((corner_y - y) / size.height()) as f32,
+                ],
+                image_id: 1,
+                ..Default::default()
+            }
+        })
+        .collect();
+    Some((
+        Triangles {
+            vertices,
+            indices: vec![0, 1, 2, 0, 2, 3],
+        },
+        RasterImage {
+            width,
+            height,
+            rgba: Arc::new(rgba.into_raw()),
+        },
+    ))
+}
@@ -1,41 +1,41 @@
-use crate::{fill::iterate_fill, stroke::iterate_stroke};
+use crate::{fill::iterate_fill, paint::VertexPaint, stroke::iterate_stroke};
 use guppies::{
     glam::Vec4,
     primitives::{Index, Triangles, Vertex},
 };
-use lyon::lyon_tessellation::VertexBuffers;
-use usvg::Path;
-pub const FALLBACK_COLOR: Vec4 = Vec4::ONE;
+use lyon::lyon_tessellation::{TessellationError, VertexBuffers};
+use usvg::{Node, Path, Visibility};
 
-pub fn prepare_triangles_from_path(p: &Path, transform_id: u32) -> Triangles {
+/// `defs` resolves gradient paints, whose stops are appended to `gradients`.
+/// Curves are flattened to within `tolerance` in the path's own units.
+pub fn prepare_triangles_from_path(
+    p: &Path,
+    transform_id: u32,
+    defs: Option<&Node>,
+    gradients: &mut Vec<Vec4>,
+    tolerance: f32,
+) -> Result<Triangles, TessellationError> {
     let mut vertex_buffer = VertexBuffers::<Vertex, Index>::new();
+    if p.visibility != Visibility::Visible {
+        return Ok(Triangles::default());
+    }
     if let Some(stroke) = &p.stroke {
-        let color = match stroke.paint {
-            usvg::Paint::Color(c) => Vec4::new(
-                c.red as f32 / u8::MAX as f32,
-                c.green as f32 / u8::MAX as f32,
-                c.blue as f32 / u8::MAX as f32,
-                stroke.opacity.value() as f32,
-            ),
-            _ => FALLBACK_COLOR,
-        };
-        iterate_stroke(stroke, p, &mut vertex_buffer, color, transform_id);
+        let paint = VertexPaint::new(&stroke.paint, stroke.opacity, p, defs, gradients);
+        iterate_stroke(
+            stroke,
+            p,
+            &mut vertex_buffer,
+            &paint,
+            transform_id,
+            tolerance,
+        )?;
     }
     if let Some(fill) = &p.fill {
-        let color = match fill.paint {
-            usvg::Paint::Color(c) => Vec4::new(
-                c.red as f32 / u8::MAX as f32,
-                c.green as f32 / u8::MAX as f32,
-                c.blue as f32 / u8::MAX as f32,
-                fill.opacity.value() as f32,
-            ),
-            _ => FALLBACK_COLOR,
-        };
-
-        iterate_fill(p, &color, &mut vertex_buffer, transform_id);
+        let paint = VertexPaint::new(&fill.paint, fill.opacity, p, defs, gradients);
+        iterate_fill(fill, p, &paint, &mut vertex_buffer, transform_id, tolerance)?;
     };
-    Triangles {
+    Ok(Triangles {
         vertices: vertex_buffer.vertices,
         indices: vertex_buffer.indices,
-    }
+    })
 }
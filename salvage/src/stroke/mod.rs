@@ -1,12 +1,14 @@
 use crate::convert_path::convert_path;
-use guppies::glam::Vec4;
+use crate::paint::VertexPaint;
 use guppies::primitives::{Index, Vertex};
-use lyon::lyon_tessellation::{StrokeOptions, StrokeTessellator, StrokeVertex};
+use lyon::algorithms::measure::{PathMeasurements, SampleType};
+use lyon::lyon_tessellation::{StrokeOptions, StrokeTessellator, StrokeVertex, TessellationError};
+use lyon::path::{self as lyon_path, PathEvent};
 use lyon::tessellation::geometry_builder::*;
 use lyon::tessellation::{self};
 use usvg::{self, Path};
 
-pub fn convert_stroke(s: &usvg::Stroke) -> StrokeOptions {
+pub fn convert_stroke(s: &usvg::Stroke, tolerance: f32) -> StrokeOptions {
     let linecap = match s.linecap {
         usvg::LineCap::Butt => tessellation::LineCap::Butt,
         usvg::LineCap::Square => tessellation::LineCap::Square,
@@ -18,32 +20,70 @@ pub fn convert_stroke(s: &usvg::Stroke) -> StrokeOptions {
         usvg::LineJoin::Round => tessellation::LineJoin::Round,
     };
 
-    StrokeOptions::tolerance(0.01)
+    StrokeOptions::tolerance(tolerance)
         .with_line_width(s.width.value() as f32)
         .with_line_cap(linecap)
         .with_line_join(linejoin)
+        .with_miter_limit(s.miterlimit.value() as f32)
+}
+
+/// Cuts `path` into its dashes, restarting the pattern at every subpath
+/// as SVG does. usvg only keeps dash arrays with an even count and a positive sum.
+fn dash_path(path: &Path, dasharray: &[f64], dashoffset: f32, tolerance: f32) -> lyon_path::Path {
+    let dasharray: Vec<f32> = dasharray.iter().map(|dash| *dash as f32).collect();
+    let period: f32 = dasharray.iter().sum();
+    let mut dashes = lyon_path::Path::builder();
+    let mut subpath = vec![];
+    for event in convert_path(path) {
+        let is_end = matches!(event, PathEvent::End { .. });
+        subpath.push(event);
+        if !is_end {
+            continue;
+        }
+        let subpath_path: lyon_path::Path = subpath.drain(..).collect();
+        let measurements = PathMeasurements::from_path(&subpath_path, tolerance);
+        let length = measurements.length();
+        if length <= 0. {
+            continue;
+        }
+        let mut sampler = measurements.create_sampler(&subpath_path, SampleType::Distance);
+        let mut start = -dashoffset.rem_euclid(period);
+        for (i, dash) in dasharray.iter().cycle().enumerate() {
+            if start >= length {
+                break;
+            }
+            let end = start + dash;
+            // Even entries are dashes, odd ones the gaps between them
+            if i % 2 == 0 && end > 0. {
+                sampler.split_range(start.max(0.)..end.min(length), dashes.inner_mut());
+            }
+            start = end;
+        }
+    }
+    dashes.build()
 }
 
 pub fn iterate_stroke(
     s: &usvg::Stroke,
     path: &Path,
     geometry: &mut VertexBuffers<Vertex, Index>,
-    color: Vec4,
+    paint: &VertexPaint,
     id: u32,
-) {
+    tolerance: f32,
+) -> Result<(), TessellationError> {
     let mut stroke_tess = StrokeTessellator::new();
-    let stroke_opts = convert_stroke(s);
-    let _ = stroke_tess.tessellate(
-        convert_path(path),
-        &stroke_opts,
-        &mut BuffersBuilder::new(geometry, |v: StrokeVertex| {
-            let position = v.position();
-            let (x, y) = path.transform.apply(position.x as f64, position.y as f64);
-            Vertex {
-                position: [x as f32, y as f32, 0.],
-                color: color.to_array(),
-                transform_id: id,
-            }
-        }),
-    );
+    let stroke_opts = convert_stroke(s, tolerance);
+    let mut builder = BuffersBuilder::new(geometry, |v: StrokeVertex| {
+        let position = v.position();
+        let (x, y) = path.transform.apply(position.x as f64, position.y as f64);
+        paint.vertex(x, y, id)
+    });
+    match &s.dasharray {
+        Some(dasharray) => stroke_tess.tessellate(
+            dash_path(path, dasharray, s.dashoffset, tolerance).iter(),
+            &stroke_opts,
+            &mut builder,
+        ),
+        None => stroke_tess.tessellate(convert_path(path), &stroke_opts, &mut builder),
+    }
 }
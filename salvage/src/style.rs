@@ -0,0 +1,233 @@
+use crate::fonts::escape;
+use roxmltree::{Document, NodeId};
+use std::ops::Range;
+use usvg::{Fill, FillRule, LineCap, LineJoin, Paint, Stroke};
+
+fn paint_value(paint: &Paint) -> String {
+    match paint {
+        Paint::Color(c) => format!("#{:02x}{:02x}{:02x}", c.red, c.green, c.blue),
+        Paint::Link(id) => format!("url(#{})", id),
+    }
+}
+
+/// Presentation attributes drawing the shapes of an element with `fill`.
+pub fn fill_properties(fill: Option<&Fill>) -> Vec<(&'static str, String)> {
+    let fill = match fill {
+        Some(fill) => fill,
+        None => return vec![("fill", "none".to_string())],
+    };
+    let rule = match fill.rule {
+        FillRule::NonZero => "nonzero",
+        FillRule::EvenOdd => "evenodd",
+    };
+    vec![
+        ("fill", paint_value(&fill.paint)),
+        ("fill-opacity", fill.opacity.value().to_string()),
+        ("fill-rule", rule.to_string()),
+    ]
+}
+
+/// Presentation attributes drawing the shapes of an element with `stroke`.
+pub fn stroke_properties(stroke: Option<&Stroke>) -> Vec<(&'static str, String)> {
+    let stroke = match stroke {
+        Some(stroke) => stroke,
+        None => return vec![("stroke", "none".to_string())],
+    };
+    let linecap = match stroke.linecap {
+        LineCap::Butt => "butt",
+        LineCap::Round => "round",
+        LineCap::Square => "square",
+    };
+    let linejoin = match stroke.linejoin {
+        LineJoin::Miter => "miter",
+        LineJoin::Round => "round",
+        LineJoin::Bevel => "bevel",
+    };
+    let dasharray = match &stroke.dasharray {
+        Some(dasharray) => dasharray
+            .iter()
+            .map(f64::to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+        None => "none".to_string(),
+    };
+    vec![
+        ("stroke", paint_value(&stroke.paint)),
+        ("stroke-opacity", stroke.opacity.value().to_string()),
+        ("stroke-width", stroke.width.value().to_string()),
+        ("stroke-linecap", linecap.to_string()),
+        ("stroke-linejoin", linejoin.to_string()),
+        ("stroke-miterlimit", stroke.miterlimit.value().to_string()),
+        ("stroke-dasharray", dasharray),
+        ("stroke-dashoffset", stroke.dashoffset.to_string()),
+    ]
+}
+
+/// `style` with its declarations of `properties` set to their values, or dropped
+/// without `values`, and the indices of the properties it declared.
+fn restyle_declarations(
+    style: &str,
+    properties: &[(&str, String)],
+    values: bool,
+) -> (String, Vec<usize>) {
+    let mut declared = vec![];
+    let declarations: Vec<String> = style
+        .split(';')
+        .filter_map(|declaration| {
+            let name = declaration.split_once(':').map(|(name, _)| name.trim());
+            match properties
+                .iter()
+                .position(|(property, _)| Some(*property) == name)
+            {
+                Some(i) if values => {
+                    declared.push(i);
+                    Some(format!("{}:{}", properties[i].0, properties[i].1))
+                }
+                Some(_) => None,
+                None => Some(declaration.to_string()),
+            }
+        })
+        .collect();
+    (declarations.join(";"), declared)
+}
+
+/// `xml` with `properties` set on `element`, where `style` declarations are
+/// changed in place and attributes added when missing.
+/// With `inherited`, the descendants lose their own values, so the whole subtree
+/// takes the new ones like usvg's shapes do.
+pub fn set_properties(
+    xml: &str,
+    element: NodeId,
+    properties: &[(&str, String)],
+    inherited: bool,
+) -> Result<String, roxmltree::Error> {
+    let document = Document::parse(xml)?;
+    let element = match document.get_node(element) {
+        Some(element) => element,
+        None => return Ok(xml.to_string()),
+    };
+    let mut edits: Vec<(Range<usize>, String)> = vec![];
+    let mut declared = vec![];
+    for a in element.attributes() {
+        if a.name() == "style" {
+            let (style, in_style) = restyle_declarations(a.value(), properties, true);
+            edits.push((a.value_range(), escape(&style)));
+            declared.extend(in_style);
+        }
+    }
+    let mut added = String::new();
+    for (i, (property, value)) in properties.iter().enumerate() {
+        if declared.contains(&i) {
+            continue;
+        }
+        match element.attributes().iter().find(|a| a.name() == *property) {
+            Some(a) => edits.push((a.value_range(), escape(value))),
+            None => added.push_str(&format!(" {}='{}'", property, escape(value))),
+        }
+    }
+    if !added.is_empty() {
+        // After the tag name, before the attributes
+        let start = element.range().start + 1;
+        let name_length = xml[start..]
+            .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+            .unwrap_or(0);
+        edits.push((start + name_length..start + name_length, added));
+    }
+    if inherited {
+        for a in element
+            .descendants()
+            .skip(1)
+            .flat_map(|node| node.attributes())
+        {
+            if a.name() == "style" {
+                let (style, _) = restyle_declarations(a.value(), properties, false);
+                edits.push((a.value_range(), escape(&style)));
+            } else if properties.iter().any(|(property, _)| *property == a.name()) {
+                // Along with the whitespace separating it from what's before
+                let start = xml[..a.range().start].trim_end().len();
+                edits.push((start..a.range().end, String::new()));
+            }
+        }
+    }
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut xml = xml.to_string();
+    // From the end, so the positions before stay where they were
+    for (range, replacement) in edits.iter().rev() {
+        xml.replace_range(range.clone(), replacement);
+    }
+    Ok(xml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `xml` after `set_properties` on the element with the id.
+    fn set(xml: &str, id: &str, properties: &[(&str, &str)], inherited: bool) -> String {
+        let document = Document::parse(xml).unwrap();
+        let element = document
+            .descendants()
+            .find(|node| node.attribute("id") == Some(id))
+            .unwrap()
+            .id();
+        let properties: Vec<(&str, String)> = properties
+            .iter()
+            .map(|(property, value)| (*property, value.to_string()))
+            .collect();
+        set_properties(xml, element, &properties, inherited).unwrap()
+    }
+
+    #[test]
+    fn replaces_attributes_in_place() {
+        let xml = "<svg><rect id='a' fill='red' x='1'/></svg>";
+        assert_eq!(
+            set(xml, "a", &[("fill", "#00ff00")], false),
+            "<svg><rect id='a' fill='#00ff00' x='1'/></svg>"
+        );
+    }
+
+    #[test]
+    fn changes_style_declarations_instead_of_attributes() {
+        let xml = "<svg><rect id='a' style='fill:red;stroke:blue'/></svg>";
+        assert_eq!(
+            set(xml, "a", &[("fill", "green")], false),
+            "<svg><rect id='a' style='fill:green;stroke:blue'/></svg>"
+        );
+    }
+
+    #[test]
+    fn adds_missing_attributes_after_the_tag_name() {
+        let xml = "<svg><rect id='a'/><g/></svg>";
+        assert_eq!(
+            set(xml, "a", &[("fill", "red"), ("opacity", "0.5")], false),
+            "<svg><rect fill='red' opacity='0.5' id='a'/><g/></svg>"
+        );
+    }
+
+    #[test]
+    fn keeps_the_text_around_the_edits() {
+        let xml = "<svg><text>héllo</text><rect id='a' fill='red'/><text>wörld</text></svg>";
+        assert_eq!(
+            set(xml, "a", &[("fill", "blue"), ("stroke", "none")], false),
+            "<svg><text>héllo</text><rect stroke='none' id='a' fill='blue'/><text>wörld</text></svg>"
+        );
+    }
+
+    #[test]
+    fn escapes_values() {
+        let xml = "<svg><rect id='a' fill='red'/></svg>";
+        let xml = set(xml, "a", &[("fill", "url(#a'b)")], false);
+        assert_eq!(xml, "<svg><rect id='a' fill='url(#a&apos;b)'/></svg>");
+        assert!(Document::parse(&xml).is_ok());
+    }
+
+    #[test]
+    fn inherited_drops_the_values_of_descendants() {
+        let xml =
+            "<svg><g id='a'><rect fill='red' x='1'/><path style='fill:red;stroke:blue'/></g></svg>";
+        assert_eq!(
+            set(xml, "a", &[("fill", "green")], true),
+            "<svg><g fill='green' id='a'><rect x='1'/><path style='stroke:blue'/></g></svg>"
+        );
+    }
+}
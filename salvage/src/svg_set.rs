@@ -1,67 +1,124 @@
-use crate::geometry::Geometry;
-use guppies::{glam::Vec2, primitives::Rect};
+use crate::{
+    error::Error,
+    fonts::{escape, Fonts},
+    geometry::{Geometry, DEFAULT_TOLERANCE},
+    glyph_atlas::{GlyphAtlas, GlyphText},
+    layer::{blend_modes, layer_composite},
+    lod::{lod_level, max_zoom, retessellate, tolerance, GeometrySource},
+    measure::{measure_text, TextMetrics, TextStyle},
+    paint::{is_defs, solid_color, FALLBACK_COLOR},
+    style::{fill_properties, set_properties, stroke_properties},
+    text::{
+        copy_context_element, fit_text, line_height, text_content, text_origin, text_style,
+        write_text, TextBox,
+    },
+};
+use guppies::{
+    glam::Vec2,
+    primitives::{BlendMode, Composite, DrawCommand, Rect},
+};
 use roxmltree::{Document, NodeId};
-use std::{collections::HashMap, fmt::Debug, sync::Arc};
-use usvg::{fontdb::Source, Node, Options, Tree};
+use std::{collections::HashMap, fmt::Debug, ops::Range, sync::Arc};
+use usvg::{Fill, Node, NodeExt, NodeKind, Opacity, Options, Stroke, Tree, Visibility};
 use xmlwriter::XmlWriter;
 
-fn recursive_svg<P: Clone + Debug, C: FnMut(Node, P) -> (Option<Geometry>, P)>(
+fn recursive_svg<P: Clone + Debug, C: FnMut(Node, P) -> Result<(Option<Geometry>, P), Error>>(
     node: usvg::Node,
     pass_down: P,
     geometries: &mut Vec<Geometry>,
+    sources: &mut Vec<GeometrySource>,
+    ranges: &mut HashMap<String, Range<usize>>,
     callback: &mut C,
-) {
-    let (geometry, pass_down) = callback(node.clone(), pass_down);
+    blend_modes: &HashMap<String, BlendMode>,
+) -> Result<(), Error> {
+    let blend_mode = blend_modes.get(&*node.id()).copied().unwrap_or_default();
+    let composite = layer_composite(&node, blend_mode);
+    let layer_start = geometries.len();
+    if composite.is_some() {
+        geometries.push(Geometry::push_layer());
+        sources.push(GeometrySource::Fixed);
+    }
+    let (geometry, pass_down) = callback(node.clone(), pass_down)?;
+    let clip_pop = geometry.as_ref().and_then(Geometry::clip_pop);
+    let geometry_index = geometries.len();
     if let Some(geometry) = geometry {
+        // Geometries the callback built otherwise are kept as they are
+        let transform_id = geometry
+            .node_transform_id
+            .filter(|_| geometry.id == *node.id());
+        sources.push(match transform_id {
+            None => GeometrySource::Fixed,
+            Some(transform_id) if geometry.triangles.vertices.is_empty() => {
+                GeometrySource::Blank(node.clone(), transform_id)
+            }
+            Some(transform_id) => GeometrySource::Node(node.clone(), transform_id),
+        });
         geometries.push(geometry);
     }
-    for child in node.children() {
-        recursive_svg(child, pass_down.clone(), geometries, callback);
+    if !is_defs(&node) {
+        for child in node.children() {
+            recursive_svg(
+                child,
+                pass_down.clone(),
+                geometries,
+                sources,
+                ranges,
+                callback,
+                blend_modes,
+            )?;
+        }
     }
-}
-
-fn find_text_node_path(node: roxmltree::Node, path: &mut Vec<roxmltree::NodeId>) -> bool {
-    if node.is_element() {
-        path.insert(0, node.id());
+    if let Some(clip_pop) = clip_pop {
+        geometries.push(clip_pop);
+        sources.push(GeometrySource::ClipPop(geometry_index));
     }
-    if let Some(text) = node.text() {
-        let no_new_line_or_space = text.replace("\n", "").replace(" ", "");
-        if no_new_line_or_space.len() != 0 {
-            return true;
-        }
+    if !node.id().is_empty() {
+        ranges.insert(node.id().to_string(), geometry_index..geometries.len());
     }
-    for child in node.children() {
-        if find_text_node_path(child, path) {
-            return true;
+    if let Some(composite) = composite {
+        // Layers which nothing was drawn into aren't worth compositing
+        match geometries.len() == layer_start + 1 {
+            true => {
+                geometries.pop();
+                sources.pop();
+            }
+            false => {
+                geometries.push(Geometry::pop_layer(composite));
+                sources.push(GeometrySource::Layer(node.clone()));
+            }
         }
     }
-    false
+    Ok(())
 }
+
 #[derive(Debug, Default, Clone)]
 pub struct SvgSet {
     pub geometries: Vec<Geometry>,
     pub raw_xml: String,
     pub id_to_svg: HashMap<String, NodeId>,
     pub id_to_geometry_index: HashMap<String, usize>,
+    // Geometries drawn for each node with an id and everything below it,
+    // e.g. the lines of a text, which usvg turns into a group of paths
+    pub id_to_geometry_range: HashMap<String, Range<usize>>,
     pub current_text_map: HashMap<String, String>,
     pub bbox: Rect,
-    usvg_options: Arc<Options>,
+    fonts: Arc<Fonts>,
+    // How each of `geometries` is tessellated again for another level of detail
+    sources: Vec<GeometrySource>,
+    // Keeps the nodes of `sources` in their tree, as nodes only weakly refer to their parents
+    _root: Option<Node>,
+    // None until `set_zoom`, while geometries use `DEFAULT_TOLERANCE`
+    lod_level: Option<i32>,
+    // Geometries of the levels visited before, so zooming back doesn't tessellate again
+    lod_cache: HashMap<i32, Vec<Geometry>>,
+    // Boxes which texts are wrapped into, see `set_text_box`
+    text_boxes: HashMap<String, TextBox>,
+    // Texts drawn from the atlas, see `use_glyph_atlas`, and their geometries
+    glyph_atlas: GlyphAtlas,
+    glyph_texts: HashMap<String, (GlyphText, Range<usize>)>,
 }
 
 impl SvgSet {
-    fn copy_element(&self, node: &roxmltree::Node, writer: &mut XmlWriter) {
-        writer.start_element(node.tag_name().name());
-        for a in node.attributes() {
-            let name = if a.namespace().is_some() {
-                format!("xml:{}", a.name())
-            } else {
-                a.name().to_string()
-            };
-            if a.name() != "filter" {
-                writer.write_attribute(&name, a.value());
-            }
-        }
-    }
     pub fn get_combined_geometries(&self) -> Geometry {
         self.geometries
             .iter()
@@ -69,20 +126,53 @@ impl SvgSet {
                 acc.extend(&geometry)
             })
     }
+    /// Panics when the SVG is invalid or can't be tessellated, see `try_new`.
     pub fn new<P: Clone + Debug, C: FnMut(Node, P) -> (Option<Geometry>, P)>(
         xml: String,
         initial_pass_down: P,
         mut callback: C,
     ) -> Self {
-        let font = include_bytes!("../fallback_font/Roboto-Medium.ttf");
-        let mut opt = Options::default();
-        opt.fontdb
-            .load_font_source(Source::Binary(Arc::new(font.as_ref())));
-        opt.font_family = "Roboto Medium".to_string();
-        opt.keep_named_groups = true;
-        let document = Document::parse(&xml).unwrap();
-        let opt = get_usvg_options();
-        let tree = Tree::from_xmltree(&document, &opt.to_ref()).unwrap();
+        Self::try_new(xml, initial_pass_down, |node, pass_down| {
+            Ok(callback(node, pass_down))
+        })
+        .unwrap()
+    }
+    /// `new` which fails instead of panicking, e.g. on malformed XML.
+    /// The callback can fail too, e.g. with the error of `Geometry::try_from_node`,
+    /// which aborts the whole set.
+    pub fn try_new<P: Clone + Debug, C: FnMut(Node, P) -> Result<(Option<Geometry>, P), Error>>(
+        xml: String,
+        initial_pass_down: P,
+        callback: C,
+    ) -> Result<Self, Error> {
+        Self::try_new_with_fonts(xml, Default::default(), initial_pass_down, callback)
+    }
+    /// Panics when the SVG is invalid or can't be tessellated, see `try_new_with_fonts`.
+    pub fn new_with_fonts<P: Clone + Debug, C: FnMut(Node, P) -> (Option<Geometry>, P)>(
+        xml: String,
+        fonts: Arc<Fonts>,
+        initial_pass_down: P,
+        mut callback: C,
+    ) -> Self {
+        Self::try_new_with_fonts(xml, fonts, initial_pass_down, |node, pass_down| {
+            Ok(callback(node, pass_down))
+        })
+        .unwrap()
+    }
+    /// `try_new` which sets texts in `fonts` instead of only the embedded Roboto Medium.
+    /// `fonts` can be shared by many sets, as loading system fonts takes a while.
+    pub fn try_new_with_fonts<
+        P: Clone + Debug,
+        C: FnMut(Node, P) -> Result<(Option<Geometry>, P), Error>,
+    >(
+        xml: String,
+        fonts: Arc<Fonts>,
+        initial_pass_down: P,
+        mut callback: C,
+    ) -> Result<Self, Error> {
+        let xml = fonts.apply_fallbacks(&xml)?;
+        let document = Document::parse(&xml)?;
+        let tree = Tree::from_xmltree(&document, &fonts.usvg_options().to_ref())?;
         let id_to_svg =
             document
                 .descendants()
@@ -93,12 +183,17 @@ impl SvgSet {
                     acc
                 });
         let mut geometries: Vec<Geometry> = vec![];
+        let mut sources: Vec<GeometrySource> = vec![];
+        let mut id_to_geometry_range = HashMap::new();
         recursive_svg(
             tree.root(),
             initial_pass_down,
             &mut geometries,
+            &mut sources,
+            &mut id_to_geometry_range,
             &mut callback,
-        );
+            &blend_modes(&document),
+        )?;
         let id_to_geometry_index: HashMap<String, usize> =
             geometries
                 .iter()
@@ -112,79 +207,736 @@ impl SvgSet {
             Vec2::new(view_box.rect.x() as f32, view_box.rect.y() as f32),
             Vec2::new(view_box.rect.width() as f32, view_box.rect.height() as f32),
         );
-        Self {
+        Ok(Self {
             geometries,
             raw_xml: xml.to_string(),
             id_to_svg,
             id_to_geometry_index,
+            id_to_geometry_range,
             bbox,
-            usvg_options: Arc::new(opt),
+            fonts,
+            sources,
+            _root: Some(tree.root()),
             ..Default::default()
+        })
+    }
+    fn tolerance(&self) -> f32 {
+        self.lod_level.map_or(DEFAULT_TOLERANCE, tolerance)
+    }
+    /// Tessellates the geometries again when `zoom`, in device pixels per SVG unit,
+    /// crosses into another level of detail, so curves stay smooth when zoomed in
+    /// and cheap when zoomed out.
+    /// Returns whether the geometries changed.
+    pub fn set_zoom(&mut self, zoom: f32) -> bool {
+        if !zoom.is_finite() || zoom <= 0. {
+            return false;
+        }
+        let level = lod_level(zoom);
+        if self.lod_level == Some(level) {
+            return false;
+        }
+        let geometries = match self.lod_cache.remove(&level) {
+            Some(geometries) => geometries,
+            None => retessellate(&self.geometries, &self.sources, tolerance(level)),
+        };
+        let previous = std::mem::replace(&mut self.geometries, geometries);
+        if let Some(previous_level) = self.lod_level.replace(level) {
+            self.lod_cache.insert(previous_level, previous);
         }
+        // Glyphs are rasterized for the zoom. When they no longer fit the atlas,
+        // the texts are drawn from the glyphs of the previous level.
+        if !self.glyph_texts.is_empty() && self.layout_glyph_texts().is_ok() {
+            self.refresh_glyph_atlas();
+        }
+        true
+    }
+    /// Device pixels per SVG unit which glyphs from the atlas are rasterized for.
+    fn zoom(&self) -> f32 {
+        self.lod_level.map_or(1., max_zoom)
     }
+    /// Panics when no text has the id, see `try_update_text`.
     pub fn update_text(&mut self, id: &str, new_text: &str) {
-        match self
+        self.try_update_text(id, new_text).unwrap()
+    }
+    /// `update_text` which fails instead of panicking, e.g. when no text has the id.
+    /// The text stays as it was on failure.
+    pub fn try_update_text(&mut self, id: &str, new_text: &str) -> Result<(), Error> {
+        if self.current_text_map.get(id).map(String::as_str) == Some(new_text) {
+            return Ok(());
+        }
+        self.write_text(id, new_text)
+    }
+    /// Panics when no text has the id, see `try_set_text_box`.
+    pub fn set_text_box(&mut self, id: &str, text_box: Option<TextBox>) {
+        self.try_set_text_box(id, text_box).unwrap()
+    }
+    /// Wraps the text with the id to `text_box`, or lets it run freely again with `None`.
+    /// The box stays for the following `update_text`s, and only needs setting again when
+    /// it changes, e.g. when the layout of the text is resized.
+    pub fn try_set_text_box(&mut self, id: &str, text_box: Option<TextBox>) -> Result<(), Error> {
+        if self.text_boxes.get(id) == text_box.as_ref() {
+            return Ok(());
+        }
+        let current_text = self.current_text(id)?;
+        let previous = match text_box {
+            Some(text_box) => self.text_boxes.insert(id.to_string(), text_box),
+            None => self.text_boxes.remove(id),
+        };
+        let result = self.write_text(id, &current_text);
+        if result.is_err() {
+            match previous {
+                Some(previous) => self.text_boxes.insert(id.to_string(), previous),
+                None => self.text_boxes.remove(id),
+            };
+        }
+        result
+    }
+    /// Panics when no text has the id, see `try_use_glyph_atlas`.
+    pub fn use_glyph_atlas(&mut self, id: &str) {
+        self.try_use_glyph_atlas(id).unwrap()
+    }
+    /// Draws the text with the id from glyphs rasterized once into an atlas from now on,
+    /// so `update_text` only places a quad per glyph instead of parsing and tessellating
+    /// the text again, e.g. for counters which change all the time.
+    /// The glyphs are rasterized for the zoom of `set_zoom`. The whole text takes the
+    /// style and colour of its first span, and gradients or strokes are left out.
+    pub fn try_use_glyph_atlas(&mut self, id: &str) -> Result<(), Error> {
+        if self.glyph_texts.contains_key(id) {
+            return Ok(());
+        }
+        let current_text = self.current_text(id)?;
+        let document = Document::parse(&self.raw_xml)?;
+        let text = self.find_text(&document, id)?;
+        let range = self.text_range(&text, id)?;
+        // usvg bakes the position of the glyphs into the paths, with the text's transform
+        let text_id = text.attribute("id").unwrap_or(id);
+        let (transform, visibility, fill) = self
+            ._root
+            .iter()
+            .flat_map(|root| root.descendants())
+            .find(|node| &*node.id() == text_id)
+            .and_then(|node| {
+                node.descendants().find_map(|node| match &*node.borrow() {
+                    NodeKind::Path(path) => {
+                        Some((path.transform, path.visibility, path.fill.clone()))
+                    }
+                    _ => None,
+                })
+            })
+            .unwrap_or_default();
+        let transform_id = self.transform_id(range.clone());
+        // The fill, as a stroke around the glyphs would be tessellated first
+        let color = match &fill {
+            Some(fill) => solid_color(&fill.paint, fill.opacity).unwrap_or(FALLBACK_COLOR.into()),
+            None => [0.; 4],
+        };
+        let style = text_style(&text);
+        let (x, y) = text_origin(&text);
+        let glyph_text = GlyphText {
+            line_height: line_height(&text, &style),
+            style,
+            x,
+            y,
+            transform,
+            color,
+            transform_id,
+            visible: visibility == Visibility::Visible,
+        };
+        self.glyph_texts
+            .insert(id.to_string(), (glyph_text, range.clone()));
+        if let Err(e) = self.write_glyph_text(id, &current_text) {
+            self.glyph_texts.remove(id);
+            return Err(e);
+        }
+        // The tessellated text is drawn again when zooming
+        for index in range {
+            self.sources[index] = GeometrySource::Fixed;
+        }
+        Ok(())
+    }
+    /// The style the text with the id is set in, to `measure_text` other strings with.
+    pub fn text_style(&self, id: &str) -> Result<TextStyle, Error> {
+        let document = Document::parse(&self.raw_xml)?;
+        Ok(text_style(&self.find_text(&document, id)?))
+    }
+    /// How large `text` renders in `style` with the fonts of this set,
+    /// e.g. to size a container to its text or decide where to truncate it.
+    pub fn measure_text(&self, style: &TextStyle, text: &str) -> TextMetrics {
+        let style = TextStyle {
+            families: self.fonts.with_fallbacks(&style.families),
+            ..style.clone()
+        };
+        measure_text(self.fonts.usvg_options(), &style, text)
+    }
+    /// Panics when no element has the id, see `try_set_fill`.
+    pub fn set_fill(&mut self, id: &str, fill: Option<Fill>) {
+        self.try_set_fill(id, fill).unwrap()
+    }
+    /// Fills every shape of the element with the id and below it with `fill`,
+    /// or nothing with None, e.g. for the states of a button or another theme.
+    /// Only the geometries of the element are tessellated again.
+    pub fn try_set_fill(&mut self, id: &str, fill: Option<Fill>) -> Result<(), Error> {
+        self.restyle(
+            id,
+            &fill_properties(fill.as_ref()),
+            true,
+            |kind| {
+                if let NodeKind::Path(path) = kind {
+                    path.fill = fill.clone();
+                }
+            },
+            |glyph_text| match &fill {
+                Some(fill) => {
+                    // Gradients aren't drawn from the atlas
+                    if let Some(color) = solid_color(&fill.paint, fill.opacity) {
+                        glyph_text.color = color
+                    }
+                }
+                None => glyph_text.color[3] = 0.,
+            },
+        )
+    }
+    /// Panics when no element has the id, see `try_set_stroke`.
+    pub fn set_stroke(&mut self, id: &str, stroke: Option<Stroke>) {
+        self.try_set_stroke(id, stroke).unwrap()
+    }
+    /// Strokes every shape of the element with the id and below it with `stroke`,
+    /// or nothing with None. Texts drawn from the glyph atlas stay unstroked.
+    pub fn try_set_stroke(&mut self, id: &str, stroke: Option<Stroke>) -> Result<(), Error> {
+        self.restyle(
+            id,
+            &stroke_properties(stroke.as_ref()),
+            true,
+            |kind| {
+                if let NodeKind::Path(path) = kind {
+                    path.stroke = stroke.clone();
+                }
+            },
+            |_| {},
+        )
+    }
+    /// Panics when no element has the id, see `try_set_opacity`.
+    pub fn set_opacity(&mut self, id: &str, opacity: f32) {
+        self.try_set_opacity(id, opacity).unwrap()
+    }
+    /// Composites the element with the id at `opacity`, like its `opacity` attribute.
+    /// An element which wasn't translucent yet gets a layer of its own, which moves
+    /// the indices of the geometries after it.
+    pub fn try_set_opacity(&mut self, id: &str, opacity: f32) -> Result<(), Error> {
+        let node = self.find_node(id)?;
+        let opacity = opacity.clamp(0., 1.);
+        self.set_xml_properties(id, &[("opacity", opacity.to_string())], false)?;
+        // usvg moves the opacity of shapes into a group without id around them
+        let mut target = match node.parent() {
+            Some(parent)
+                if !matches!(*node.borrow(), NodeKind::Group(_))
+                    && parent.id().is_empty()
+                    && parent.children().count() == 1 =>
+            {
+                parent
+            }
+            _ => node.clone(),
+        };
+        if let NodeKind::Group(group) = &mut *target.borrow_mut() {
+            group.opacity = Opacity::new(opacity as f64);
+        }
+        let layer = self
+            .sources
+            .iter()
+            .position(|source| matches!(source, GeometrySource::Layer(layer) if *layer == target));
+        match layer {
+            Some(index) => {
+                let composite = match self.geometries[index].draw_commands.first() {
+                    Some(DrawCommand::PopLayer(composite)) => *composite,
+                    _ => Composite::default(),
+                };
+                self.geometries[index] = Geometry::pop_layer(Composite {
+                    opacity,
+                    ..composite
+                });
+            }
+            // Opaque elements draw the same without a layer
+            None => match self.id_to_geometry_range.get(id) {
+                Some(range) if !range.is_empty() && opacity < 1. => {
+                    let composite = Composite {
+                        opacity,
+                        blend_mode: BlendMode::Normal,
+                    };
+                    self.insert_layer(&node, target, range.clone(), composite);
+                }
+                _ => {}
+            },
+        }
+        self.lod_cache.clear();
+        Ok(())
+    }
+    /// Panics when no element has the id, see `try_set_visibility`.
+    pub fn set_visibility(&mut self, id: &str, visible: bool) {
+        self.try_set_visibility(id, visible).unwrap()
+    }
+    /// Shows or hides every shape and image of the element with the id and below it,
+    /// like its `visibility` attribute.
+    pub fn try_set_visibility(&mut self, id: &str, visible: bool) -> Result<(), Error> {
+        let (visibility, value) = match visible {
+            true => (Visibility::Visible, "visible"),
+            false => (Visibility::Hidden, "hidden"),
+        };
+        self.restyle(
+            id,
+            &[("visibility", value.to_string())],
+            true,
+            |kind| match kind {
+                NodeKind::Path(path) => path.visibility = visibility,
+                NodeKind::Image(image) => image.visibility = visibility,
+                _ => {}
+            },
+            |glyph_text| glyph_text.visible = visible,
+        )
+    }
+    /// Panics when no `<path>` has the id, see `try_set_path_data`.
+    pub fn set_path_data(&mut self, id: &str, data: &str) {
+        self.try_set_path_data(id, data).unwrap()
+    }
+    /// Replaces the outline of the `<path>` with the id by `data`, in the syntax of
+    /// its `d` attribute, e.g. for icons which morph between states.
+    /// Invalid data draws nothing, like in the SVG.
+    pub fn try_set_path_data(&mut self, id: &str, data: &str) -> Result<(), Error> {
+        let node = self.find_node(id)?;
+        let document = Document::parse(&self.raw_xml)?;
+        let is_path = self
+            .id_to_svg
+            .get(id)
+            .and_then(|node_id| document.get_node(*node_id))
+            .is_some_and(|element| element.has_tag_name("path"));
+        if !is_path || !matches!(*node.borrow(), NodeKind::Path(_)) {
+            return Err(Error::NotPath(id.to_string()));
+        }
+        let xml = format!(
+            "<svg xmlns='http://www.w3.org/2000/svg'><path d='{}'/></svg>",
+            escape(data)
+        );
+        let tree = Tree::from_str(&xml, &self.fonts.usvg_options().to_ref())?;
+        let path_data = tree
+            .root()
+            .descendants()
+            .find_map(|node| match &*node.borrow() {
+                NodeKind::Path(path) => Some(path.data.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        self.restyle(
+            id,
+            &[("d", data.to_string())],
+            false,
+            |kind| {
+                if let NodeKind::Path(path) = kind {
+                    path.data = path_data.clone();
+                }
+            },
+            |_| {},
+        )
+    }
+    /// The node of the element with the id in the tree.
+    fn find_node(&self, id: &str) -> Result<Node, Error> {
+        self._root
+            .iter()
+            .flat_map(|root| root.descendants())
+            .find(|node| &*node.id() == id)
+            .ok_or_else(|| Error::UnknownId(id.to_string()))
+    }
+    /// The `<text>` of the element with the id, or the element itself.
+    fn find_text<'a, 'input>(
+        &self,
+        document: &'a Document<'input>,
+        id: &str,
+    ) -> Result<roxmltree::Node<'a, 'input>, Error> {
+        let unknown_id = || Error::UnknownId(id.to_string());
+        let node_id = self.id_to_svg.get(id).ok_or_else(unknown_id)?;
+        let node = document.get_node(*node_id).ok_or_else(unknown_id)?;
+        node.descendants()
+            .find(|node| node.has_tag_name("text"))
+            .ok_or_else(|| Error::NotText(id.to_string()))
+    }
+    /// The text with the id as it's drawn now.
+    fn current_text(&self, id: &str) -> Result<String, Error> {
+        match self.current_text_map.get(id) {
+            Some(current_text) => Ok(current_text.clone()),
+            None => {
+                let document = Document::parse(&self.raw_xml)?;
+                Ok(text_content(&self.find_text(&document, id)?))
+            }
+        }
+    }
+    /// The geometries drawn for `text`, found with the id.
+    fn text_range(&self, text: &roxmltree::Node, id: &str) -> Result<Range<usize>, Error> {
+        // The text's own geometries, unless only its container has an id
+        text.attribute("id")
+            .and_then(|text_id| self.id_to_geometry_range.get(text_id))
+            .or_else(|| self.id_to_geometry_range.get(id))
+            .filter(|range| (*range).clone().any(|index| !self.is_clip_or_layer(index)))
+            .cloned()
+            .ok_or_else(|| Error::UnknownId(id.to_string()))
+    }
+    /// The transform the geometries in `range` are drawn with, or the SVG's own
+    /// when they have no vertices.
+    fn transform_id(&self, range: Range<usize>) -> u32 {
+        self.geometries[range]
+            .iter()
+            .find_map(|geometry| geometry.triangles.vertices.first())
+            .map_or(1, |vertex| vertex.transform_id)
+    }
+    /// Whether the geometry at the index opens or closes a clip or a layer,
+    /// e.g. of the container of a text.
+    fn is_clip_or_layer(&self, index: usize) -> bool {
+        matches!(
+            self.sources[index],
+            GeometrySource::ClipPop(_) | GeometrySource::Layer(_)
+        ) || self.geometries[index]
+            .draw_commands
+            .iter()
+            .any(|command| matches!(command, DrawCommand::PushClip(_) | DrawCommand::PushLayer))
+    }
+    /// Puts `geometry` in place of the first geometry of `range` drawing content, keeping
+    /// the indices of the others, which are left empty. Clips and layers stay as they are.
+    fn replace_geometries(
+        &mut self,
+        range: Range<usize>,
+        geometry: Geometry,
+        source: GeometrySource,
+    ) {
+        let content: Vec<usize> = range
+            .filter(|index| !self.is_clip_or_layer(*index))
+            .collect();
+        let (&first, rest) = content.split_first().expect("text_range has content");
+        self.geometries[first] = Geometry {
+            id: self.geometries[first].id.clone(),
+            ..geometry
+        };
+        self.sources[first] = source;
+        for &index in rest {
+            self.geometries[index] = Geometry::default();
+            self.sources[index] = GeometrySource::Fixed;
+        }
+    }
+    /// Lays out the current text of the glyph text with the id.
+    /// Returns None when its glyphs don't fit into the atlas.
+    fn layout_glyph_text(&mut self, id: &str) -> Option<()> {
+        let (glyph_text, range) = self.glyph_texts.get(id)?.clone();
+        if !glyph_text.visible {
+            self.replace_geometries(range, Geometry::default(), GeometrySource::Fixed);
+            return Some(());
+        }
+        let current_text = self.current_text_map.get(id)?;
+        let opt = self.fonts.usvg_options();
+        let (text, x) = match self.text_boxes.get(id) {
+            Some(text_box) => {
+                let (text, x) = fit_text(
+                    current_text,
+                    text_box,
+                    &glyph_text.style,
+                    glyph_text.line_height,
+                    |line| measure_text(opt, &glyph_text.style, line).width,
+                );
+                (text, Some(x))
+            }
+            None => (current_text.clone(), None),
+        };
+        let zoom = self.zoom();
+        let triangles = self.glyph_atlas.layout(opt, &glyph_text, &text, x, zoom)?;
+        let geometry = Geometry {
+            triangles,
+            images: self.glyph_atlas.images().to_vec(),
+            ..Default::default()
+        };
+        self.replace_geometries(range, geometry, GeometrySource::Fixed);
+        Some(())
+    }
+    /// Lays out every glyph text again, e.g. after the zoom changed.
+    /// Starts over with an empty atlas when they don't fit.
+    fn layout_glyph_texts(&mut self) -> Result<(), Error> {
+        let ids: Vec<String> = self.glyph_texts.keys().cloned().collect();
+        if ids.iter().all(|id| self.layout_glyph_text(id).is_some()) {
+            return Ok(());
+        }
+        self.glyph_atlas.clear();
+        match ids.iter().all(|id| self.layout_glyph_text(id).is_some()) {
+            true => Ok(()),
+            false => Err(Error::GlyphAtlasFull),
+        }
+    }
+    /// Points the geometries of every glyph text to the atlas with the latest glyphs.
+    fn refresh_glyph_atlas(&mut self) {
+        for (_, range) in self.glyph_texts.values() {
+            self.geometries[range.start].images = self.glyph_atlas.images().to_vec();
+        }
+    }
+    fn write_glyph_text(&mut self, id: &str, new_text: &str) -> Result<(), Error> {
+        let previous = self
             .current_text_map
-            .insert(id.to_string(), new_text.to_string())
-        {
-            Some(old_text) if old_text == new_text => {
-                return;
+            .insert(id.to_string(), new_text.to_string());
+        // The other texts are laid out again too when the atlas had to be cleared
+        if self.layout_glyph_text(id).is_none() {
+            if let Err(e) = self.layout_glyph_texts() {
+                match previous {
+                    Some(previous) => self.current_text_map.insert(id.to_string(), previous),
+                    None => self.current_text_map.remove(id),
+                };
+                return Err(e);
+            }
+        }
+        self.refresh_glyph_atlas();
+        Ok(())
+    }
+    /// Sets `properties` on the element with the id in `raw_xml`, so the texts
+    /// written again later keep them too.
+    fn set_xml_properties(
+        &mut self,
+        id: &str,
+        properties: &[(&str, String)],
+        inherited: bool,
+    ) -> Result<(), Error> {
+        let element = self
+            .id_to_svg
+            .get(id)
+            .ok_or_else(|| Error::UnknownId(id.to_string()))?;
+        self.raw_xml = set_properties(&self.raw_xml, *element, properties, inherited)?;
+        Ok(())
+    }
+    /// Sets `properties` on the element with the id, see `set_xml_properties`, and
+    /// changes the shapes, images and glyph texts drawn for it to match with `restyle`
+    /// and `restyle_glyph_text`. Only its geometries are tessellated again.
+    /// Everything is left as it was when any of it fails.
+    fn restyle(
+        &mut self,
+        id: &str,
+        properties: &[(&str, String)],
+        inherited: bool,
+        mut restyle: impl FnMut(&mut NodeKind),
+        restyle_glyph_text: impl FnMut(&mut GlyphText),
+    ) -> Result<(), Error> {
+        let element = self
+            .id_to_svg
+            .get(id)
+            .ok_or_else(|| Error::UnknownId(id.to_string()))?;
+        let raw_xml = set_properties(&self.raw_xml, *element, properties, inherited)?;
+        let range = match self.id_to_geometry_range.get(id) {
+            Some(range) => range.clone(),
+            None => {
+                self.raw_xml = raw_xml;
+                return Ok(());
             }
-            _ => {}
         };
-
-        let document = Document::parse(&self.raw_xml).unwrap();
-        let node_id = self.id_to_svg.get(id).ok_or("Not in node_id").unwrap();
-        let node = document
-            .get_node(*node_id)
-            .ok_or("Not in document")
-            .unwrap();
+        // The nodes as they were before `restyle`, to put back when anything fails
+        let mut restyled = vec![];
+        let geometries = self
+            .restyled_geometries(range.clone(), &mut restyle, &mut restyled)
+            .and_then(|geometries| {
+                self.restyle_glyph_texts(range, restyle_glyph_text)?;
+                Ok(geometries)
+            });
+        let geometries = match geometries {
+            Ok(geometries) => geometries,
+            Err(e) => {
+                for (mut node, kind) in restyled.into_iter().rev() {
+                    *node.borrow_mut() = kind;
+                }
+                return Err(e);
+            }
+        };
+        for (index, geometry, source) in geometries {
+            self.geometries[index] = Geometry {
+                id: self.geometries[index].id.clone(),
+                ..geometry
+            };
+            self.sources[index] = source;
+        }
+        self.raw_xml = raw_xml;
+        // Other levels still show the old style
+        self.lod_cache.clear();
+        Ok(())
+    }
+    /// The geometries within `range` which are built again after `restyle`, with their
+    /// indices and sources. `restyled` gets the nodes before `restyle` changed them.
+    fn restyled_geometries(
+        &self,
+        range: Range<usize>,
+        restyle: &mut impl FnMut(&mut NodeKind),
+        restyled: &mut Vec<(Node, NodeKind)>,
+    ) -> Result<Vec<(usize, Geometry, GeometrySource)>, Error> {
+        let tolerance = self.tolerance();
+        let mut restyle = |mut node: Node| {
+            restyled.push((node.clone(), node.borrow().clone()));
+            restyle(&mut node.borrow_mut());
+        };
+        let mut geometries = vec![];
+        for index in range {
+            let (node, transform_id) = match self.sources[index].clone() {
+                GeometrySource::Node(node, transform_id)
+                | GeometrySource::Blank(node, transform_id) => (node, transform_id),
+                GeometrySource::Subtree(root, transform_id) => {
+                    for node in root.descendants() {
+                        if !node.ancestors().any(|ancestor| is_defs(&ancestor)) {
+                            restyle(node);
+                        }
+                    }
+                    let geometry =
+                        Geometry::from_subtree(&root, transform_id, tolerance, &HashMap::new())?;
+                    geometries.push((index, geometry, GeometrySource::Subtree(root, transform_id)));
+                    continue;
+                }
+                GeometrySource::ClipPop(_) | GeometrySource::Layer(_) | GeometrySource::Fixed => {
+                    continue
+                }
+            };
+            // Groups only draw their clip shapes
+            if !matches!(*node.borrow(), NodeKind::Path(_) | NodeKind::Image(_)) {
+                continue;
+            }
+            restyle(node.clone());
+            let geometry = Geometry::from_node_with_tolerance(&node, transform_id, tolerance)?
+                .unwrap_or_default();
+            let source = match geometry.triangles.vertices.is_empty() {
+                true => GeometrySource::Blank(node, transform_id),
+                false => GeometrySource::Node(node, transform_id),
+            };
+            geometries.push((index, geometry, source));
+        }
+        Ok(geometries)
+    }
+    /// Changes the glyph texts drawn within `range` with `restyle`, and lays them out again.
+    /// They're put back as they were when they don't fit into the atlas any more.
+    fn restyle_glyph_texts(
+        &mut self,
+        range: Range<usize>,
+        mut restyle: impl FnMut(&mut GlyphText),
+    ) -> Result<(), Error> {
+        let mut previous = vec![];
+        for (id, (glyph_text, glyph_range)) in self.glyph_texts.iter_mut() {
+            if range.start <= glyph_range.start && glyph_range.end <= range.end {
+                previous.push((id.clone(), glyph_text.clone()));
+                restyle(glyph_text);
+            }
+        }
+        if previous.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.layout_glyph_texts() {
+            for (id, glyph_text) in previous {
+                if let Some((restyled, _)) = self.glyph_texts.get_mut(&id) {
+                    *restyled = glyph_text;
+                }
+            }
+            // They fitted before, into an atlas with other glyphs too
+            self.layout_glyph_texts()?;
+            self.refresh_glyph_atlas();
+            return Err(e);
+        }
+        self.refresh_glyph_atlas();
+        Ok(())
+    }
+    /// Composites the geometries of `node`, within `range`, in a layer of their own,
+    /// which `target` is composited with from now on.
+    fn insert_layer(
+        &mut self,
+        node: &Node,
+        target: Node,
+        range: Range<usize>,
+        composite: Composite,
+    ) {
+        let Range { start, end } = range;
+        self.geometries.insert(end, Geometry::pop_layer(composite));
+        self.sources.insert(end, GeometrySource::Layer(target));
+        self.geometries.insert(start, Geometry::push_layer());
+        self.sources.insert(start, GeometrySource::Fixed);
+        // Indices from `start` move past the `push_layer`, and from `end` past the `pop_layer`
+        let moved = |index: usize| index + (index >= start) as usize + (index >= end) as usize;
+        for source in self.sources.iter_mut() {
+            if let GeometrySource::ClipPop(index) = source {
+                *index = moved(*index);
+            }
+        }
+        for index in self.id_to_geometry_index.values_mut() {
+            *index = moved(*index);
+        }
+        let ids = |nodes: &mut dyn Iterator<Item = Node>| -> Vec<String> {
+            nodes
+                .map(|node| node.id().to_string())
+                .filter(|id| !id.is_empty())
+                .collect()
+        };
+        let ancestors = ids(&mut node.ancestors().skip(1));
+        let descendants = ids(&mut node.descendants());
+        for (id, range) in self.id_to_geometry_range.iter_mut() {
+            *range = match () {
+                _ if descendants.contains(id) => range.start + 1..range.end + 1,
+                _ if ancestors.contains(id) => range.start..range.end + 2,
+                _ if range.end <= start => continue,
+                _ => range.start + 2..range.end + 2,
+            };
+        }
+        // Glyph texts are either within the layer or apart from it
+        for (_, range) in self.glyph_texts.values_mut() {
+            *range = moved(range.start)..moved(range.end - 1) + 1;
+        }
+    }
+    fn write_text(&mut self, id: &str, new_text: &str) -> Result<(), Error> {
+        if self.glyph_texts.contains_key(id) {
+            return self.write_glyph_text(id, new_text);
+        }
+        let document = Document::parse(&self.raw_xml)?;
+        let text = self.find_text(&document, id)?;
+        let range = self.text_range(&text, id)?;
         let mut writer = XmlWriter::new(xmlwriter::Options {
             use_single_quote: true,
             ..Default::default()
         });
         writer.set_preserve_whitespaces(true);
-        let mut parent_ids: Vec<roxmltree::NodeId> = vec![];
-
-        // TODO: this only works with one line of text
-        find_text_node_path(node, &mut parent_ids);
-
-        let mut current_node = node.clone();
-        while let Some(parent) = current_node.parent() {
-            parent_ids.push(parent.id());
-            current_node = parent
+        // The ancestors below the root element carry the text's transforms and styles
+        let ancestors: Vec<roxmltree::Node> = text
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| ancestor.is_element() && ancestor.parent_element().is_some())
+            .collect();
+        for ancestor in ancestors.iter().rev() {
+            copy_context_element(ancestor, &mut writer);
         }
-        parent_ids.pop();
-        parent_ids.pop();
-
-        while let Some(parent_id) = parent_ids.pop() {
-            let parent = document.get_node(parent_id).unwrap();
-            self.copy_element(&parent, &mut writer);
+        match self.text_boxes.get(id) {
+            Some(text_box) => {
+                let style = text_style(&text);
+                let line_height = line_height(&text, &style);
+                let (fitted_text, x) = fit_text(new_text, text_box, &style, line_height, |line| {
+                    measure_text(self.fonts.usvg_options(), &style, line).width
+                });
+                write_text(&text, &fitted_text, Some(x), &mut writer);
+            }
+            None => write_text(&text, new_text, None, &mut writer),
         }
-        writer.write_text(new_text);
         let xml = format!(
             "<?xml version='1.0' encoding='UTF-8' standalone='no'?><svg xmlns='http://www.w3.org/2000/svg'>{}</svg>",
             &writer.end_document()
         );
-        let tree = Tree::from_str(&xml, &self.usvg_options.to_ref()).unwrap();
-        let geometry_to_update =
-            &mut self.geometries[*self.id_to_geometry_index.get(id).unwrap() as usize];
-        let transform_id = geometry_to_update
-            .triangles
-            .vertices
-            .get(0)
-            .map_or(1, |v| v.transform_id);
-        self.geometries[*self.id_to_geometry_index.get(id).unwrap() as usize] =
-            Geometry::from_tree(tree, transform_id);
+        let tree = Tree::from_str(&xml, &self.fonts.usvg_options().to_ref())?;
+        let transform_id = self.transform_id(range.clone());
+        // The text's own effects are left out of the tree, see `text::GROUP_EFFECTS`
+        let geometry = Geometry::from_subtree(
+            &tree.root(),
+            transform_id,
+            self.tolerance(),
+            &HashMap::new(),
+        )?;
+        self.replace_geometries(
+            range,
+            geometry,
+            GeometrySource::Subtree(tree.root(), transform_id),
+        );
+        // Other levels still show the old text
+        self.lod_cache.clear();
+        self.current_text_map
+            .insert(id.to_string(), new_text.to_string());
+        Ok(())
     }
 }
 
 pub fn get_usvg_options() -> Options {
-    let font = include_bytes!("../fallback_font/Roboto-Medium.ttf");
-    let mut opt = Options::default();
-    opt.fontdb
-        .load_font_source(Source::Binary(Arc::new(font.as_ref())));
-    opt.font_family = "Roboto Medium".to_string();
-    opt.keep_named_groups = true;
-    opt
+    Fonts::default().into_usvg_options()
 }
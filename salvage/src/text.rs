@@ -0,0 +1,383 @@
+use crate::measure::TextStyle;
+use roxmltree::{Attribute, Node};
+use xmlwriter::XmlWriter;
+
+// Line spacing of lines added beyond the ones in the SVG, unless the SVG spaces its own
+const LINE_HEIGHT: &str = "1.2em";
+const LINE_HEIGHT_EM: f32 = 1.2;
+const ELLIPSIS: char = '\u{2026}';
+// Already drawn by the geometries around the text's, so they'd apply twice.
+// Their references into defs wouldn't resolve either.
+const GROUP_EFFECTS: [&str; 4] = ["opacity", "clip-path", "mask", "mix-blend-mode"];
+
+fn attribute_name(a: &Attribute) -> String {
+    if a.namespace().is_some() {
+        format!("xml:{}", a.name())
+    } else {
+        a.name().to_string()
+    }
+}
+
+/// Area which `SvgSet::set_text_box` fits a text into, in the user units of the text.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TextBox {
+    /// Left edge. Lines are aligned to it, the center or the right edge by `text-anchor`.
+    pub x: f32,
+    /// Lines wrap between words, or within words longer than this.
+    pub width: f32,
+    /// Lines beyond this height are cut, and the last one left ends with an ellipsis.
+    pub height: Option<f32>,
+}
+
+/// Opens `node` in `writer` with the attributes `keep` accepts.
+/// `x` replaces the element's own position.
+fn open_element(node: &Node, keep: fn(&str) -> bool, x: Option<&str>, writer: &mut XmlWriter) {
+    writer.start_element(node.tag_name().name());
+    for a in node.attributes() {
+        if keep(a.name()) && !(x.is_some() && a.name() == "x") {
+            writer.write_attribute(&attribute_name(a), a.value());
+        }
+    }
+    if let Some(x) = x {
+        writer.write_attribute("x", x);
+    }
+}
+
+/// Opens `node` in `writer` with all its attributes but `filter`, which isn't supported.
+/// `x` replaces the position of elements positioned with one.
+fn copy_element(node: &Node, x: Option<&str>, writer: &mut XmlWriter) {
+    let x = x.filter(|_| node.has_attribute("x"));
+    open_element(node, |name| name != "filter", x, writer);
+}
+
+fn is_context_attribute(name: &str) -> bool {
+    name != "filter" && !GROUP_EFFECTS.contains(&name)
+}
+
+/// `copy_element` for the text and its ancestors, which only pass down
+/// transforms and styles.
+pub fn copy_context_element(node: &Node, writer: &mut XmlWriter) {
+    open_element(node, is_context_attribute, None, writer);
+}
+
+fn is_blank(node: &Node) -> bool {
+    node.is_text() && node.text().is_none_or(|text| text.trim().is_empty())
+}
+
+fn starts_line(node: &Node) -> bool {
+    node.is_element() && (node.has_attribute("y") || node.has_attribute("dy"))
+}
+
+/// The children of the `<text>` element grouped into lines. A line starts at
+/// every `<tspan>` positioned with `y` or `dy`, other spans style part of a line.
+fn lines<'a, 'input>(text: &Node<'a, 'input>) -> Vec<Vec<Node<'a, 'input>>> {
+    let mut lines: Vec<Vec<Node>> = vec![];
+    for child in text.children() {
+        match lines.last_mut() {
+            Some(line) if !starts_line(&child) || line.iter().all(|node| is_blank(node)) => {
+                line.push(child)
+            }
+            _ => lines.push(vec![child]),
+        }
+    }
+    lines
+}
+
+/// Copies `node`, replacing its first non-blank text with `new_text`
+/// and leaving out the rest, so the line keeps the styling of its spans.
+/// Lines without any text put `new_text` into their first innermost element,
+/// which `fill_empty` asks for.
+fn write_line_node(
+    node: &Node,
+    new_text: &mut Option<&str>,
+    fill_empty: bool,
+    x: Option<&str>,
+    writer: &mut XmlWriter,
+) {
+    if node.is_text() {
+        if !is_blank(node) {
+            if let Some(new_text) = new_text.take() {
+                writer.write_text(new_text);
+            }
+        }
+        return;
+    }
+    if !node.is_element() {
+        return;
+    }
+    copy_element(node, x, writer);
+    for child in node.children() {
+        write_line_node(&child, new_text, fill_empty, x, writer);
+    }
+    if fill_empty && node.children().all(|child| !child.is_element()) {
+        if let Some(new_text) = new_text.take() {
+            writer.write_text(new_text);
+        }
+    }
+    writer.end_element();
+}
+
+/// Spacing of the lines in the SVG when at least two have an absolute `y`.
+fn line_gap(lines: &[Vec<Node>]) -> Option<f64> {
+    let ys: Vec<f64> = lines
+        .iter()
+        .filter_map(|line| {
+            let y = line.iter().find(|node| starts_line(node))?.attribute("y")?;
+            y.split(|c: char| c.is_whitespace() || c == ',')
+                .next()?
+                .parse()
+                .ok()
+        })
+        .collect();
+    match ys.as_slice() {
+        [.., previous, last] => Some(last - previous),
+        _ => None,
+    }
+}
+
+/// A line after the last one of the SVG, styled like that line's first span.
+fn write_extra_line(
+    text: &Node,
+    template: Option<&Node>,
+    gap: Option<f64>,
+    x: Option<&str>,
+    line: &str,
+    writer: &mut XmlWriter,
+) {
+    writer.start_element("tspan");
+    if let Some(template) = template {
+        for a in template.attributes() {
+            if !matches!(a.name(), "x" | "y" | "dx" | "dy" | "filter") {
+                writer.write_attribute(&attribute_name(a), a.value());
+            }
+        }
+    }
+    let x = x
+        .or_else(|| template.and_then(|template| template.attribute("x")))
+        .or_else(|| text.attribute("x"))
+        .unwrap_or("0");
+    writer.write_attribute("x", x);
+    match gap {
+        Some(gap) => writer.write_attribute("dy", &gap),
+        None => writer.write_attribute("dy", LINE_HEIGHT),
+    }
+    writer.write_text(line);
+    writer.end_element();
+}
+
+/// Copies the `<text>` element with its content replaced by `new_text`.
+/// Each line of `new_text` goes into the matching line of the element, keeping its
+/// position and styling. Extra lines continue below the last one and missing
+/// lines are left out.
+/// `x` moves every line there instead, e.g. to align it within a `TextBox`.
+pub fn write_text(text: &Node, new_text: &str, x: Option<f32>, writer: &mut XmlWriter) {
+    let x = x.map(|x| x.to_string());
+    let x = x.as_deref();
+    open_element(text, is_context_attribute, x, writer);
+    let lines = lines(text);
+    let gap = line_gap(&lines);
+    let template = lines
+        .last()
+        .and_then(|line| line.iter().find(|node| node.is_element()));
+    for (i, new_line) in new_text.split('\n').enumerate() {
+        let new_line = new_line.strip_suffix('\r').unwrap_or(new_line);
+        match lines.get(i) {
+            Some(line) => {
+                let mut new_text = Some(new_line);
+                let fill_empty = line.iter().all(|node| {
+                    node.descendants()
+                        .all(|node| !node.is_text() || is_blank(&node))
+                });
+                for node in line {
+                    write_line_node(node, &mut new_text, fill_empty, x, writer);
+                }
+                if let Some(new_text) = new_text {
+                    writer.write_text(new_text);
+                }
+            }
+            None => write_extra_line(text, template, gap, x, new_line, writer),
+        }
+    }
+    writer.end_element();
+}
+
+/// The text of the `<text>` element, with a line break between each of its lines.
+pub fn text_content(text: &Node) -> String {
+    lines(text)
+        .iter()
+        .map(|line| {
+            line.iter()
+                .flat_map(|node| node.descendants())
+                .filter(|node| node.is_text() && !is_blank(node))
+                .filter_map(|node| node.text())
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The style of the first text of the `<text>` element, which wrapping measures lines in.
+pub fn text_style(text: &Node) -> TextStyle {
+    let first_text = text
+        .descendants()
+        .find(|node| node.is_text() && !is_blank(node))
+        .and_then(|node| node.parent_element());
+    TextStyle::from_node(&first_text.unwrap_or(*text))
+}
+
+/// Breaks each line of `paragraph` between words so it's at most `width` wide.
+/// Words which are wider on their own are broken between characters.
+fn wrap_paragraph(
+    paragraph: &str,
+    width: f32,
+    measure: &impl Fn(&str) -> f32,
+    lines: &mut Vec<String>,
+) {
+    let mut line = String::new();
+    for word in paragraph.split_whitespace() {
+        let joined = format!("{} {}", line, word);
+        if !line.is_empty() && measure(&joined) <= width {
+            line = joined;
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        for c in word.chars() {
+            line.push(c);
+            if line.chars().count() > 1 && measure(&line) > width {
+                line.pop();
+                lines.push(std::mem::replace(&mut line, c.to_string()));
+            }
+        }
+    }
+    lines.push(line);
+}
+
+/// Spacing of the lines of the `<text>` element, or of lines added to it.
+pub fn line_height(text: &Node, style: &TextStyle) -> f32 {
+    line_gap(&lines(text)).map_or(style.size * LINE_HEIGHT_EM, |gap| gap as f32)
+}
+
+/// Where the first line of the `<text>` element starts, on its baseline.
+pub fn text_origin(text: &Node) -> (f32, f32) {
+    let first_line = lines(text)
+        .first()
+        .and_then(|line| line.iter().find(|node| starts_line(node)).copied());
+    let coordinate = |name| {
+        first_line
+            .and_then(|node| node.attribute(name))
+            .or_else(|| text.attribute(name))
+            .and_then(|value| {
+                value
+                    .split(|c: char| c.is_whitespace() || c == ',')
+                    .next()?
+                    .parse()
+                    .ok()
+            })
+            .unwrap_or(0.)
+    };
+    (coordinate("x"), coordinate("y"))
+}
+
+/// `new_text` wrapped to the width of `text_box`, and cut with an ellipsis after
+/// the lines which fit its height, along with where `text-anchor` puts the lines.
+/// `measure` gives the width of a line set in `style`.
+pub fn fit_text(
+    new_text: &str,
+    text_box: &TextBox,
+    style: &TextStyle,
+    line_height: f32,
+    measure: impl Fn(&str) -> f32,
+) -> (String, f32) {
+    let mut wrapped = vec![];
+    for paragraph in new_text.split('\n') {
+        wrap_paragraph(paragraph, text_box.width, &measure, &mut wrapped);
+    }
+    // The box of a single line is about as high as the font, so it holds one line
+    let max_lines = text_box
+        .height
+        .map(|height| ((height / line_height).round() as usize).max(1));
+    if let Some(max_lines) = max_lines.filter(|max_lines| wrapped.len() > *max_lines) {
+        wrapped.truncate(max_lines);
+        if let Some(last) = wrapped.last_mut() {
+            while !last.is_empty() && measure(&format!("{}{}", last, ELLIPSIS)) > text_box.width {
+                last.pop();
+            }
+            *last = format!("{}{}", last.trim_end(), ELLIPSIS);
+        }
+    }
+    let x = match style.anchor.as_str() {
+        "middle" => text_box.x + text_box.width / 2.,
+        "end" => text_box.x + text_box.width,
+        _ => text_box.x,
+    };
+    (wrapped.join("\n"), x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every character is 10 units wide
+    fn measure(line: &str) -> f32 {
+        line.chars().count() as f32 * 10.
+    }
+
+    fn fit(new_text: &str, width: f32, height: Option<f32>) -> String {
+        let text_box = TextBox {
+            x: 0.,
+            width,
+            height,
+        };
+        fit_text(new_text, &text_box, &TextStyle::default(), 10., measure).0
+    }
+
+    #[test]
+    fn wraps_between_words() {
+        assert_eq!(fit("aa bb cc", 50., None), "aa bb\ncc");
+    }
+
+    #[test]
+    fn breaks_long_words_between_characters() {
+        assert_eq!(fit("abcdefgh", 30., None), "abc\ndef\ngh");
+        assert_eq!(fit("a abcdefgh", 30., None), "a\nabc\ndef\ngh");
+    }
+
+    #[test]
+    fn keeps_line_breaks() {
+        assert_eq!(fit("aa\nbb cc", 100., None), "aa\nbb cc");
+    }
+
+    #[test]
+    fn ellipsizes_the_last_line_which_fits() {
+        assert_eq!(fit("aa bb cc dd", 50., Some(20.)), "aa bb\ncc dd");
+        assert_eq!(fit("aa bb cc dd", 50., Some(10.)), "aa b\u{2026}");
+        // Boxes lower than a line still show one
+        assert_eq!(fit("aa bb cc", 50., Some(2.)), "aa b\u{2026}");
+    }
+
+    #[test]
+    fn ellipsis_follows_a_line_which_fits() {
+        assert_eq!(fit("aaa bbbb", 50., Some(10.)), "aaa\u{2026}");
+    }
+
+    #[test]
+    fn aligns_to_the_anchor() {
+        let text_box = TextBox {
+            x: 10.,
+            width: 100.,
+            height: None,
+        };
+        let x = |anchor: &str| {
+            let style = TextStyle {
+                anchor: anchor.to_string(),
+                ..Default::default()
+            };
+            fit_text("a", &text_box, &style, 10., measure).1
+        };
+        assert_eq!(x("start"), 10.);
+        assert_eq!(x("middle"), 60.);
+        assert_eq!(x("end"), 110.);
+    }
+}